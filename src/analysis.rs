@@ -0,0 +1,419 @@
+//! Text analysis: turns document and query text into index terms.
+//!
+//! An analyzer runs character filters over the raw text, splits it with a
+//! tokenizer and passes the tokens through token filters. Analyzers are
+//! looked up by name; the name an index was built with is stored in its
+//! manifest so queries are always analyzed the same way as the documents.
+
+use crate::stemmer::Stemmer;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use unicode_normalization::UnicodeNormalization;
+
+/// Analyzer used when none is given on the command line.
+pub const DEFAULT_ANALYZER: &str = "persian";
+
+/// Analyzer names accepted in `AnalysisConfig`.
+pub const ANALYZER_NAMES: &[&str] = &["persian", "light", "standard"];
+
+/// A term and its position in the token stream. Filters that drop tokens
+/// leave the positions of the others untouched, so phrase matching still
+/// sees the original distances.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub text: String,
+    pub position: usize,
+}
+
+pub trait CharFilter: Send + Sync {
+    fn filter(&self, text: &str) -> String;
+}
+
+pub trait Tokenizer: Send + Sync {
+    fn tokenize(&self, text: &str) -> Vec<Token>;
+}
+
+pub trait TokenFilter: Send + Sync {
+    fn filter(&self, tokens: Vec<Token>) -> Vec<Token>;
+}
+
+pub trait Analyzer: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn analyze(&self, text: &str) -> Vec<Token>;
+
+    /// `text` after the char filters only, for matching raw query input
+    /// such as wildcard patterns against terms.
+    fn normalize(&self, text: &str) -> String;
+
+    /// Just the terms of `analyze`, in order.
+    fn terms(&self, text: &str) -> Vec<String> {
+        self.analyze(text).into_iter().map(|t| t.text).collect()
+    }
+}
+
+/// char filters → tokenizer → token filters.
+pub struct Pipeline {
+    name: String,
+    char_filters: Vec<Box<dyn CharFilter>>,
+    tokenizer: Box<dyn Tokenizer>,
+    token_filters: Vec<Box<dyn TokenFilter>>,
+}
+
+impl Pipeline {
+    pub fn new(name: &str, tokenizer: impl Tokenizer + 'static) -> Self {
+        Pipeline {
+            name: name.to_string(),
+            char_filters: Vec::new(),
+            tokenizer: Box::new(tokenizer),
+            token_filters: Vec::new(),
+        }
+    }
+
+    pub fn char_filter(mut self, filter: impl CharFilter + 'static) -> Self {
+        self.char_filters.push(Box::new(filter));
+        self
+    }
+
+    pub fn token_filter(mut self, filter: impl TokenFilter + 'static) -> Self {
+        self.token_filters.push(Box::new(filter));
+        self
+    }
+}
+
+impl Analyzer for Pipeline {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn analyze(&self, text: &str) -> Vec<Token> {
+        let mut tokens = self.tokenizer.tokenize(&self.normalize(text));
+        for f in &self.token_filters {
+            tokens = f.filter(tokens);
+        }
+        tokens
+    }
+
+    fn normalize(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for f in &self.char_filters {
+            text = f.filter(&text);
+        }
+        text
+    }
+}
+
+/// How an index analyzes text, as recorded in its manifest.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AnalysisConfig {
+    /// One of `ANALYZER_NAMES`.
+    pub analyzer: String,
+    /// Words left out of the index. Their positions are still counted, so
+    /// phrases containing them keep matching at the right distances.
+    #[serde(default)]
+    pub stopwords: Vec<String>,
+    pub zwnj: ZwnjMode,
+}
+
+/// What to do with the zero-width non-joiner (نیم‌فاصله).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ZwnjMode {
+    /// Treat it as a word break: "می‌روم" is indexed as "می" and "روم".
+    Split,
+    /// Drop it so compounds stay one word: "می‌روم" becomes "میروم", and
+    /// affixes are left to the stemmer.
+    Join,
+}
+
+impl Default for AnalysisConfig {
+    fn default() -> Self {
+        AnalysisConfig {
+            analyzer: DEFAULT_ANALYZER.to_string(),
+            stopwords: Vec::new(),
+            zwnj: ZwnjMode::Join,
+        }
+    }
+}
+
+impl AnalysisConfig {
+    pub fn build(&self) -> anyhow::Result<Box<dyn Analyzer>> {
+        by_name(&self.analyzer, &self.stopwords, self.zwnj)
+    }
+}
+
+/// Looks up a named analyzer. Stopwords are removed after lowercasing and
+/// before stemming.
+fn by_name(name: &str, stopwords: &[String], zwnj: ZwnjMode) -> anyhow::Result<Box<dyn Analyzer>> {
+    let base = || {
+        let pipeline = Pipeline::new(name, StandardTokenizer)
+            .char_filter(Nfkc)
+            .char_filter(StripMarks)
+            .char_filter(PersianCharMap)
+            .char_filter(Zwnj(zwnj))
+            .token_filter(Lowercase);
+        // normalized like document text, so lists can use either Arabic or
+        // Persian letters, diacritics and any case
+        let stop = StopFilter(
+            stopwords
+                .iter()
+                .map(|w| pipeline.normalize(w).to_lowercase())
+                .collect(),
+        );
+        pipeline.token_filter(stop)
+    };
+    let analyzer = match name {
+        "persian" => base().token_filter(Stem(Stemmer::Persian)),
+        "light" => base().token_filter(Stem(Stemmer::Light)),
+        "standard" => base(),
+        _ => anyhow::bail!(
+            "unknown analyzer {:?} (expected one of: {})",
+            name,
+            ANALYZER_NAMES.join(", ")
+        ),
+    };
+    Ok(Box::new(analyzer))
+}
+
+/// Unicode compatibility normalization, which among other things folds
+/// Arabic presentation forms (ﻙ, ﻼ, ...) back into ordinary letters.
+pub struct Nfkc;
+
+impl CharFilter for Nfkc {
+    fn filter(&self, text: &str) -> String {
+        text.nfkc().collect()
+    }
+}
+
+/// Removes Arabic diacritics (اعراب) and tatweel (ـ). Diacritics are not
+/// letters, so left in place they would split words apart.
+pub struct StripMarks;
+
+impl CharFilter for StripMarks {
+    fn filter(&self, text: &str) -> String {
+        text.chars()
+            .filter(|&c| !matches!(c, '\u{064B}'..='\u{065F}' | '\u{0670}' | '\u{0640}'))
+            .collect()
+    }
+}
+
+/// Maps Arabic letters and letter variants to the Persian letter they stand
+/// for, and Persian and Arabic-Indic digits to ASCII.
+pub struct PersianCharMap;
+
+impl CharFilter for PersianCharMap {
+    fn filter(&self, text: &str) -> String {
+        text.chars()
+            .map(|c| match c {
+                'ي' | 'ى' | 'ئ' => 'ی',
+                'ك' => 'ک',
+                'ة' | 'ۀ' => 'ه',
+                'أ' | 'إ' | 'آ' | 'ٱ' => 'ا',
+                'ؤ' => 'و',
+                '۰'..='۹' => char::from(b'0' + (c as u32 - '۰' as u32) as u8),
+                '٠'..='٩' => char::from(b'0' + (c as u32 - '٠' as u32) as u8),
+                c => c,
+            })
+            .collect()
+    }
+}
+
+pub struct Zwnj(pub ZwnjMode);
+
+impl CharFilter for Zwnj {
+    fn filter(&self, text: &str) -> String {
+        let replacement = match self.0 {
+            ZwnjMode::Split => " ",
+            ZwnjMode::Join => "",
+        };
+        // the zero-width joiner never separates words
+        text.replace('\u{200D}', "")
+            .replace('\u{200C}', replacement)
+    }
+}
+
+/// Words of `text` as they are written: runs of letters, digits and the
+/// marks and half-spaces that occur inside words. Used to find the surface
+/// form an index term was written as.
+pub fn surface_words(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| {
+        !(c.is_alphanumeric()
+            || matches!(c, '\u{064B}'..='\u{065F}' | '\u{0670}' | '\u{0640}')
+            || matches!(c, '\u{200C}' | '\u{200D}'))
+    })
+    .map(|w| w.trim_matches(['\u{200C}', '\u{200D}']))
+    .filter(|w| !w.is_empty())
+}
+
+/// A word as it is shown to users: lowercased, with diacritics and tatweel
+/// removed and Arabic ي and ك written the Persian way, but otherwise as
+/// written (half-spaces and آ are kept, unlike in index terms).
+pub fn display_form(word: &str) -> String {
+    let word: String = Nfkc.filter(word);
+    StripMarks
+        .filter(&word)
+        .chars()
+        .map(|c| match c {
+            'ي' | 'ى' => 'ی',
+            'ك' => 'ک',
+            c => c,
+        })
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Splits on anything that is not a letter or digit and between runs of
+/// letters and digits.
+pub struct StandardTokenizer;
+
+impl Tokenizer for StandardTokenizer {
+    fn tokenize(&self, text: &str) -> Vec<Token> {
+        let mut tokens = Vec::new();
+        let mut word = String::new();
+        let mut last_was_digit = None; // برای ردیابی نوع کاراکتر قبلی
+
+        let mut flush = |word: &mut String| {
+            if !word.is_empty() {
+                let position = tokens.len();
+                tokens.push(Token {
+                    text: std::mem::take(word),
+                    position,
+                });
+            }
+        };
+
+        for c in text.chars() {
+            if !c.is_alphanumeric() {
+                flush(&mut word);
+                last_was_digit = None;
+                continue;
+            }
+            let is_digit = c.is_numeric();
+            if last_was_digit.is_some_and(|was_digit| was_digit != is_digit) {
+                flush(&mut word);
+            }
+            word.push(c);
+            last_was_digit = Some(is_digit);
+        }
+        flush(&mut word);
+        tokens
+    }
+}
+
+pub struct Lowercase;
+
+impl TokenFilter for Lowercase {
+    fn filter(&self, mut tokens: Vec<Token>) -> Vec<Token> {
+        for t in &mut tokens {
+            t.text = t.text.to_lowercase();
+        }
+        tokens
+    }
+}
+
+/// Drops stopwords, keeping the positions of the remaining tokens.
+pub struct StopFilter(pub HashSet<String>);
+
+impl TokenFilter for StopFilter {
+    fn filter(&self, mut tokens: Vec<Token>) -> Vec<Token> {
+        if !self.0.is_empty() {
+            tokens.retain(|t| !self.0.contains(&t.text));
+        }
+        tokens
+    }
+}
+
+pub struct Stem(pub Stemmer);
+
+impl TokenFilter for Stem {
+    fn filter(&self, mut tokens: Vec<Token>) -> Vec<Token> {
+        for t in &mut tokens {
+            t.text = self.0.stem(&t.text);
+        }
+        tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn persian(zwnj: ZwnjMode) -> Box<dyn Analyzer> {
+        by_name("persian", &[], zwnj).unwrap()
+    }
+
+    #[test]
+    fn char_map_folds_arabic_letters() {
+        assert_eq!(PersianCharMap.filter("علي"), "علی");
+        assert_eq!(PersianCharMap.filter("كتاب"), "کتاب");
+        assert_eq!(PersianCharMap.filter("خانۀ"), "خانه");
+        assert_eq!(PersianCharMap.filter("آب"), "اب");
+        assert_eq!(PersianCharMap.filter("مسئله"), "مسیله");
+    }
+
+    #[test]
+    fn char_map_folds_digits() {
+        assert_eq!(PersianCharMap.filter("۱۴۰۳"), "1403");
+        assert_eq!(PersianCharMap.filter("٢٠٢٤"), "2024");
+        assert_eq!(PersianCharMap.filter("abc 42"), "abc 42");
+    }
+
+    #[test]
+    fn strip_marks_removes_diacritics_and_tatweel() {
+        assert_eq!(StripMarks.filter("کِتاب"), "کتاب");
+        assert_eq!(StripMarks.filter("مـــحمد"), "محمد");
+        assert_eq!(StripMarks.filter("عَلَیْهِ"), "علیه");
+    }
+
+    #[test]
+    fn zwnj_modes() {
+        assert_eq!(Zwnj(ZwnjMode::Split).filter("می\u{200C}روم"), "می روم");
+        assert_eq!(Zwnj(ZwnjMode::Join).filter("می\u{200C}روم"), "میروم");
+        // the joiner never separates words
+        assert_eq!(Zwnj(ZwnjMode::Split).filter("a\u{200D}b"), "ab");
+    }
+
+    #[test]
+    fn tokenizer_splits_letters_from_digits() {
+        let terms: Vec<String> = StandardTokenizer
+            .tokenize("سال1403، iPhone15!")
+            .into_iter()
+            .map(|t| t.text)
+            .collect();
+        assert_eq!(terms, ["سال", "1403", "iPhone", "15"]);
+    }
+
+    #[test]
+    fn persian_analyzer_on_sample() {
+        let analyzer = persian(ZwnjMode::Join);
+        assert_eq!(
+            analyzer.terms("كتاب‌های فارسيِ ما در کتابخانه‌ها"),
+            ["کتاب", "فارسی", "ما", "در", "کتابخانه"]
+        );
+        assert_eq!(analyzer.terms("کتابهای"), analyzer.terms("کتاب"));
+        assert_eq!(analyzer.terms("می‌روند"), ["رو"]);
+        assert_eq!(analyzer.terms("سال ۱۴۰۳"), ["سال", "1403"]);
+    }
+
+    #[test]
+    fn split_mode_separates_affixes() {
+        let analyzer = persian(ZwnjMode::Split);
+        assert_eq!(analyzer.terms("کتاب‌ها"), ["کتاب", "ها"]);
+    }
+
+    #[test]
+    fn stopwords_keep_positions() {
+        let analyzer = by_name("standard", &["و".to_string()], ZwnjMode::Join).unwrap();
+        let tokens = analyzer.analyze("کتاب و دفتر");
+        let positions: Vec<(&str, usize)> = tokens
+            .iter()
+            .map(|t| (t.text.as_str(), t.position))
+            .collect();
+        assert_eq!(positions, [("کتاب", 0), ("دفتر", 2)]);
+    }
+
+    #[test]
+    fn unknown_analyzer_is_an_error() {
+        assert!(by_name("klingon", &[], ZwnjMode::Join).is_err());
+    }
+}
@@ -0,0 +1,480 @@
+//! JSON search API, backed by the same pipeline as the HTML results page,
+//! and search-as-you-type suggestions.
+
+use crate::automaton::PatternTooComplex;
+use crate::ranking::{Ranker, RankingParams};
+use crate::search::SearchOptions;
+use crate::server::{encode_query_param, highlighted_snippet, run_search, AppState};
+use crate::suggest::{SuggestionKind, MAX_SUGGESTIONS};
+use actix_web::{http::StatusCode, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+const DEFAULT_PAGE_SIZE: usize = 10;
+const MAX_PAGE_SIZE: usize = 100;
+const DEFAULT_SUGGESTIONS: usize = 8;
+
+/// Raw query-string parameters. Numbers are parsed by hand so a malformed
+/// value gets a JSON error instead of actix's plain-text 400.
+#[derive(Deserialize)]
+pub struct ApiQuery {
+    q: Option<String>,
+    page: Option<String>,
+    size: Option<String>,
+    #[serde(flatten)]
+    ranking: RankingQuery,
+}
+
+/// Ranking model and parameters a request may set, on the API and the
+/// results page alike.
+#[derive(Deserialize)]
+pub struct RankingQuery {
+    ranker: Option<String>,
+    k1: Option<String>,
+    b: Option<String>,
+    delta: Option<String>,
+    mu: Option<String>,
+    c: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SearchResponse {
+    query: String,
+    /// Ranking model the hits were scored with.
+    ranker: &'static str,
+    /// The model's parameters.
+    params: BTreeMap<&'static str, f64>,
+    total: usize,
+    page: usize,
+    page_size: usize,
+    total_pages: usize,
+    took_ms: f64,
+    hits: Vec<Hit>,
+    suggestion: Option<Suggestion>,
+    /// Synonyms and transliterations the query was expanded with.
+    expansions: Vec<Change>,
+}
+
+#[derive(Serialize)]
+struct Hit {
+    doc_id: usize,
+    url: String,
+    title: String,
+    score: f64,
+    /// HTML-escaped excerpt with query terms wrapped in `<mark>`.
+    snippet: String,
+}
+
+#[derive(Serialize)]
+struct Suggestion {
+    query: String,
+    changes: Vec<Change>,
+    hits: usize,
+}
+
+#[derive(Serialize)]
+struct Change {
+    from: String,
+    to: String,
+}
+
+#[derive(Deserialize)]
+pub struct SuggestQuery {
+    prefix: Option<String>,
+    size: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SuggestResponse {
+    prefix: String,
+    took_ms: f64,
+    suggestions: Vec<SuggestionBody>,
+}
+
+#[derive(Serialize)]
+struct SuggestionBody {
+    text: String,
+    /// `query` (searched before) or `term` (last word completed).
+    kind: &'static str,
+    score: u64,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: ErrorDetail,
+}
+
+#[derive(Serialize)]
+struct ErrorDetail {
+    code: &'static str,
+    message: String,
+}
+
+fn error(status: StatusCode, code: &'static str, message: impl Into<String>) -> HttpResponse {
+    HttpResponse::build(status).json(ErrorBody {
+        error: ErrorDetail {
+            code,
+            message: message.into(),
+        },
+    })
+}
+
+/// Parses an optional positive integer parameter, falling back to `default`.
+/// The error is the message for an `invalid_parameter` response.
+fn positive_param(value: Option<&str>, name: &str, default: usize) -> Result<usize, String> {
+    match value.map(str::trim) {
+        None | Some("") => Ok(default),
+        Some(v) => match v.parse::<usize>() {
+            Ok(n) if n >= 1 => Ok(n),
+            _ => Err(format!(
+                "`{}` must be a positive integer, got {:?}",
+                name, v
+            )),
+        },
+    }
+}
+
+/// Parses an optional number parameter, falling back to `default`.
+fn number_param(value: Option<&str>, name: &str, default: f64) -> Result<f64, String> {
+    match value.map(str::trim) {
+        None | Some("") => Ok(default),
+        Some(v) => match v.parse::<f64>() {
+            Ok(x) if x.is_finite() => Ok(x),
+            _ => Err(format!("`{}` must be a number, got {:?}", name, v)),
+        },
+    }
+}
+
+impl RankingQuery {
+    /// The requested model, or the server's, and the server's parameters
+    /// with those the request sets. The error is the message for an
+    /// `invalid_parameter` response.
+    pub fn resolve(&self, options: &SearchOptions) -> Result<(Ranker, RankingParams), String> {
+        let ranker = match self.ranker.as_deref().map(str::trim) {
+            None | Some("") => options.ranker,
+            Some(name) => name.parse::<Ranker>()?,
+        };
+        let defaults = &options.params;
+        let params = RankingParams {
+            k1: number_param(self.k1.as_deref(), "k1", defaults.k1)?,
+            b: number_param(self.b.as_deref(), "b", defaults.b)?,
+            delta: number_param(self.delta.as_deref(), "delta", defaults.delta)?,
+            mu: number_param(self.mu.as_deref(), "mu", defaults.mu)?,
+            c: number_param(self.c.as_deref(), "c", defaults.c)?,
+        };
+        params.validate()?;
+        Ok((ranker, params))
+    }
+
+    /// `&name=value` for every parameter the request set, to keep links to
+    /// other pages of the results ranked the same way.
+    pub fn link_params(&self) -> String {
+        let set = [
+            ("ranker", &self.ranker),
+            ("k1", &self.k1),
+            ("b", &self.b),
+            ("delta", &self.delta),
+            ("mu", &self.mu),
+            ("c", &self.c),
+        ];
+        let mut out = String::new();
+        for (name, value) in set {
+            if let Some(v) = value.as_deref().map(str::trim).filter(|v| !v.is_empty()) {
+                out.push_str(&format!("&{}={}", name, encode_query_param(v)));
+            }
+        }
+        out
+    }
+}
+
+pub async fn search_api(
+    data: web::Data<AppState>,
+    web::Query(params): web::Query<ApiQuery>,
+) -> HttpResponse {
+    let start_time = Instant::now();
+
+    let q = params.q.as_deref().unwrap_or("").trim();
+    if q.is_empty() {
+        return error(
+            StatusCode::BAD_REQUEST,
+            "empty_query",
+            "the `q` parameter is required",
+        );
+    }
+    let page = match positive_param(params.page.as_deref(), "page", 1) {
+        Ok(p) => p,
+        Err(message) => return error(StatusCode::BAD_REQUEST, "invalid_parameter", message),
+    };
+    let page_size = match positive_param(params.size.as_deref(), "size", DEFAULT_PAGE_SIZE) {
+        Ok(s) if s <= MAX_PAGE_SIZE => s,
+        Ok(_) => {
+            return error(
+                StatusCode::BAD_REQUEST,
+                "invalid_parameter",
+                format!("`size` must be at most {}", MAX_PAGE_SIZE),
+            )
+        }
+        Err(message) => return error(StatusCode::BAD_REQUEST, "invalid_parameter", message),
+    };
+    let (ranker, ranking) = match params.ranking.resolve(&data.options) {
+        Ok(r) => r,
+        Err(message) => return error(StatusCode::BAD_REQUEST, "invalid_parameter", message),
+    };
+
+    let index = data.index();
+    let scorer = ranker.scorer(&ranking);
+    let outcome = match run_search(&index, &data.options, q, scorer.as_ref()) {
+        Ok(outcome) => outcome,
+        Err(e) if e.is::<PatternTooComplex>() => {
+            return error(
+                StatusCode::BAD_REQUEST,
+                "pattern_too_complex",
+                e.to_string(),
+            )
+        }
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, "internal", e.to_string()),
+    };
+    if outcome.only_stopwords {
+        return error(
+            StatusCode::BAD_REQUEST,
+            "only_stopwords",
+            "every word of the query is a stopword, and stopwords are not indexed",
+        );
+    }
+    if !outcome.results.is_empty() && page == 1 {
+        data.suggester.record(q);
+    }
+
+    let total = outcome.results.len();
+    let total_pages = total.div_ceil(page_size);
+    if total > 0 && page > total_pages {
+        return error(
+            StatusCode::BAD_REQUEST,
+            "page_out_of_range",
+            format!(
+                "page {} requested but there are {} pages",
+                page, total_pages
+            ),
+        );
+    }
+
+    let hits = outcome
+        .results
+        .iter()
+        .skip((page - 1) * page_size)
+        .take(page_size)
+        .map(|&(doc_id, score)| {
+            let meta = index.doc(doc_id);
+            Hit {
+                doc_id,
+                snippet: highlighted_snippet(&meta.body, &outcome.highlight_terms),
+                url: meta.url,
+                title: meta.title,
+                score,
+            }
+        })
+        .collect();
+
+    let suggestion = outcome.correction.map(|c| Suggestion {
+        query: c.query,
+        changes: c
+            .changes
+            .into_iter()
+            .map(|(from, to)| Change { from, to })
+            .collect(),
+        hits: c.hits,
+    });
+    let expansions = outcome
+        .expansions
+        .into_iter()
+        .map(|e| Change {
+            from: e.from,
+            to: e.to,
+        })
+        .collect();
+
+    HttpResponse::Ok().json(SearchResponse {
+        query: q.to_string(),
+        ranker: ranker.name(),
+        params: ranking.of(ranker).into_iter().collect(),
+        total,
+        page,
+        page_size,
+        total_pages,
+        took_ms: start_time.elapsed().as_secs_f64() * 1000.0,
+        hits,
+        suggestion,
+        expansions,
+    })
+}
+
+/// Completions for a partially typed query. An empty prefix gets an empty
+/// list rather than an error, since the box sends one whenever it is
+/// cleared.
+pub async fn suggest_api(
+    data: web::Data<AppState>,
+    web::Query(params): web::Query<SuggestQuery>,
+) -> HttpResponse {
+    let start_time = Instant::now();
+
+    let prefix = params.prefix.unwrap_or_default();
+    let size = match positive_param(params.size.as_deref(), "size", DEFAULT_SUGGESTIONS) {
+        Ok(s) if s <= MAX_SUGGESTIONS => s,
+        Ok(_) => {
+            return error(
+                StatusCode::BAD_REQUEST,
+                "invalid_parameter",
+                format!("`size` must be at most {}", MAX_SUGGESTIONS),
+            )
+        }
+        Err(message) => return error(StatusCode::BAD_REQUEST, "invalid_parameter", message),
+    };
+
+    let index = data.index();
+    let suggestions = data
+        .suggester
+        .suggest(&index, &prefix, size)
+        .into_iter()
+        .map(|s| SuggestionBody {
+            text: s.text,
+            kind: match s.kind {
+                SuggestionKind::Query => "query",
+                SuggestionKind::Term => "term",
+            },
+            score: s.weight,
+        })
+        .collect();
+
+    HttpResponse::Ok().json(SuggestResponse {
+        prefix,
+        took_ms: start_time.elapsed().as_secs_f64() * 1000.0,
+        suggestions,
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::analysis::AnalysisConfig;
+    use crate::indexer::IndexStore;
+    use crate::search::tests::options;
+    use crate::segment::tests::{build, doc, temp_dir};
+    use crate::segment::IndexWriter;
+    use crate::suggest::Suggester;
+    use actix_web::{test, App};
+    use serde_json::Value;
+
+    /// Twelve documents about rust, two about go, in an index that drops
+    /// "the".
+    pub(crate) fn state(name: &str) -> (std::path::PathBuf, AppState) {
+        let dir = temp_dir(name);
+        let mut docs: Vec<_> = (0..12)
+            .map(|i| doc(&format!("rust-{}", i), &["rust", "the", "language"]))
+            .collect();
+        docs.push(doc("go-0", &["go", "language"]));
+        docs.push(doc("go-1", &["go", "rust"]));
+        let analysis = AnalysisConfig {
+            stopwords: vec!["the".to_string()],
+            ..AnalysisConfig::default()
+        };
+        let mut writer = IndexWriter::create(&dir, analysis, false).unwrap();
+        writer.add_segment(build(docs)).unwrap();
+        writer.commit().unwrap();
+        let index = IndexStore::open(&dir).unwrap();
+        let state = AppState::new(index, options(), Suggester::new(None).unwrap());
+        (dir, state)
+    }
+
+    /// Status and JSON body of a GET of `uri`.
+    async fn get(state: &AppState, uri: &str) -> (StatusCode, Value) {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .route("/api/search", web::get().to(search_api))
+                .route("/api/suggest", web::get().to(suggest_api)),
+        )
+        .await;
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+        let status = response.status();
+        (status, test::read_body_json(response).await)
+    }
+
+    async fn error_code(state: &AppState, uri: &str) -> String {
+        let (status, body) = get(state, uri).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", uri);
+        body["error"]["code"].as_str().unwrap().to_string()
+    }
+
+    #[actix_web::test]
+    async fn bad_requests_get_json_errors() {
+        let (dir, state) = state("api-errors");
+        assert_eq!(error_code(&state, "/api/search").await, "empty_query");
+        assert_eq!(error_code(&state, "/api/search?q=+").await, "empty_query");
+        for params in [
+            "page=0", "page=x", "size=500", "k1=-1", "b=2", "mu=x", "ranker=x",
+        ] {
+            let uri = format!("/api/search?q=rust&{}", params);
+            assert_eq!(error_code(&state, &uri).await, "invalid_parameter");
+        }
+        assert_eq!(
+            error_code(&state, "/api/search?q=the").await,
+            "only_stopwords"
+        );
+        assert_eq!(
+            error_code(&state, "/api/search?q=rust&page=3").await,
+            "page_out_of_range"
+        );
+        let (_, body) = get(&state, "/api/search?q=rust&page=3").await;
+        assert_eq!(
+            body["error"]["message"],
+            "page 3 requested but there are 2 pages"
+        );
+        let complex = format!("/api/search?q=*a{}", "%3F".repeat(16));
+        assert_eq!(error_code(&state, &complex).await, "pattern_too_complex");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn searches_are_paged() {
+        let (dir, state) = state("api-search");
+        let (status, body) = get(&state, "/api/search?q=rust&page=2&k1=2").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["query"], "rust");
+        assert_eq!(body["ranker"], "bm25");
+        assert_eq!(body["params"]["k1"], 2.0);
+        assert_eq!(body["total"], 13);
+        assert_eq!(body["total_pages"], 2);
+        let hits = body["hits"].as_array().unwrap();
+        assert_eq!(hits.len(), 3);
+        assert!(hits.iter().all(|h| h["score"].as_f64().unwrap() > 0.0));
+        assert!(hits[0]["snippet"]
+            .as_str()
+            .unwrap()
+            .contains("<mark>rust</mark>"));
+        // a query that finds nothing is not out of range
+        let (status, body) = get(&state, "/api/search?q=python&page=4").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["total"], 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn suggestions_complete_the_last_word() {
+        let (dir, state) = state("api-suggest");
+        let (status, body) = get(&state, "/api/suggest?prefix=learn%20la&size=5").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["prefix"], "learn la");
+        let suggestions = body["suggestions"].as_array().unwrap();
+        assert_eq!(suggestions.len(), 1);
+        assert_eq!(suggestions[0]["text"], "learn language");
+        assert_eq!(suggestions[0]["kind"], "term");
+        assert_eq!(suggestions[0]["score"], 13);
+        assert_eq!(
+            error_code(&state, "/api/suggest?prefix=r&size=0").await,
+            "invalid_parameter"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
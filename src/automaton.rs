@@ -0,0 +1,274 @@
+//! Automata for walking the FST term dictionary.
+
+use fst::Automaton;
+use regex_automata::dfa::{dense, Automaton as _, StartKind};
+use regex_automata::util::primitives::StateID;
+use regex_automata::util::start;
+use regex_automata::Anchored;
+use std::fmt;
+
+/// Most heap a regex's DFA may take, both while it is determinized and
+/// once built. Patterns like `*a??????????` blow up exponentially, and a
+/// DFA is built for every query that uses one.
+const DFA_SIZE_LIMIT: usize = 1 << 20;
+
+/// A pattern whose DFA would exceed `DFA_SIZE_LIMIT`.
+#[derive(Debug)]
+pub struct PatternTooComplex;
+
+impl fmt::Display for PatternTooComplex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("pattern too complex")
+    }
+}
+
+impl std::error::Error for PatternTooComplex {}
+
+/// Matches terms that are entirely matched by a regular expression.
+///
+/// Backed by a dense DFA so the FST can prune whole subtrees as soon as the
+/// DFA reaches a dead state.
+pub struct RegexAutomaton {
+    dfa: dense::DFA<Vec<u32>>,
+    start: StateID,
+}
+
+impl RegexAutomaton {
+    pub fn new(pattern: &str) -> anyhow::Result<Self> {
+        // anchored start plus `$` means the whole term has to match
+        let dfa = dense::Builder::new()
+            .configure(
+                dense::Config::new()
+                    .start_kind(StartKind::Anchored)
+                    .dfa_size_limit(Some(DFA_SIZE_LIMIT))
+                    .determinize_size_limit(Some(DFA_SIZE_LIMIT)),
+            )
+            .build(&format!("(?:{})$", pattern))
+            .map_err(|e| -> anyhow::Error {
+                if e.is_size_limit_exceeded() {
+                    PatternTooComplex.into()
+                } else {
+                    e.into()
+                }
+            })?;
+        let start = dfa.start_state(&start::Config::new().anchored(Anchored::Yes))?;
+        Ok(RegexAutomaton { dfa, start })
+    }
+}
+
+impl Automaton for RegexAutomaton {
+    type State = StateID;
+
+    fn start(&self) -> StateID {
+        self.start
+    }
+
+    fn is_match(&self, state: &StateID) -> bool {
+        // DFA matches are delayed by one byte, so look at the end-of-input
+        // transition to see whether the term read so far is a match
+        self.dfa.is_match_state(self.dfa.next_eoi_state(*state))
+    }
+
+    fn can_match(&self, state: &StateID) -> bool {
+        !self.dfa.is_dead_state(*state)
+    }
+
+    fn accept(&self, state: &StateID, byte: u8) -> StateID {
+        self.dfa.next_state(*state, byte)
+    }
+}
+
+/// Matches terms within `max_dist` edits of a query word, using the same
+/// optimal-string-alignment distance as `search::damerau_levenshtein`
+/// (insertions, deletions, substitutions and adjacent transpositions).
+///
+/// Each state carries the last two rows of the edit-distance matrix, so the
+/// FST only descends into branches whose best row entry is still within
+/// `max_dist`. Distances are counted in chars; the FST feeds bytes, so a
+/// partial UTF-8 sequence is buffered until its char is complete.
+pub struct DamerauAutomaton {
+    query: Vec<char>,
+    max_dist: usize,
+}
+
+#[derive(Clone)]
+pub struct DamerauState {
+    prev_row: Vec<usize>,
+    row: Vec<usize>,
+    prev_char: Option<char>,
+    pending: Vec<u8>,
+}
+
+impl DamerauAutomaton {
+    pub fn new(query: &str, max_dist: usize) -> Self {
+        DamerauAutomaton {
+            query: query.chars().collect(),
+            max_dist,
+        }
+    }
+
+    fn step(&self, state: &DamerauState, c: char) -> DamerauState {
+        let q = &self.query;
+        let mut row = Vec::with_capacity(q.len() + 1);
+        row.push(state.row[0] + 1);
+        for j in 1..=q.len() {
+            let cost = usize::from(q[j - 1] != c);
+            let mut d = (state.row[j] + 1)
+                .min(row[j - 1] + 1)
+                .min(state.row[j - 1] + cost);
+            // adjacent transposition: the last two term chars swapped
+            if j > 1
+                && !state.prev_row.is_empty()
+                && state.prev_char == Some(q[j - 1])
+                && q[j - 2] == c
+            {
+                d = d.min(state.prev_row[j - 2] + 1);
+            }
+            row.push(d);
+        }
+        DamerauState {
+            prev_row: state.row.clone(),
+            row,
+            prev_char: Some(c),
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl Automaton for DamerauAutomaton {
+    type State = DamerauState;
+
+    fn start(&self) -> DamerauState {
+        DamerauState {
+            prev_row: Vec::new(),
+            row: (0..=self.query.len()).collect(),
+            prev_char: None,
+            pending: Vec::new(),
+        }
+    }
+
+    fn is_match(&self, state: &DamerauState) -> bool {
+        state.pending.is_empty() && state.row[self.query.len()] <= self.max_dist
+    }
+
+    fn can_match(&self, state: &DamerauState) -> bool {
+        state.row.iter().min().is_some_and(|&d| d <= self.max_dist)
+    }
+
+    fn accept(&self, state: &DamerauState, byte: u8) -> DamerauState {
+        let mut pending = state.pending.clone();
+        pending.push(byte);
+        match std::str::from_utf8(&pending) {
+            Ok(s) => self.step(state, s.chars().next().unwrap()),
+            Err(e) if e.error_len().is_none() => DamerauState {
+                pending,
+                ..state.clone()
+            },
+            // invalid UTF-8 can't be a term we care about
+            Err(_) => DamerauState {
+                prev_row: Vec::new(),
+                row: vec![usize::MAX / 2; self.query.len() + 1],
+                prev_char: None,
+                pending: Vec::new(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fst::{IntoStreamer, Set};
+
+    fn dictionary() -> Set<Vec<u8>> {
+        let mut terms = vec![
+            "book",
+            "books",
+            "bookshop",
+            "look",
+            "cook",
+            "کتاب",
+            "کتابخانه",
+            "کتب",
+            "مکتب",
+        ];
+        terms.sort();
+        Set::from_iter(terms).unwrap()
+    }
+
+    fn search<A: Automaton>(automaton: A) -> Vec<String> {
+        dictionary()
+            .search(automaton)
+            .into_stream()
+            .into_strs()
+            .unwrap()
+    }
+
+    #[test]
+    fn regex_matches_whole_terms_only() {
+        let matched = search(RegexAutomaton::new("book").unwrap());
+        assert_eq!(matched, ["book"]);
+        let matched = search(RegexAutomaton::new("[bc]ook.*").unwrap());
+        assert_eq!(matched, ["book", "books", "bookshop", "cook"]);
+        let matched = search(RegexAutomaton::new("کتا.*").unwrap());
+        assert_eq!(matched, ["کتاب", "کتابخانه"]);
+        let matched = search(RegexAutomaton::new(".*کتب").unwrap());
+        // sorted by bytes: م (U+0645) before ک (U+06A9)
+        assert_eq!(matched, ["مکتب", "کتب"]);
+        assert!(search(RegexAutomaton::new("oo").unwrap()).is_empty());
+        assert!(RegexAutomaton::new("(").is_err());
+    }
+
+    #[test]
+    fn exponential_patterns_are_rejected_quickly() {
+        // `.*a` then 16 `.`: the DFA has to remember the last 17 chars
+        let pattern = format!(".*a{}", ".".repeat(16));
+        let start = std::time::Instant::now();
+        let err = RegexAutomaton::new(&pattern).err().unwrap();
+        assert!(err.downcast_ref::<PatternTooComplex>().is_some());
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+        // reasonable wildcards still fit
+        assert!(RegexAutomaton::new(".*ook.*").is_ok());
+        assert!(RegexAutomaton::new(".*a.....").is_ok());
+    }
+
+    #[test]
+    fn damerau_finds_terms_within_the_distance() {
+        // substitution, transposition, insertion and deletion
+        assert_eq!(
+            search(DamerauAutomaton::new("book", 1)),
+            ["book", "books", "cook", "look"]
+        );
+        assert_eq!(search(DamerauAutomaton::new("obok", 1)), ["book"]);
+        assert_eq!(search(DamerauAutomaton::new("bookhsop", 1)), ["bookshop"]);
+        assert!(search(DamerauAutomaton::new("bookhsop", 0)).is_empty());
+        // distances count chars, not UTF-8 bytes
+        assert_eq!(search(DamerauAutomaton::new("کتاپ", 1)), ["کتاب"]);
+        assert_eq!(search(DamerauAutomaton::new("کاتب", 1)), ["کتاب", "کتب"]);
+        assert_eq!(
+            search(DamerauAutomaton::new("کتاب", 2)),
+            ["مکتب", "کتاب", "کتب"]
+        );
+    }
+
+    #[test]
+    fn damerau_agrees_with_the_edit_distance() {
+        let all = dictionary().stream().into_strs().unwrap();
+        for query in ["bokk", "oobk", "کتبا", "مکاتب", "x"] {
+            for max_dist in 0..=2 {
+                let expected: Vec<&String> = all
+                    .iter()
+                    .filter(|t| crate::search::damerau_levenshtein(query, t) <= max_dist)
+                    .collect();
+                let found = search(DamerauAutomaton::new(query, max_dist));
+                assert_eq!(
+                    found.iter().collect::<Vec<_>>(),
+                    expected,
+                    "{} {}",
+                    query,
+                    max_dist
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Little-endian helpers for reading and writing the segment files.
+
+pub fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+pub fn put_u64(buf: &mut Vec<u8>, v: u64) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+pub fn get_u32(buf: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap())
+}
+
+pub fn get_u64(buf: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(buf[pos..pos + 8].try_into().unwrap())
+}
+
+/// Checks that `buf` starts with `magic`, so a file of the wrong kind or an
+/// older format fails loudly instead of decoding garbage.
+pub fn check_magic(buf: &[u8], magic: &[u8; 8], what: &str) -> anyhow::Result<()> {
+    if buf.len() < magic.len() || &buf[..magic.len()] != magic {
+        anyhow::bail!("{} has an unknown format (rebuild the index)", what);
+    }
+    Ok(())
+}
+
+/// Appends `v` as a variable-byte integer: 7 bits per byte, low bits
+/// first, high bit set on every byte except the last.
+pub fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+/// Reads a variable-byte integer at `*pos` and advances `*pos` past it.
+pub fn get_varint(buf: &[u8], pos: &mut usize) -> u64 {
+    let mut v = 0u64;
+    let mut shift = 0;
+    loop {
+        let b = buf[*pos];
+        *pos += 1;
+        v |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return v;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varints_round_trip_at_byte_boundaries() {
+        let values = [
+            0,
+            1,
+            127,
+            128,
+            255,
+            16_383,
+            16_384,
+            u32::MAX as u64,
+            u64::MAX,
+        ];
+        let mut buf = Vec::new();
+        for &v in &values {
+            put_varint(&mut buf, v);
+        }
+        let mut pos = 0;
+        for &v in &values {
+            assert_eq!(get_varint(&buf, &mut pos), v);
+        }
+        assert_eq!(pos, buf.len());
+    }
+
+    #[test]
+    fn varints_take_seven_bits_per_byte() {
+        let len = |v: u64| {
+            let mut buf = Vec::new();
+            put_varint(&mut buf, v);
+            buf.len()
+        };
+        assert_eq!(len(0), 1);
+        assert_eq!(len(127), 1);
+        assert_eq!(len(128), 2);
+        assert_eq!(len(16_383), 2);
+        assert_eq!(len(16_384), 3);
+        assert_eq!(len(u64::MAX), 10);
+
+        let mut buf = Vec::new();
+        put_varint(&mut buf, 300);
+        assert_eq!(buf, [0xac, 0x02]);
+    }
+
+    #[test]
+    fn fixed_width_integers_are_little_endian() {
+        let mut buf = Vec::new();
+        put_u32(&mut buf, 0x0102_0304);
+        put_u64(&mut buf, u64::MAX - 1);
+        assert_eq!(buf[..4], [4, 3, 2, 1]);
+        assert_eq!(get_u32(&buf, 0), 0x0102_0304);
+        assert_eq!(get_u64(&buf, 4), u64::MAX - 1);
+    }
+
+    #[test]
+    fn magic_mismatches_are_errors() {
+        assert!(check_magic(b"SEPOSTS5rest", b"SEPOSTS5", "postings").is_ok());
+        let err = check_magic(b"SEPOSTS4rest", b"SEPOSTS5", "postings").unwrap_err();
+        assert!(err.to_string().contains("rebuild the index"));
+        assert!(check_magic(b"SEP", b"SEPOSTS5", "postings").is_err());
+    }
+}
@@ -0,0 +1,320 @@
+//! Offline relevance evaluation: runs judged queries through `search` and
+//! scores the rankings against the judgments.
+//!
+//! A document is relevant when its grade is above 0. nDCG uses the grades
+//! as gains (2^grade − 1); the other metrics only ask whether a document is
+//! relevant.
+
+use crate::indexer::IndexStore;
+use crate::judgments::Judgments;
+use crate::search::{search, SearchOptions};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Metrics of one query.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct QueryResult {
+    pub qid: String,
+    pub query: String,
+    /// Average precision.
+    pub ap: f64,
+    pub ndcg: f64,
+    pub precision: f64,
+    /// Reciprocal rank of the first relevant hit.
+    pub rr: f64,
+    pub recall: f64,
+}
+
+impl QueryResult {
+    fn metrics(&self) -> [f64; 5] {
+        [self.ap, self.ndcg, self.precision, self.rr, self.recall]
+    }
+}
+
+/// Names of `QueryResult::metrics`, or of their means.
+fn metric_names(k: usize, means: bool) -> [String; 5] {
+    let (ap, rr) = if means { ("MAP", "MRR") } else { ("AP", "RR") };
+    [
+        ap.to_string(),
+        format!("nDCG@{}", k),
+        format!("P@{}", k),
+        rr.to_string(),
+        "Recall".to_string(),
+    ]
+}
+
+/// Metrics of every judged query of one configuration.
+#[derive(Serialize, Deserialize)]
+pub struct Report {
+    /// Cutoff of nDCG and precision.
+    pub k: usize,
+    /// Hits retrieved per query.
+    pub depth: usize,
+    pub queries: Vec<QueryResult>,
+}
+
+impl Report {
+    pub fn run(
+        index: &IndexStore,
+        judgments: &Judgments,
+        options: &SearchOptions,
+        k: usize,
+        depth: usize,
+    ) -> anyhow::Result<Report> {
+        let scorer = options.ranker.scorer(&options.params);
+        let queries = judgments
+            .topics
+            .iter()
+            .filter(|(qid, _)| judgments.is_judged(qid))
+            .map(|(qid, query)| {
+                let hits = search(index, query, depth, options, scorer.as_ref())
+                    .with_context(|| format!("topic {}", qid))?
+                    .hits;
+                let ranked: Vec<i32> = hits
+                    .iter()
+                    .map(|&(doc_id, _)| judgments.grade(qid, &index.doc(doc_id).url))
+                    .collect();
+                Ok(score(qid, query, &ranked, &judgments.grades(qid), k))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Report { k, depth, queries })
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Report> {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("reading results {:?}", path))?;
+        serde_json::from_str(&text).with_context(|| format!("parsing results {:?}", path))
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)? + "\n")
+            .with_context(|| format!("writing results {:?}", path))
+    }
+
+    pub fn print(&self, per_query: bool) {
+        println!(
+            "📋 {} judged queries (k={}, depth={})",
+            self.queries.len(),
+            self.k,
+            self.depth
+        );
+        if per_query {
+            let names = metric_names(self.k, false);
+            println!("{:<10}{}  query", "qid", row(&names));
+            for q in &self.queries {
+                println!(
+                    "{:<10}{}  {}",
+                    q.qid,
+                    row(&q.metrics().map(|m| format!("{:.4}", m))),
+                    q.query
+                );
+            }
+            println!();
+        }
+        let means = means(self.queries.iter());
+        for (name, value) in metric_names(self.k, true).iter().zip(means) {
+            println!("{:<10}{:>10.4}", name, value);
+        }
+    }
+
+    /// Compares this run to `baseline` on the queries both have: the mean
+    /// of every metric, then every query whose nDCG changed, biggest
+    /// change first.
+    pub fn print_diff(&self, baseline: &Report) -> anyhow::Result<()> {
+        if baseline.k != self.k {
+            anyhow::bail!(
+                "baseline was evaluated with k={}, this run with k={}",
+                baseline.k,
+                self.k
+            );
+        }
+        let before: HashMap<&str, &QueryResult> = baseline
+            .queries
+            .iter()
+            .map(|q| (q.qid.as_str(), q))
+            .collect();
+        let pairs: Vec<(&QueryResult, &QueryResult)> = self
+            .queries
+            .iter()
+            .filter_map(|q| Some((*before.get(q.qid.as_str())?, q)))
+            .collect();
+        let skipped = self.queries.len() + baseline.queries.len() - 2 * pairs.len();
+
+        println!("📋 {} queries in both runs (k={})", pairs.len(), self.k);
+        if skipped > 0 {
+            println!("   {} queries in only one of them are left out", skipped);
+        }
+        println!(
+            "{:<10}{:>10}{:>10}{:>10}",
+            "", "baseline", "current", "delta"
+        );
+        let old = means(pairs.iter().map(|(b, _)| *b));
+        let new = means(pairs.iter().map(|(_, c)| *c));
+        for (i, name) in metric_names(self.k, true).iter().enumerate() {
+            println!(
+                "{:<10}{:>10.4}{:>10.4}{:>+10.4}",
+                name,
+                old[i],
+                new[i],
+                new[i] - old[i]
+            );
+        }
+
+        let mut changed: Vec<(&QueryResult, f64)> = pairs
+            .iter()
+            .map(|(b, c)| (*c, c.ndcg - b.ndcg))
+            .filter(|(_, delta)| delta.abs() > 1e-9)
+            .collect();
+        let better = changed.iter().filter(|(_, d)| *d > 0.0).count();
+        println!(
+            "\nnDCG@{}: {} better, {} worse, {} unchanged",
+            self.k,
+            better,
+            changed.len() - better,
+            pairs.len() - changed.len()
+        );
+        changed.sort_by(|a, b| b.1.abs().total_cmp(&a.1.abs()));
+        for (q, delta) in changed {
+            println!(
+                "{:<10}{:>10.4} → {:<8.4}{:>+10.4}  {}",
+                q.qid,
+                q.ndcg - delta,
+                q.ndcg,
+                delta,
+                q.query
+            );
+        }
+        Ok(())
+    }
+}
+
+fn row(cells: &[String]) -> String {
+    cells.iter().map(|c| format!("{:>10}", c)).collect()
+}
+
+fn means<'a>(queries: impl Iterator<Item = &'a QueryResult>) -> [f64; 5] {
+    let mut sums = [0.0; 5];
+    let mut n = 0;
+    for q in queries {
+        for (sum, m) in sums.iter_mut().zip(q.metrics()) {
+            *sum += m;
+        }
+        n += 1;
+    }
+    sums.map(|s| s / n.max(1) as f64)
+}
+
+/// Metrics of a ranking, given the grades of the ranked documents in order
+/// and of every document judged for the query.
+fn score(qid: &str, query: &str, ranked: &[i32], judged: &[i32], k: usize) -> QueryResult {
+    let relevant = judged.iter().filter(|&&g| g > 0).count();
+    let mut found = 0;
+    let mut precision_sum = 0.0;
+    let mut rr = 0.0;
+    for (i, &grade) in ranked.iter().enumerate() {
+        if grade > 0 {
+            found += 1;
+            precision_sum += found as f64 / (i + 1) as f64;
+            if rr == 0.0 {
+                rr = 1.0 / (i + 1) as f64;
+            }
+        }
+    }
+
+    let dcg = |grades: &[i32]| -> f64 {
+        grades
+            .iter()
+            .take(k)
+            .enumerate()
+            .map(|(i, &g)| gain(g) / (i as f64 + 2.0).log2())
+            .sum()
+    };
+    let mut ideal = judged.to_vec();
+    ideal.sort_unstable_by_key(|&g| Reverse(g));
+    let ideal_dcg = dcg(&ideal);
+
+    let ratio = |a: f64, b: usize| if b == 0 { 0.0 } else { a / b as f64 };
+    QueryResult {
+        qid: qid.to_string(),
+        query: query.to_string(),
+        ap: ratio(precision_sum, relevant),
+        ndcg: if ideal_dcg > 0.0 {
+            dcg(ranked) / ideal_dcg
+        } else {
+            0.0
+        },
+        precision: ratio(ranked.iter().take(k).filter(|&&g| g > 0).count() as f64, k),
+        rr,
+        recall: ratio(found as f64, relevant),
+    }
+}
+
+fn gain(grade: i32) -> f64 {
+    if grade > 0 {
+        2f64.powi(grade) - 1.0
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-6,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn metrics_match_hand_computed_values() {
+        // relevant hits at ranks 2, 3 and 5; four relevant documents judged
+        let r = score("1", "q", &[0, 2, 1, 0, 1], &[2, 1, 1, 1, 0], 3);
+        assert_close(r.ap, (1.0 / 2.0 + 2.0 / 3.0 + 3.0 / 5.0) / 4.0);
+        assert_close(r.rr, 0.5);
+        assert_close(r.precision, 2.0 / 3.0);
+        assert_close(r.recall, 0.75);
+        // DCG@3 = 3/log2(3) + 1/log2(4); ideal [2, 1, 1] = 3 + 1/log2(3) + 1/2
+        let dcg = 3.0 / 3f64.log2() + 0.5;
+        let ideal = 3.0 + 1.0 / 3f64.log2() + 0.5;
+        assert_close(r.ndcg, dcg / ideal);
+    }
+
+    #[test]
+    fn perfect_and_empty_rankings() {
+        let r = score("1", "q", &[3, 1, 0], &[1, 0, 3], 2);
+        assert_eq!(r.metrics(), [1.0, 1.0, 1.0, 1.0, 1.0]);
+
+        let r = score("2", "q", &[], &[1, 2], 10);
+        assert_eq!(r.metrics(), [0.0; 5]);
+        // nothing relevant judged: every metric is 0 rather than NaN
+        let r = score("3", "q", &[0, 0], &[0, 0], 10);
+        assert_eq!(r.metrics(), [0.0; 5]);
+    }
+
+    #[test]
+    fn precision_counts_missing_ranks_as_misses() {
+        let r = score("1", "q", &[1], &[1, 1], 5);
+        assert_close(r.precision, 0.2);
+        assert_close(r.ap, 0.5);
+        assert_close(r.recall, 0.5);
+    }
+
+    #[test]
+    fn means_average_every_query() {
+        let a = score("1", "q", &[1], &[1], 1);
+        let b = score("2", "q", &[0, 1], &[1], 1);
+        let m = means([&a, &b].into_iter());
+        assert_close(m[0], 0.75);
+        assert_close(m[2], 0.5);
+        assert_close(m[3], 0.75);
+        assert_eq!(means(std::iter::empty()), [0.0; 5]);
+    }
+}
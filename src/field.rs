@@ -0,0 +1,124 @@
+//! Document fields and their BM25F weights.
+
+use std::str::FromStr;
+
+pub const FIELD_COUNT: usize = 5;
+
+/// Positions skipped between consecutive fields of a document, so phrase
+/// and proximity matching never join the end of one field to the next.
+pub const FIELD_POSITION_GAP: usize = 100;
+
+/// A separately indexed part of a document. Every field gets its own term
+/// frequencies and length, and its positions follow the previous field's
+/// in declaration order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Field {
+    Title,
+    Headings,
+    Body,
+    Url,
+    /// Text of the links on other pages pointing to the document.
+    Anchor,
+}
+
+impl Field {
+    pub const ALL: [Field; FIELD_COUNT] = [
+        Field::Title,
+        Field::Headings,
+        Field::Body,
+        Field::Url,
+        Field::Anchor,
+    ];
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    /// Name used in queries (`title:...`) and on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Field::Title => "title",
+            Field::Headings => "headings",
+            Field::Body => "body",
+            Field::Url => "url",
+            Field::Anchor => "anchor",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Field> {
+        Field::ALL
+            .into_iter()
+            .find(|f| f.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// Per-field boosts applied to term frequencies before BM25 saturation.
+#[derive(Debug, Clone, Copy)]
+pub struct FieldWeights([f64; FIELD_COUNT]);
+
+impl Default for FieldWeights {
+    fn default() -> Self {
+        FieldWeights([5.0, 2.0, 1.0, 2.0, 3.0])
+    }
+}
+
+impl FieldWeights {
+    pub fn get(&self, field: Field) -> f64 {
+        self.0[field.index()]
+    }
+}
+
+/// Parses `title=5,body=1`; fields that are not mentioned keep their
+/// default weight.
+impl FromStr for FieldWeights {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut weights = FieldWeights::default();
+        for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (name, value) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected field=weight, got {:?}", pair))?;
+            let field = Field::from_name(name.trim())
+                .ok_or_else(|| format!("unknown field {:?}", name.trim()))?;
+            let weight: f64 = value
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid weight {:?} for {}", value.trim(), field.name()))?;
+            if weight < 0.0 {
+                return Err(format!("weight for {} must not be negative", field.name()));
+            }
+            weights.0[field.index()] = weight;
+        }
+        Ok(weights)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weights_are_parsed_over_the_defaults() {
+        let weights: FieldWeights = "title=3, BODY=1.5,".parse().unwrap();
+        assert_eq!(weights.get(Field::Title), 3.0);
+        assert_eq!(weights.get(Field::Body), 1.5);
+        assert_eq!(
+            weights.get(Field::Anchor),
+            FieldWeights::default().get(Field::Anchor)
+        );
+        assert_eq!(
+            "".parse::<FieldWeights>().unwrap().0,
+            FieldWeights::default().0
+        );
+    }
+
+    #[test]
+    fn bad_weights_are_rejected() {
+        let err = |s: &str| s.parse::<FieldWeights>().unwrap_err();
+        assert_eq!(err("author=2"), "unknown field \"author\"");
+        assert_eq!(err("title=heavy"), "invalid weight \"heavy\" for title");
+        assert_eq!(err("title"), "expected field=weight, got \"title\"");
+        assert_eq!(err("body=-1"), "weight for body must not be negative");
+    }
+}
@@ -1,133 +1,1027 @@
-use crate::parser::Page;
+use crate::analysis::{self, AnalysisConfig, Analyzer};
+use crate::automaton::{PatternTooComplex, RegexAutomaton};
+use crate::field::{Field, FIELD_COUNT, FIELD_POSITION_GAP};
+use crate::ngram;
+use crate::pagerank::PageRank;
+use crate::parser::{Link, Page};
+use crate::segment::{IndexWriter, Manifest, MergePolicy, Segment, SegmentBuilder};
+use fst::automaton::{AlwaysMatch, Str};
+use fst::map::OpBuilder;
+use fst::{Automaton, IntoStreamer, Streamer};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::BufWriter;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
 use std::path::Path;
+use std::time::UNIX_EPOCH;
 use walkdir::WalkDir;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Posting {
     pub doc_id: usize,
     pub tf: usize,
+    /// Term frequency in each field, in `Field::ALL` order; sums to `tf`.
+    pub field_tf: [usize; FIELD_COUNT],
+    /// Positions in all fields, sorted. Fields occupy increasing position
+    /// ranges, so each field's positions form a contiguous run.
     pub positions: Vec<usize>,
 }
 
+impl Posting {
+    /// Positions of the term within `field`.
+    pub fn field_positions(&self, field: Field) -> &[usize] {
+        let start: usize = self.field_tf[..field.index()].iter().sum();
+        &self.positions[start..start + self.field_tf[field.index()]]
+    }
+}
+
+/// Postings of one document keyed by term, before the document has an id.
+pub type DocTerms = HashMap<String, Posting>;
+
+/// Term → the word one document most often wrote it as.
+pub type Surfaces = HashMap<String, String>;
+
+/// The form with the highest count; ties go to the smallest form, so the
+/// choice does not depend on hash order.
+pub fn most_common_form(counts: HashMap<String, usize>) -> String {
+    counts
+        .into_iter()
+        .max_by(|a, b| a.1.cmp(&b.1).then_with(|| b.0.cmp(&a.0)))
+        .map(|(form, _)| form)
+        .unwrap_or_default()
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DocMeta {
     pub url: String,
     pub title: String,
     pub body: String,
     pub length: usize,
+    /// Number of tokens in each field, in `Field::ALL` order.
+    pub field_lengths: [usize; FIELD_COUNT],
+    /// Source file the document was parsed from, used by `update`.
+    pub path: String,
+    /// Modification time of `path` in nanoseconds since the Unix epoch.
+    pub mtime: u64,
+    pub content_hash: u64,
+    /// The page's links, kept so `update` can rebuild the link graph
+    /// without re-parsing unchanged files.
+    pub links: Vec<Link>,
+    /// PageRank when the document was indexed (see `pagerank`).
+    pub pagerank: f64,
 }
 
-#[derive(Serialize, Deserialize)]
+/// Read-only view over every segment of an index directory. Doc ids are
+/// global: each segment's local ids are offset by the sizes of the segments
+/// before it in the manifest.
+///
+/// Opening only reads the manifest and maps the segment files, so startup
+/// time does not depend on the size of the index.
 pub struct IndexStore {
-    pub dict: HashMap<String, Vec<Posting>>,
-    pub docs: Vec<DocMeta>,
+    segments: Vec<Segment>,
+    bases: Vec<usize>,
+    /// Manifest generation this view was opened at.
+    pub generation: u64,
+    /// Number of live (non-deleted) documents.
     pub doc_count: usize,
+    pub analyzer: Box<dyn Analyzer>,
+    /// `analyzer` without stopword removal, if the index has stopwords.
+    unfiltered: Option<Box<dyn Analyzer>>,
+    avg_field_len: [f64; FIELD_COUNT],
 }
 
 impl IndexStore {
-    pub fn new() -> Self {
-        IndexStore {
-            dict: HashMap::new(),
-            docs: Vec::new(),
-            doc_count: 0,
+    pub fn open(dir: &Path) -> anyhow::Result<Self> {
+        // a concurrent commit may remove files of the manifest we just read;
+        // re-reading the manifest picks up the generation that replaced it
+        let mut attempts = 0;
+        loop {
+            match Self::open_once(dir) {
+                Ok(s) => return Ok(s),
+                Err(e) if attempts >= 3 => return Err(e),
+                Err(_) => attempts += 1,
+            }
+        }
+    }
+
+    fn open_once(dir: &Path) -> anyhow::Result<Self> {
+        let manifest = Manifest::load(dir)?;
+        let segments = manifest
+            .segments
+            .iter()
+            .map(|info| Segment::open(dir, info))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let mut bases = Vec::with_capacity(segments.len());
+        let mut next_base = 0;
+        for info in &manifest.segments {
+            bases.push(next_base);
+            next_base += info.doc_count;
+        }
+
+        let total_docs: usize = manifest.segments.iter().map(|s| s.doc_count).sum();
+        let mut avg_field_len = [0.0; FIELD_COUNT];
+        for (f, avg) in avg_field_len.iter_mut().enumerate() {
+            let field_len: usize = manifest.segments.iter().map(|s| s.field_len[f]).sum();
+            *avg = field_len as f64 / total_docs.max(1) as f64;
+        }
+
+        let unfiltered = if manifest.analysis.stopwords.is_empty() {
+            None
+        } else {
+            let config = AnalysisConfig {
+                stopwords: Vec::new(),
+                ..manifest.analysis.clone()
+            };
+            Some(config.build()?)
+        };
+
+        Ok(IndexStore {
+            segments,
+            bases,
+            generation: manifest.generation,
+            doc_count: manifest.segments.iter().map(|s| s.live_docs()).sum(),
+            analyzer: manifest.analysis.build()?,
+            unfiltered,
+            avg_field_len,
+        })
+    }
+
+    /// Whether `text` has words but every one of them is a stopword, so
+    /// nothing of it was indexed.
+    pub fn only_stopwords(&self, text: &str) -> bool {
+        self.unfiltered
+            .as_ref()
+            .is_some_and(|a| self.analyzer.terms(text).is_empty() && !a.terms(text).is_empty())
+    }
+
+    fn locate(&self, doc_id: usize) -> (&Segment, usize) {
+        let i = self.bases.partition_point(|&b| b <= doc_id) - 1;
+        (&self.segments[i], doc_id - self.bases[i])
+    }
+
+    pub fn is_live(&self, doc_id: usize) -> bool {
+        let (seg, local) = self.locate(doc_id);
+        seg.is_live(local)
+    }
+
+    pub fn doc(&self, doc_id: usize) -> DocMeta {
+        let (seg, local) = self.locate(doc_id);
+        seg.doc(local)
+    }
+
+    pub fn field_len(&self, doc_id: usize, field: Field) -> usize {
+        let (seg, local) = self.locate(doc_id);
+        seg.field_len(local, field)
+    }
+
+    pub fn pagerank(&self, doc_id: usize) -> f64 {
+        let (seg, local) = self.locate(doc_id);
+        seg.pagerank(local)
+    }
+
+    pub fn avg_field_len(&self, field: Field) -> f64 {
+        self.avg_field_len[field.index()]
+    }
+
+    /// Decodes the postings of `term` across all segments with global doc
+    /// ids, sorted by doc id. Deleted documents are not filtered out.
+    pub fn postings(&self, term: &str) -> impl Iterator<Item = Posting> + '_ {
+        let term = term.to_string();
+        self.segments
+            .iter()
+            .zip(&self.bases)
+            .flat_map(move |(seg, &base)| {
+                seg.postings(&term).into_iter().flatten().map(move |mut p| {
+                    p.doc_id += base;
+                    p
+                })
+            })
+    }
+
+    /// Number of documents (deleted ones included) containing `term`.
+    pub fn doc_freq(&self, term: &str) -> usize {
+        self.segments
+            .iter()
+            .filter_map(|seg| seg.term_dict().get(term).map(|o| seg.doc_freq_at(o)))
+            .sum()
+    }
+
+    /// Calls `f` once per distinct term, in sorted order, with its document
+    /// frequency summed over all segments.
+    pub fn for_each_term(&self, f: impl FnMut(&str, usize)) {
+        self.terms_matching(AlwaysMatch, f);
+    }
+
+    /// The word `term` is most often written as in the documents, such as
+    /// "برنامه‌نویس" for the term "برنامهنویس", or `None` if that is the
+    /// term itself.
+    pub fn surface_form(&self, term: &str) -> Option<String> {
+        self.segments
+            .iter()
+            .filter_map(|seg| {
+                let offset = seg.term_dict().get(term)?;
+                Some((seg.doc_freq_at(offset), seg.surface_at(offset)))
+            })
+            .max_by_key(|(df, _)| *df)
+            .and_then(|(_, form)| form)
+    }
+
+    /// Terms starting with `prefix`, in sorted order.
+    pub fn terms_with_prefix(&self, prefix: &str, f: impl FnMut(&str, usize)) {
+        self.terms_matching(Str::new(prefix).starts_with(), f);
+    }
+
+    /// Terms within `lower..upper`, in sorted order.
+    pub fn terms_in_range(
+        &self,
+        lower: Bound<&str>,
+        upper: Bound<&str>,
+        f: impl FnMut(&str, usize),
+    ) {
+        let mut op = OpBuilder::new();
+        for seg in &self.segments {
+            let range = seg.term_dict().range();
+            let range = match lower {
+                Bound::Included(t) => range.ge(t),
+                Bound::Excluded(t) => range.gt(t),
+                Bound::Unbounded => range,
+            };
+            let range = match upper {
+                Bound::Included(t) => range.le(t),
+                Bound::Excluded(t) => range.lt(t),
+                Bound::Unbounded => range,
+            };
+            op = op.add(range);
         }
+        self.union_terms(op, f);
     }
 
-    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
-        let f = File::create(path)?;
-        let mut bw = BufWriter::with_capacity(1024 * 1024, f);
-        bincode::serialize_into(&mut bw, &self)?;
+    /// Terms fully matched by the regular expression `pattern`.
+    pub fn terms_matching_regex(
+        &self,
+        pattern: &str,
+        f: impl FnMut(&str, usize),
+    ) -> anyhow::Result<()> {
+        self.terms_matching(RegexAutomaton::new(pattern)?, f);
         Ok(())
     }
 
-    pub fn load(path: &Path) -> anyhow::Result<Self> {
-        let f = File::open(path)?;
-        let br = std::io::BufReader::new(f);
-        let s: IndexStore = bincode::deserialize_from(br)?;
-        Ok(s)
+    /// Terms matching a wildcard pattern (`*` stands for any run of
+    /// characters), in sorted order. Segments with an n-gram index only
+    /// check the terms sharing the pattern's grams, one by one with a lazy
+    /// matcher; others, and patterns too short to have grams, fall back to
+    /// walking the dictionary with a DFA, built only if some segment needs
+    /// it.
+    pub fn terms_matching_wildcard(
+        &self,
+        pattern: &str,
+        mut f: impl FnMut(&str, usize),
+    ) -> anyhow::Result<()> {
+        let grams = ngram::pattern_grams(pattern);
+        let regex = ngram::wildcard_regex(pattern);
+        let verify = regex::Regex::new(&format!("^(?:{})$", regex)).map_err(|e| match e {
+            regex::Error::CompiledTooBig(_) => anyhow::Error::from(PatternTooComplex),
+            e => e.into(),
+        })?;
+        let mut automaton: Option<RegexAutomaton> = None;
+
+        let mut found: BTreeMap<String, usize> = BTreeMap::new();
+        for seg in &self.segments {
+            let candidates = if grams.is_empty() {
+                None
+            } else {
+                seg.terms_with_grams(&grams)
+            };
+            match candidates {
+                Some(terms) => {
+                    for term in terms.into_iter().filter(|t| verify.is_match(t)) {
+                        let df = seg.term_dict().get(&term).map_or(0, |o| seg.doc_freq_at(o));
+                        *found.entry(term).or_default() += df;
+                    }
+                }
+                None => {
+                    let automaton = match &mut automaton {
+                        Some(automaton) => automaton,
+                        empty => empty.insert(RegexAutomaton::new(&regex)?),
+                    };
+                    let mut stream = seg.term_dict().search(&*automaton).into_stream();
+                    while let Some((term, offset)) = stream.next() {
+                        let term = String::from_utf8_lossy(term).into_owned();
+                        *found.entry(term).or_default() += seg.doc_freq_at(offset);
+                    }
+                }
+            }
+        }
+        for (term, df) in found {
+            f(&term, df);
+        }
+        Ok(())
+    }
+
+    /// Terms accepted by `automaton`, in sorted order, with their summed
+    /// document frequency.
+    pub fn terms_matching<A: Automaton>(&self, automaton: A, f: impl FnMut(&str, usize)) {
+        let mut op = OpBuilder::new();
+        for seg in &self.segments {
+            op = op.add(seg.term_dict().search(&automaton));
+        }
+        self.union_terms(op, f);
+    }
+
+    fn union_terms(&self, op: OpBuilder<'_>, mut f: impl FnMut(&str, usize)) {
+        let mut stream = op.union();
+        while let Some((term, hits)) = stream.next() {
+            let df = hits
+                .iter()
+                .map(|hit| self.segments[hit.index].doc_freq_at(hit.value))
+                .sum();
+            f(&String::from_utf8_lossy(term), df);
+        }
+    }
+
+    pub fn term_count(&self) -> usize {
+        let mut n = 0;
+        self.for_each_term(|_, _| n += 1);
+        n
+    }
+
+    pub fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// Live documents of every segment, as (segment name, local id, meta).
+    fn live_docs(&self) -> impl Iterator<Item = (&str, usize, DocMeta)> + '_ {
+        self.segments.iter().flat_map(|seg| {
+            (0..seg.doc_count())
+                .filter(|&local| seg.is_live(local))
+                .map(move |local| (seg.info.name.as_str(), local, seg.doc(local)))
+        })
+    }
+}
+
+fn modified_nanos(meta: &std::fs::Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+/// 64-bit FNV-1a. Stable across builds, unlike `DefaultHasher`, which matters
+/// because the hash is persisted in the index.
+fn content_hash(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for &b in bytes {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x100000001b3);
     }
+    h
+}
+
+fn parse_file(bytes: Vec<u8>, url: &str) -> Page {
+    String::from_utf8(bytes)
+        .ok()
+        .and_then(|html| crate::parser::parse_html(&html, url).ok())
+        .unwrap_or_else(|| Page {
+            url: url.to_string(),
+            title: "".into(),
+            headings: "".into(),
+            body: "".into(),
+            links: vec![],
+        })
+}
+
+/// URL and links of the page at `path`, for the link graph.
+fn page_links(path: &Path) -> (String, Vec<Link>) {
+    let url = path.to_string_lossy().to_string();
+    let page = parse_file(std::fs::read(path).unwrap_or_default(), &url);
+    (page.url, page.links)
+}
+
+fn index_file(
+    path: &Path,
+    mtime: u64,
+    analyzer: &dyn Analyzer,
+    graph: &LinkGraph,
+) -> (DocMeta, DocTerms, Surfaces) {
+    let bytes = std::fs::read(path).unwrap_or_default();
+    let hash = content_hash(&bytes);
+    let url = path.to_string_lossy().to_string();
+    let page = parse_file(bytes, &url);
+
+    let mut terms = DocTerms::new();
+    let mut field_lengths = [0; FIELD_COUNT];
+    let mut base = 0;
+    let anchors = graph.anchors.get(&page.url).map_or(&[][..], Vec::as_slice);
+    for field in Field::ALL {
+        // every anchor text is a part of its own, gapped like fields, so a
+        // phrase never spans the texts of two links
+        let parts: Vec<&str> = match field {
+            Field::Title => vec![&page.title],
+            Field::Headings => vec![&page.headings],
+            Field::Body => vec![&page.body],
+            Field::Url => vec![&page.url],
+            Field::Anchor => anchors.iter().map(String::as_str).collect(),
+        };
+        for text in parts {
+            let tokens = analyzer.analyze(text);
+            field_lengths[field.index()] += tokens.len();
+            let mut part_end = 0;
+            for token in tokens {
+                part_end = token.position + 1;
+                let posting = terms.entry(token.text).or_insert_with(|| Posting {
+                    doc_id: 0,
+                    tf: 0,
+                    field_tf: [0; FIELD_COUNT],
+                    positions: Vec::new(),
+                });
+                posting.tf += 1;
+                posting.field_tf[field.index()] += 1;
+                posting.positions.push(base + token.position);
+            }
+            base += part_end + FIELD_POSITION_GAP;
+        }
+    }
+
+    // words that analyze to a single term, counted per written form
+    let mut forms: HashMap<String, HashMap<String, usize>> = HashMap::new();
+    for text in [&page.title, &page.headings, &page.body] {
+        for word in analysis::surface_words(text) {
+            if let [term] = analyzer.terms(word).as_slice() {
+                *forms
+                    .entry(term.clone())
+                    .or_default()
+                    .entry(analysis::display_form(word))
+                    .or_default() += 1;
+            }
+        }
+    }
+    let surfaces: Surfaces = forms
+        .into_iter()
+        .map(|(term, counts)| (term, most_common_form(counts)))
+        .collect();
+
+    let snippet: String = page.body.chars().take(500).collect();
+    let pagerank = graph.ranks.get(&page.url);
+
+    (
+        DocMeta {
+            url: page.url,
+            title: page.title,
+            body: snippet,
+            length: field_lengths.iter().sum(),
+            field_lengths,
+            path: url,
+            mtime,
+            content_hash: hash,
+            links: page.links,
+            pagerank,
+        },
+        terms,
+        surfaces,
+    )
 }
 
-pub fn build_index(input_dir: &Path, out: &Path, limit: Option<usize>) -> anyhow::Result<()> {
-    let entries: Vec<_> = WalkDir::new(input_dir)
+fn walk_files(input_dir: &Path) -> Vec<walkdir::DirEntry> {
+    WalkDir::new(input_dir)
         .into_iter()
         .filter_map(|e| e.ok())
         .filter(|e| e.file_type().is_file())
-        .collect();
+        .collect()
+}
+
+pub fn build_index(
+    input_dir: &Path,
+    out: &Path,
+    limit: Option<usize>,
+    segment_size: usize,
+    policy: &MergePolicy,
+    analysis: AnalysisConfig,
+    ngrams: bool,
+) -> anyhow::Result<()> {
+    let entries = walk_files(input_dir);
 
     println!("Found {} files", entries.len());
     let max_docs = limit.unwrap_or(entries.len());
     let selected = &entries[..entries.len().min(max_docs)];
 
-    let processed_data: Vec<(DocMeta, HashMap<String, Vec<usize>>)> = selected
-        .par_iter()
-        .map(|entry| {
-            let p = entry.path();
-            let page = crate::parser::parse_html_file(p).unwrap_or_else(|_| Page {
-                url: p.to_string_lossy().to_string(),
-                title: "".into(),
-                body: "".into(),
-            });
-
-            let title_tokens = crate::normalize::tokenize(&page.title);
-            let body_tokens = crate::normalize::tokenize(&page.body);
-
-            let mut all_tokens = title_tokens;
-            all_tokens.extend(body_tokens.clone());
-            let length = all_tokens.len();
-
-            let mut pos_map: HashMap<String, Vec<usize>> = HashMap::with_capacity(length / 2);
-            for (pos, term) in all_tokens.into_iter().enumerate() {
-                pos_map.entry(term).or_default().push(pos);
+    let pages: Vec<(String, Vec<Link>)> =
+        selected.par_iter().map(|e| page_links(e.path())).collect();
+    let graph = link_graph(&pages);
+
+    let mut writer = IndexWriter::create(out, analysis, ngrams)?;
+    write_segments(
+        &mut writer,
+        selected.iter().map(|e| e.path()),
+        segment_size,
+        &graph,
+    )?;
+    writer.commit()?;
+
+    let merges = writer.maybe_merge(policy)?;
+    if merges > 0 {
+        println!("Merged segments {} time(s)", merges);
+    }
+
+    let store = IndexStore::open(out)?;
+    println!(
+        "✅ Indexed {} docs in {} segment(s), unique terms: {}",
+        store.doc_count,
+        store.segment_count(),
+        store.term_count()
+    );
+    Ok(())
+}
+
+/// What other pages say about each page, gathered from all links before
+/// any page is indexed.
+struct LinkGraph {
+    ranks: PageRank,
+    /// URL → texts of the links to it from other pages.
+    anchors: HashMap<String, Vec<String>>,
+}
+
+/// Builds the link graph of `pages`, given as (URL, links on the page).
+fn link_graph(pages: &[(String, Vec<Link>)]) -> LinkGraph {
+    let ranks = PageRank::compute(
+        pages
+            .iter()
+            .map(|(url, links)| (url.as_str(), links.as_slice())),
+    );
+    let anchors = incoming_anchors(pages);
+    println!(
+        "🔗 PageRank: {} links between {} docs, {} iterations; {} docs have anchor text",
+        ranks.links,
+        pages.len(),
+        ranks.iterations,
+        anchors.len()
+    );
+    LinkGraph { ranks, anchors }
+}
+
+/// URL → texts of the links to it from other pages.
+fn incoming_anchors(pages: &[(String, Vec<Link>)]) -> HashMap<String, Vec<String>> {
+    let mut anchors: HashMap<String, Vec<String>> = HashMap::new();
+    for (url, links) in pages {
+        for link in links {
+            if link.url != *url && !link.text.is_empty() {
+                anchors
+                    .entry(link.url.clone())
+                    .or_default()
+                    .push(link.text.clone());
             }
+        }
+    }
+    anchors
+}
+
+/// Parses `paths` in parallel batches of `segment_size`, writing each batch
+/// as its own segment so memory use stays bounded by the batch size.
+fn write_segments<'a>(
+    writer: &mut IndexWriter,
+    paths: impl Iterator<Item = &'a Path>,
+    segment_size: usize,
+    graph: &LinkGraph,
+) -> anyhow::Result<()> {
+    let analyzer = writer.manifest().analysis.build()?;
+    let paths: Vec<&Path> = paths.collect();
+    for batch in paths.chunks(segment_size.max(1)) {
+        let processed_data: Vec<(DocMeta, DocTerms, Surfaces)> = batch
+            .par_iter()
+            .map(|p| {
+                let mtime = std::fs::metadata(p)
+                    .map(|m| modified_nanos(&m))
+                    .unwrap_or(0);
+                index_file(p, mtime, analyzer.as_ref(), graph)
+            })
+            .collect();
+
+        let mut builder = SegmentBuilder::new();
+        for (meta, terms, surfaces) in processed_data {
+            builder.add_document(meta, terms, surfaces);
+        }
+        println!("Writing segment with {} docs...", builder.len());
+        writer.add_segment(builder)?;
+    }
+    Ok(())
+}
 
-            let snippet: String = page.body.chars().take(500).collect();
-
-            (
-                DocMeta {
-                    url: page.url,
-                    title: page.title,
-                    body: snippet,
-                    length,
-                },
-                pos_map,
-            )
+#[derive(Default)]
+pub struct UpdateStats {
+    pub added: usize,
+    pub modified: usize,
+    pub deleted: usize,
+    /// Unchanged files reindexed because their incoming links changed.
+    pub relinked: usize,
+    pub unchanged: usize,
+}
+
+/// Relative change of an unchanged document's PageRank that gets it
+/// reindexed. Any link change shifts every score a little; those small
+/// shifts are left for the next full rebuild.
+const PAGERANK_DRIFT: f64 = 0.05;
+
+/// Brings an existing index in line with `input_dir` without re-parsing
+/// unchanged files. Changed and removed documents are marked deleted in
+/// their segments; changed and new files go into new segments. With
+/// `compact`, everything is force-merged into a single segment afterwards.
+///
+/// PageRank and anchor texts are recomputed over the whole link graph.
+/// Segments are immutable, so unchanged documents whose anchor texts or
+/// PageRank (beyond `PAGERANK_DRIFT`) changed are reindexed along with the
+/// modified ones.
+pub fn update_index(
+    input_dir: &Path,
+    index_dir: &Path,
+    segment_size: usize,
+    policy: &MergePolicy,
+    compact: bool,
+) -> anyhow::Result<UpdateStats> {
+    let mut writer = IndexWriter::open(index_dir)?;
+    let store = IndexStore::open(index_dir)?;
+    let mut stats = UpdateStats::default();
+
+    let mut on_disk: HashMap<String, (walkdir::DirEntry, u64)> = walk_files(input_dir)
+        .into_iter()
+        .map(|e| {
+            let mtime = e.metadata().map(|m| modified_nanos(&m)).unwrap_or(0);
+            (e.path().to_string_lossy().to_string(), (e, mtime))
         })
         .collect();
+    println!("Found {} files", on_disk.len());
 
-    let mut store = IndexStore::new();
-    store.docs.reserve(processed_data.len());
+    let mut to_index: Vec<walkdir::DirEntry> = Vec::new();
+    let mut pages: Vec<(String, Vec<Link>)> = Vec::new();
+    // the link graph the index was built with, to tell whose anchor texts
+    // change
+    let mut old_pages: Vec<(String, Vec<Link>)> = Vec::new();
+    let mut unchanged = Vec::new();
 
-    for (doc_id, (meta, pos_map)) in processed_data.into_iter().enumerate() {
-        store.docs.push(meta);
-        for (term, positions) in pos_map {
-            store.dict.entry(term).or_default().push(Posting {
-                doc_id,
-                tf: positions.len(),
-                positions,
-            });
+    for (segment, local_id, doc) in store.live_docs() {
+        old_pages.push((doc.url.clone(), doc.links.clone()));
+        let Some((entry, mtime)) = on_disk.remove(&doc.path) else {
+            writer.delete_document(segment, local_id);
+            stats.deleted += 1;
+            continue;
+        };
+
+        // an unchanged mtime is trusted; a changed one only triggers a
+        // reindex if the content really did change. Segments are immutable,
+        // so a touched-but-identical file is re-hashed on every update until
+        // its segment gets merged.
+        if mtime == doc.mtime
+            || content_hash(&std::fs::read(entry.path()).unwrap_or_default()) == doc.content_hash
+        {
+            unchanged.push((segment, local_id, entry, doc.url.clone(), doc.pagerank));
+            pages.push((doc.url, doc.links));
+        } else {
+            writer.delete_document(segment, local_id);
+            stats.modified += 1;
+            to_index.push(entry);
         }
     }
 
-    store.doc_count = store.docs.len();
+    stats.added = on_disk.len();
+    to_index.extend(on_disk.into_values().map(|(e, _)| e));
+
+    pages.par_extend(to_index.par_iter().map(|e| page_links(e.path())));
+    let graph = link_graph(&pages);
+
+    let old_anchors = incoming_anchors(&old_pages);
+    let sorted_anchors = |anchors: &HashMap<String, Vec<String>>, url: &str| {
+        let mut texts = anchors.get(url).cloned().unwrap_or_default();
+        texts.sort();
+        texts
+    };
+    for (segment, local_id, entry, url, old_rank) in unchanged {
+        if sorted_anchors(&old_anchors, &url) != sorted_anchors(&graph.anchors, &url)
+            || (graph.ranks.get(&url) - old_rank).abs() > PAGERANK_DRIFT * old_rank
+        {
+            writer.delete_document(segment, local_id);
+            stats.relinked += 1;
+            to_index.push(entry);
+        } else {
+            stats.unchanged += 1;
+        }
+    }
+    drop(store);
 
-    store.dict.par_iter_mut().for_each(|(_, postings)| {
-        postings.sort_by_key(|p| p.doc_id);
-    });
+    write_segments(
+        &mut writer,
+        to_index.iter().map(|e| e.path()),
+        segment_size,
+        &graph,
+    )?;
+    writer.commit()?;
 
-    println!("Saving index to {:?}...", out);
-    std::fs::create_dir_all(out.parent().unwrap_or(Path::new(".")))?;
-    store.save(out)?;
+    if compact {
+        writer.force_merge()?;
+    } else {
+        writer.maybe_merge(policy)?;
+    }
 
+    let store = IndexStore::open(index_dir)?;
     println!(
-        "✅ Indexed {} docs, unique terms: {}",
+        "✅ Indexed {} live docs in {} segment(s), unique terms: {}",
         store.doc_count,
-        store.dict.len()
+        store.segment_count(),
+        store.term_count()
     );
-    Ok(())
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::segment::tests::{build, doc, temp_dir};
+
+    /// An index of two segments sharing some terms.
+    fn two_segments(name: &str) -> (std::path::PathBuf, IndexStore) {
+        two_segments_with(name, false)
+    }
+
+    fn two_segments_with(name: &str, ngrams: bool) -> (std::path::PathBuf, IndexStore) {
+        let dir = temp_dir(name);
+        let mut writer = IndexWriter::create(&dir, AnalysisConfig::default(), ngrams).unwrap();
+        writer
+            .add_segment(build(vec![
+                doc("a", &["book", "cook"]),
+                doc("b", &["books", "book"]),
+            ]))
+            .unwrap();
+        writer
+            .add_segment(build(vec![doc("c", &["book", "bookshop", "کتاب"])]))
+            .unwrap();
+        writer.commit().unwrap();
+        let store = IndexStore::open(&dir).unwrap();
+        (dir, store)
+    }
+
+    fn collect(lookup: impl FnOnce(&mut dyn FnMut(&str, usize))) -> Vec<(String, usize)> {
+        let mut out = Vec::new();
+        lookup(&mut |term, df| out.push((term.to_string(), df)));
+        out
+    }
+
+    fn terms(found: &[(String, usize)]) -> Vec<&str> {
+        found.iter().map(|(t, _)| t.as_str()).collect()
+    }
+
+    #[test]
+    fn dictionary_lookups_merge_segments() {
+        let (dir, store) = two_segments("dictionary");
+        assert_eq!(store.doc_freq("book"), 3);
+        assert_eq!(store.term_count(), 5);
+
+        let found = collect(|f| store.terms_with_prefix("book", f));
+        assert_eq!(
+            found,
+            [
+                ("book".to_string(), 3),
+                ("books".to_string(), 1),
+                ("bookshop".to_string(), 1)
+            ]
+        );
+
+        let found =
+            collect(|f| store.terms_in_range(Bound::Excluded("book"), Bound::Included("cook"), f));
+        assert_eq!(terms(&found), ["books", "bookshop", "cook"]);
+        let found = collect(|f| store.terms_in_range(Bound::Included("z"), Bound::Unbounded, f));
+        assert_eq!(terms(&found), ["کتاب"]);
+
+        let found = collect(|f| store.terms_matching_regex("[bc]ook", f).unwrap());
+        assert_eq!(found, [("book".to_string(), 3), ("cook".to_string(), 1)]);
+        assert!(store.terms_matching_regex("(", |_, _| {}).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn wildcards_match_with_or_without_an_ngram_index() {
+        for ngrams in [false, true] {
+            let (dir, store) = two_segments_with(&format!("wildcard-{}", ngrams), ngrams);
+            let wildcard =
+                |pattern: &str| collect(|f| store.terms_matching_wildcard(pattern, f).unwrap());
+            assert_eq!(
+                wildcard("book*"),
+                [
+                    ("book".to_string(), 3),
+                    ("books".to_string(), 1),
+                    ("bookshop".to_string(), 1)
+                ]
+            );
+            assert_eq!(terms(&wildcard("*ook")), ["book", "cook"]);
+            assert_eq!(terms(&wildcard("*oks*")), ["books", "bookshop"]);
+            assert_eq!(terms(&wildcard("b*p")), ["bookshop"]);
+            assert_eq!(terms(&wildcard("?ook")), ["book", "cook"]);
+            assert_eq!(terms(&wildcard("book?")), ["books"]);
+            assert_eq!(terms(&wildcard("کت*")), ["کتاب"]);
+            assert!(wildcard("*xyz*").is_empty());
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn ngram_candidates_are_checked_without_a_dfa() {
+        // far too big for a DFA, but the grams of `book` leave few terms
+        let pattern = format!("book*a{}", "?".repeat(16));
+        for ngrams in [false, true] {
+            let (dir, store) = two_segments_with(&format!("wildcard-dfa-{}", ngrams), ngrams);
+            let found = store.terms_matching_wildcard(&pattern, |_, _| {});
+            if ngrams {
+                assert!(found.is_ok());
+            } else {
+                assert!(found.err().unwrap().is::<PatternTooComplex>());
+            }
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    /// Writes `files` as HTML pages into `dir`.
+    fn write_pages(dir: &Path, files: &[(&str, &str)]) {
+        for (name, html) in files {
+            std::fs::write(dir.join(name), html).unwrap();
+        }
+    }
+
+    /// Moves the mtime of `dir/name` forward, as an editor saving it would.
+    fn touch(dir: &Path, name: &str) {
+        let file = std::fs::File::options()
+            .write(true)
+            .open(dir.join(name))
+            .unwrap();
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(10);
+        file.set_modified(later).unwrap();
+    }
+
+    /// File names of the live documents containing `word`.
+    fn found(store: &IndexStore, word: &str) -> Vec<String> {
+        let [term] = store.analyzer.terms(word).try_into().unwrap();
+        let mut names: Vec<String> = store
+            .postings(&term)
+            .filter(|p| store.is_live(p.doc_id))
+            .map(|p| {
+                let url = store.doc(p.doc_id).url;
+                url.rsplit('/').next().unwrap().to_string()
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn update_reindexes_changed_files_and_their_link_targets() {
+        let root = temp_dir("update");
+        let (input, out) = (root.join("input"), root.join("index"));
+        std::fs::create_dir_all(&input).unwrap();
+        write_pages(
+            &input,
+            &[
+                ("a.html", "<p>start <a href='b.html'>guide</a></p>"),
+                ("b.html", "<p>second page</p>"),
+                ("c.html", "<p>third page</p>"),
+                ("d.html", "<p>fourth page</p>"),
+                ("f.html", "<p>doomed page <a href='c.html'></a></p>"),
+            ],
+        );
+        // keep the deletes in place instead of merging them away
+        let policy = MergePolicy {
+            merge_factor: 10,
+            max_deleted_ratio: 1.0,
+        };
+        build_index(
+            &input,
+            &out,
+            None,
+            100,
+            &policy,
+            AnalysisConfig::default(),
+            false,
+        )
+        .unwrap();
+        let store = IndexStore::open(&out).unwrap();
+        assert_eq!(store.doc_count, 5);
+        assert_eq!(found(&store, "guide"), ["a.html", "b.html"]);
+        drop(store);
+
+        // a's link text changes, so b is relinked; e replaces f, linking to c
+        // the same way, so no PageRank moves; d is saved unchanged
+        write_pages(
+            &input,
+            &[
+                ("a.html", "<p>start <a href='b.html'>manual</a></p>"),
+                ("e.html", "<p>fresh page <a href='c.html'></a></p>"),
+            ],
+        );
+        touch(&input, "a.html");
+        touch(&input, "d.html");
+        std::fs::remove_file(input.join("f.html")).unwrap();
+
+        let stats = update_index(&input, &out, 100, &policy, false).unwrap();
+        assert_eq!(stats.added, 1);
+        assert_eq!(stats.modified, 1);
+        assert_eq!(stats.deleted, 1);
+        assert_eq!(stats.relinked, 1);
+        assert_eq!(stats.unchanged, 2);
+
+        let store = IndexStore::open(&out).unwrap();
+        assert_eq!(store.doc_count, 5);
+        assert_eq!(
+            found(&store, "page"),
+            ["b.html", "c.html", "d.html", "e.html"]
+        );
+        assert_eq!(found(&store, "manual"), ["a.html", "b.html"]);
+        assert!(found(&store, "guide").is_empty());
+        assert!(found(&store, "doomed").is_empty());
+
+        // the first segment keeps its documents, with a, b and f deleted
+        let first = &store.segments[0];
+        let mut live: Vec<String> = (0..first.doc_count())
+            .filter(|&id| first.is_live(id))
+            .map(|id| first.doc(id).url.rsplit('/').next().unwrap().to_string())
+            .collect();
+        live.sort();
+        assert_eq!(live, ["c.html", "d.html"]);
+        assert_eq!(first.info.del_count, 3);
+        drop(store);
+
+        // a second link to c moves its PageRank well past the drift
+        let rank_of_c = |store: &IndexStore| {
+            store
+                .live_docs()
+                .find(|(_, _, doc)| doc.url.ends_with("/c.html"))
+                .map(|(_, _, doc)| doc.pagerank)
+                .unwrap()
+        };
+        let old_rank = rank_of_c(&IndexStore::open(&out).unwrap());
+        write_pages(&input, &[("g.html", "<p><a href='c.html'></a></p>")]);
+        let stats = update_index(&input, &out, 100, &policy, false).unwrap();
+        assert_eq!(stats.added, 1);
+        assert!(stats.relinked >= 1);
+        let store = IndexStore::open(&out).unwrap();
+        assert!(rank_of_c(&store) > old_rank * (1.0 + PAGERANK_DRIFT));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn documents_are_found_by_the_text_of_links_to_them() {
+        let root = temp_dir("anchors");
+        let (input, out) = (root.join("input"), root.join("index"));
+        std::fs::create_dir_all(&input).unwrap();
+        write_pages(
+            &input,
+            &[
+                ("a.html", "<p>see <a href='b.html'>zebra facts</a></p>"),
+                ("b.html", "<p>striped horses</p>"),
+            ],
+        );
+        let policy = MergePolicy::default();
+        build_index(
+            &input,
+            &out,
+            None,
+            100,
+            &policy,
+            AnalysisConfig::default(),
+            false,
+        )
+        .unwrap();
+
+        // b never says "zebra"; the link from a does, into b's anchor field
+        let anchor_only = |store: &IndexStore, word: &str| -> Vec<String> {
+            let [term] = store.analyzer.terms(word).try_into().unwrap();
+            store
+                .postings(&term)
+                .filter(|p| store.is_live(p.doc_id))
+                .filter(|p| p.tf == p.field_tf[Field::Anchor.index()])
+                .map(|p| {
+                    store
+                        .doc(p.doc_id)
+                        .url
+                        .rsplit('/')
+                        .next()
+                        .unwrap()
+                        .to_string()
+                })
+                .collect()
+        };
+        let store = IndexStore::open(&out).unwrap();
+        assert_eq!(found(&store, "zebra"), ["a.html", "b.html"]);
+        assert_eq!(anchor_only(&store, "zebra"), ["b.html"]);
+        drop(store);
+
+        // rewording the link reindexes b, although b itself is untouched
+        write_pages(
+            &input,
+            &[("a.html", "<p>see <a href='b.html'>equine trivia</a></p>")],
+        );
+        touch(&input, "a.html");
+        let stats = update_index(&input, &out, 100, &policy, false).unwrap();
+        assert_eq!(stats.modified, 1);
+        assert_eq!(stats.relinked, 1);
+        let store = IndexStore::open(&out).unwrap();
+        assert!(found(&store, "zebra").is_empty());
+        assert_eq!(anchor_only(&store, "trivia"), ["b.html"]);
+        std::fs::remove_dir_all(root).unwrap();
+    }
 }
@@ -0,0 +1,158 @@
+//! Relevance judgments: a topics file with the queries and a TREC qrels
+//! file grading documents for them.
+//!
+//! ```text
+//! topics:  q1<TAB>موتور جستجو
+//! qrels:   q1 0 https://example.com/search-engines.html 2
+//! ```
+//!
+//! Documents are identified by their URL, which stays the same when the
+//! index is rebuilt. Unjudged documents count as not relevant.
+
+use anyhow::Context;
+use std::collections::HashMap;
+use std::path::Path;
+
+pub struct Judgments {
+    /// (query id, query), in file order.
+    pub topics: Vec<(String, String)>,
+    /// Query id → document URL → grade.
+    qrels: HashMap<String, HashMap<String, i32>>,
+}
+
+impl Judgments {
+    pub fn load(topics: &Path, qrels: &Path) -> anyhow::Result<Judgments> {
+        Ok(Judgments {
+            topics: load_topics(topics)?,
+            qrels: load_qrels(qrels)?,
+        })
+    }
+
+    /// Whether any document is judged for `qid`.
+    pub fn is_judged(&self, qid: &str) -> bool {
+        self.qrels.contains_key(qid)
+    }
+
+    /// Grades of every document judged for `qid`.
+    pub fn grades(&self, qid: &str) -> Vec<i32> {
+        self.qrels
+            .get(qid)
+            .map(|docs| docs.values().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Grade of `url` for `qid`, 0 if unjudged.
+    pub fn grade(&self, qid: &str, url: &str) -> i32 {
+        self.qrels
+            .get(qid)
+            .and_then(|docs| docs.get(url))
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+/// `query-id<TAB>query` per line.
+fn load_topics(path: &Path) -> anyhow::Result<Vec<(String, String)>> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("reading topics {:?}", path))?;
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(n, line)| {
+            let (qid, query) = line.split_once('\t').with_context(|| {
+                format!(
+                    "{}:{}: expected `query-id<TAB>query`",
+                    path.display(),
+                    n + 1
+                )
+            })?;
+            Ok((qid.trim().to_string(), query.trim().to_string()))
+        })
+        .collect()
+}
+
+/// `query-id iteration doc-id grade` per line; the iteration is ignored.
+fn load_qrels(path: &Path) -> anyhow::Result<HashMap<String, HashMap<String, i32>>> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("reading qrels {:?}", path))?;
+    let mut qrels: HashMap<String, HashMap<String, i32>> = HashMap::new();
+    for (n, line) in text.lines().enumerate() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [] => continue,
+            [qid, _, doc, grade] => {
+                let grade = grade.parse().with_context(|| {
+                    format!("{}:{}: invalid grade {:?}", path.display(), n + 1, grade)
+                })?;
+                qrels
+                    .entry(qid.to_string())
+                    .or_default()
+                    .insert(doc.to_string(), grade);
+            }
+            _ => anyhow::bail!(
+                "{}:{}: expected `query-id iteration doc-id grade`",
+                path.display(),
+                n + 1
+            ),
+        }
+    }
+    Ok(qrels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("judgments-{}-{}", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn topics_and_qrels_are_loaded() {
+        let topics = temp_file("topics", "q1\tموتور جستجو\n\nq2\t rust \nq3\tunjudged\n");
+        let qrels = temp_file(
+            "qrels",
+            "q1 0 https://a/x.html 2\nq1 0 https://a/y.html 0\n\nq2 1 /tmp/c.html 1\n",
+        );
+        let judgments = Judgments::load(&topics, &qrels).unwrap();
+        assert_eq!(
+            judgments.topics,
+            [
+                ("q1".to_string(), "موتور جستجو".to_string()),
+                ("q2".to_string(), "rust".to_string()),
+                ("q3".to_string(), "unjudged".to_string()),
+            ]
+        );
+        assert!(judgments.is_judged("q1"));
+        assert!(!judgments.is_judged("q3"));
+        assert_eq!(judgments.grade("q1", "https://a/x.html"), 2);
+        assert_eq!(judgments.grade("q1", "https://a/z.html"), 0);
+        assert_eq!(judgments.grade("q2", "/tmp/c.html"), 1);
+        let mut grades = judgments.grades("q1");
+        grades.sort();
+        assert_eq!(grades, [0, 2]);
+        assert!(judgments.grades("q3").is_empty());
+        std::fs::remove_file(topics).unwrap();
+        std::fs::remove_file(qrels).unwrap();
+    }
+
+    #[test]
+    fn malformed_lines_name_their_line() {
+        let qrels = temp_file("bad-qrels", "q1 0 /a.html 1\nq1 /b.html 1\n");
+        let err = load_qrels(&qrels).err().unwrap();
+        assert!(err
+            .to_string()
+            .ends_with(":2: expected `query-id iteration doc-id grade`"));
+        std::fs::write(&qrels, "q1 0 /a.html high\n").unwrap();
+        let err = load_qrels(&qrels).err().unwrap();
+        assert!(err.to_string().ends_with(":1: invalid grade \"high\""));
+        std::fs::remove_file(qrels).unwrap();
+
+        let topics = temp_file("bad-topics", "q1 no tab here\n");
+        assert!(load_topics(&topics).is_err());
+        std::fs::remove_file(topics).unwrap();
+    }
+}
@@ -1,12 +1,32 @@
+mod analysis;
+mod api;
+mod automaton;
+mod codec;
+mod eval;
+mod field;
 mod indexer;
-mod normalize;
+mod judgments;
+mod ngram;
+mod pagerank;
 mod parser;
+mod query;
+mod ranking;
+mod rerank;
 mod search;
+mod segment;
 mod server;
+mod spell;
+mod stemmer;
+mod stopwords;
+mod suggest;
+mod synonyms;
+mod translit;
 
-use clap::{Parser as ClapParser, Subcommand};
+use anyhow::Context;
+use clap::{Args, Parser as ClapParser, Subcommand};
+use std::ops::Bound;
 use std::path::PathBuf;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 #[derive(ClapParser)]
 #[command(
@@ -19,17 +39,252 @@ struct Cli {
     command: Commands,
 }
 
+#[derive(Args)]
+struct MergeArgs {
+    /// Number of similarly sized segments that triggers a merge.
+    #[arg(long, default_value_t = 10)]
+    merge_factor: usize,
+
+    /// Rewrite a segment once this fraction of its docs is deleted.
+    #[arg(long, default_value_t = 0.3)]
+    max_deleted_ratio: f64,
+}
+
+impl MergeArgs {
+    fn policy(&self) -> segment::MergePolicy {
+        segment::MergePolicy {
+            merge_factor: self.merge_factor,
+            max_deleted_ratio: self.max_deleted_ratio,
+        }
+    }
+}
+
+/// How queries are expanded and ranked.
+#[derive(Args)]
+struct SearchArgs {
+    /// BM25F field weights, e.g. `title=5,headings=2,body=1,url=2,anchor=3`.
+    /// Fields left out keep their default weight.
+    #[arg(long, default_value = "")]
+    field_weights: field::FieldWeights,
+
+    /// Synonym file applied to queries (see `synonyms` for the format).
+    #[arg(long)]
+    synonyms: Option<PathBuf>,
+
+    /// Also search Latin-script query words in Persian script (and Persian
+    /// words in Latin script) when they are missing or rare in the index.
+    #[arg(long)]
+    transliteration: bool,
+
+    /// Most dictionary terms a wildcard pattern expands to.
+    #[arg(long, default_value_t = 64)]
+    max_wildcard_terms: usize,
+
+    /// How documents matched through a wildcard are scored.
+    #[arg(long, value_enum, default_value_t = search::WildcardScoring::Blended)]
+    wildcard_scoring: search::WildcardScoring,
+
+    /// Ranking model: bm25, bm25plus, tfidf, dirichlet or dfr. Requests
+    /// can pick another with `ranker=`.
+    #[arg(long, default_value = "bm25")]
+    ranker: ranking::Ranker,
+
+    /// Weight of the documents' PageRank, as ln(1 + PageRank), added to
+    /// their text score. 0 ignores links.
+    #[arg(long, default_value_t = 0.0)]
+    pagerank_weight: f64,
+
+    /// BM25 and BM25+ term-frequency saturation. Requests can set it with
+    /// `k1=`, like every ranking parameter.
+    #[arg(long, default_value_t = 1.2)]
+    bm25_k1: f64,
+
+    /// BM25 and BM25+ length normalization, from 0 (none) to 1.
+    #[arg(long, default_value_t = 0.75)]
+    bm25_b: f64,
+
+    /// BM25+ floor on a matching term's score.
+    #[arg(long, default_value_t = 1.0)]
+    bm25_delta: f64,
+
+    /// Dirichlet smoothing.
+    #[arg(long, default_value_t = 2000.0)]
+    dirichlet_mu: f64,
+
+    /// DFR (PL2) length normalization.
+    #[arg(long, default_value_t = 1.0)]
+    dfr_c: f64,
+}
+
+impl SearchArgs {
+    fn options(
+        &self,
+        idx: &indexer::IndexStore,
+        reranker: Option<rerank::Reranker>,
+    ) -> anyhow::Result<search::SearchOptions> {
+        let synonyms = match &self.synonyms {
+            Some(path) => {
+                let loaded = synonyms::Synonyms::load(path, idx.analyzer.as_ref())?;
+                println!("📚 Synonyms: {} entries", loaded.rule_count());
+                loaded
+            }
+            None => synonyms::Synonyms::default(),
+        };
+        let params = ranking::RankingParams {
+            k1: self.bm25_k1,
+            b: self.bm25_b,
+            delta: self.bm25_delta,
+            mu: self.dirichlet_mu,
+            c: self.dfr_c,
+        };
+        params.validate().map_err(anyhow::Error::msg)?;
+        let used: Vec<String> = params
+            .of(self.ranker)
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        println!("📊 Ranker: {} {}", self.ranker.name(), used.join(" "));
+        if self.pagerank_weight != 0.0 {
+            println!("🔗 PageRank weight: {}", self.pagerank_weight);
+        }
+        Ok(search::SearchOptions {
+            weights: self.field_weights,
+            synonyms,
+            transliteration: self.transliteration.then(translit::Transliterator::default),
+            wildcards: search::WildcardOptions {
+                max_terms: self.max_wildcard_terms,
+                scoring: self.wildcard_scoring,
+            },
+            ranker: self.ranker,
+            params,
+            pagerank_weight: self.pagerank_weight,
+            reranker,
+        })
+    }
+}
+
+#[derive(Args)]
+struct RerankArgs {
+    /// Reranking model (see `train`) applied to the best first-stage
+    /// hits.
+    #[arg(long)]
+    rerank_model: Option<PathBuf>,
+
+    /// First-stage hits reordered by the reranking model.
+    #[arg(long, default_value_t = 100)]
+    rerank_depth: usize,
+}
+
+impl RerankArgs {
+    fn load(&self) -> anyhow::Result<Option<rerank::Reranker>> {
+        let Some(path) = &self.rerank_model else {
+            return Ok(None);
+        };
+        let loaded = rerank::Reranker::load(path, self.rerank_depth)?;
+        println!("🎯 Reranking the top {} hits with {:?}", loaded.depth, path);
+        Ok(Some(loaded))
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     Index {
         #[arg(long)]
         input: PathBuf,
 
+        /// Index directory (segments and manifest are written here).
         #[arg(long)]
         out: PathBuf,
 
         #[arg(long)]
         limit: Option<usize>,
+
+        /// Documents per segment written by the indexer.
+        #[arg(long, default_value_t = 10_000)]
+        segment_size: usize,
+
+        /// Analyzer applied to indexed text; queries use the same one.
+        #[arg(
+            long,
+            default_value = analysis::DEFAULT_ANALYZER,
+            value_parser = clap::builder::PossibleValuesParser::new(analysis::ANALYZER_NAMES),
+        )]
+        analyzer: String,
+
+        /// Comma-separated stopword lists: `persian`, `english`, paths of
+        /// word-per-line files, or `none`.
+        #[arg(long, default_value = "persian,english")]
+        stopwords: String,
+
+        /// Whether a ZWNJ splits words or joins compounds like "می‌روم".
+        #[arg(long, value_enum, default_value_t = analysis::ZwnjMode::Join)]
+        zwnj: analysis::ZwnjMode,
+
+        /// Build an n-gram index of the terms so infix wildcard queries
+        /// (`*کتاب*`) don't have to scan the whole dictionary.
+        #[arg(long)]
+        ngrams: bool,
+
+        #[command(flatten)]
+        merge: MergeArgs,
+    },
+    /// Apply added, modified and deleted files to an existing index.
+    Update {
+        #[arg(long)]
+        input: PathBuf,
+
+        #[arg(long)]
+        index: PathBuf,
+
+        /// Force-merge everything into one segment, purging deleted docs.
+        #[arg(long)]
+        compact: bool,
+
+        #[arg(long, default_value_t = 10_000)]
+        segment_size: usize,
+
+        #[command(flatten)]
+        merge: MergeArgs,
+    },
+    /// Run the merge policy (or a full merge) on an index.
+    Merge {
+        #[arg(long)]
+        index: PathBuf,
+
+        /// Merge everything into a single segment.
+        #[arg(long)]
+        force: bool,
+
+        #[command(flatten)]
+        merge: MergeArgs,
+    },
+    /// List dictionary terms with their document frequencies.
+    Terms {
+        #[arg(long)]
+        index: PathBuf,
+
+        /// Only terms starting with this prefix.
+        #[arg(long, conflicts_with_all = ["from", "to", "regex", "wildcard"])]
+        prefix: Option<String>,
+
+        /// Lower bound (inclusive) of a term range.
+        #[arg(long)]
+        from: Option<String>,
+
+        /// Upper bound (exclusive) of a term range.
+        #[arg(long)]
+        to: Option<String>,
+
+        /// Only terms fully matching this regular expression.
+        #[arg(long, conflicts_with_all = ["from", "to", "wildcard"])]
+        regex: Option<String>,
+
+        /// Only terms matching this wildcard pattern, e.g. `*کتاب*`.
+        #[arg(long, conflicts_with_all = ["from", "to"])]
+        wildcard: Option<String>,
+
+        #[arg(long, default_value_t = 100)]
+        limit: usize,
     },
     Serve {
         #[arg(long)]
@@ -37,6 +292,91 @@ enum Commands {
 
         #[arg(long, default_value = "127.0.0.1:8080")]
         host: String,
+
+        /// Seconds between background merge/reload checks (0 disables).
+        #[arg(long, default_value_t = 30)]
+        merge_interval: u64,
+
+        #[command(flatten)]
+        search: SearchArgs,
+
+        #[command(flatten)]
+        rerank: RerankArgs,
+
+        /// File queries with results are appended to; logged queries are
+        /// offered as completions by `/api/suggest`.
+        #[arg(long)]
+        query_log: Option<PathBuf>,
+
+        #[command(flatten)]
+        merge: MergeArgs,
+    },
+    /// Fit a linear reranking model from relevance judgments.
+    Train {
+        #[arg(long)]
+        index: PathBuf,
+
+        /// Training queries, `query-id<TAB>query` per line.
+        #[arg(long)]
+        topics: PathBuf,
+
+        /// TREC qrels grading documents (by URL) for the queries.
+        #[arg(long)]
+        qrels: PathBuf,
+
+        /// Where the model is written.
+        #[arg(long)]
+        out: PathBuf,
+
+        /// First-stage hits per query to learn from; use the depth the
+        /// model will rerank at.
+        #[arg(long, default_value_t = 100)]
+        depth: usize,
+
+        #[command(flatten)]
+        search: SearchArgs,
+    },
+    /// Score rankings against relevance judgments: MAP, nDCG@k, P@k, MRR
+    /// and recall.
+    Eval {
+        #[arg(long)]
+        index: PathBuf,
+
+        /// Queries, `query-id<TAB>query` per line.
+        #[arg(long)]
+        topics: PathBuf,
+
+        /// TREC qrels grading documents (by URL) for the queries.
+        #[arg(long)]
+        qrels: PathBuf,
+
+        /// Cutoff of nDCG and precision.
+        #[arg(long, default_value_t = 10)]
+        k: usize,
+
+        /// Hits retrieved per query; MAP, MRR and recall look this deep.
+        #[arg(long, default_value_t = server::MAX_RESULTS)]
+        depth: usize,
+
+        /// Print every query's metrics, not just the means.
+        #[arg(long)]
+        per_query: bool,
+
+        /// Write the per-query results to this file, to compare a later
+        /// run against with `--baseline`.
+        #[arg(long)]
+        save: Option<PathBuf>,
+
+        /// Results saved by an earlier run; prints how every metric and
+        /// query changed since.
+        #[arg(long)]
+        baseline: Option<PathBuf>,
+
+        #[command(flatten)]
+        search: SearchArgs,
+
+        #[command(flatten)]
+        rerank: RerankArgs,
     },
 }
 
@@ -45,41 +385,253 @@ async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Index { input, out, limit } => {
+        Commands::Index {
+            input,
+            out,
+            limit,
+            segment_size,
+            analyzer,
+            stopwords,
+            zwnj,
+            ngrams,
+            merge,
+        } => {
             println!("🚀 Starting Indexing Process...");
             println!("📂 Input Path: {:?}", input.display());
+            let analysis = analysis::AnalysisConfig {
+                analyzer,
+                stopwords: stopwords::load(&stopwords)?,
+                zwnj,
+            };
+            println!(
+                "🌱 Analyzer: {} ({} stopwords, ZWNJ: {:?})",
+                analysis.analyzer,
+                analysis.stopwords.len(),
+                analysis.zwnj
+            );
 
             let start_time = Instant::now();
 
-            indexer::build_index(&input, &out, limit)?;
+            indexer::build_index(
+                &input,
+                &out,
+                limit,
+                segment_size,
+                &merge.policy(),
+                analysis,
+                ngrams,
+            )?;
 
             let duration = start_time.elapsed();
 
-            let file_size_mb = if let Ok(metadata) = std::fs::metadata(&out) {
-                metadata.len() as f64 / (1024.0 * 1024.0)
-            } else {
-                0.0
-            };
+            let file_size_mb = segment::dir_size(&out) as f64 / (1024.0 * 1024.0);
+            let (raw_postings, postings) = segment::Manifest::load(&out)?.postings_sizes();
+            let mb = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
 
             let separator = "=".repeat(40);
             println!("\n{}", separator);
             println!("✅ Indexing completed successfully.");
             println!("⏱ Time Elapsed: {:.2?}", duration);
-            println!("📦 Index File Size: {:.2} MB", file_size_mb);
+            println!("📦 Index Size: {:.2} MB", file_size_mb);
+            println!(
+                "🗜 Postings: {:.2} MB (raw {:.2} MB, {:.1}x compression)",
+                mb(postings),
+                mb(raw_postings),
+                raw_postings as f64 / postings.max(1) as f64
+            );
             println!("💾 Saved to: {:?}", out.display());
             println!("{}", separator);
         }
-        Commands::Serve { index, host } => {
+        Commands::Update {
+            input,
+            index,
+            compact,
+            segment_size,
+            merge,
+        } => {
+            println!("🔁 Updating index: {:?}", index.display());
+            println!("📂 Input Path: {:?}", input.display());
+
+            let start_time = Instant::now();
+
+            let stats =
+                indexer::update_index(&input, &index, segment_size, &merge.policy(), compact)?;
+
+            let duration = start_time.elapsed();
+
+            let separator = "=".repeat(40);
+            println!("\n{}", separator);
+            println!("✅ Update completed successfully.");
+            println!(
+                "➕ Added: {}  ✏️ Modified: {}  ➖ Deleted: {}  🔗 Relinked: {}  💤 Unchanged: {}",
+                stats.added, stats.modified, stats.deleted, stats.relinked, stats.unchanged
+            );
+            println!("⏱ Time Elapsed: {:.2?}", duration);
+            println!("💾 Saved to: {:?}", index.display());
+            println!("{}", separator);
+        }
+        Commands::Merge {
+            index,
+            force,
+            merge,
+        } => {
+            let start_time = Instant::now();
+            let mut writer = segment::IndexWriter::open(&index)?;
+            let before = writer.manifest().segments.len();
+
+            if force {
+                writer.force_merge()?;
+            } else {
+                writer.maybe_merge(&merge.policy())?;
+            }
+
+            println!(
+                "✅ Segments: {} → {} in {:.2?}",
+                before,
+                writer.manifest().segments.len(),
+                start_time.elapsed()
+            );
+        }
+        Commands::Terms {
+            index,
+            prefix,
+            from,
+            to,
+            regex,
+            wildcard,
+            limit,
+        } => {
+            let idx = indexer::IndexStore::open(&index)?;
+            let mut shown = 0;
+            let mut print = |term: &str, df: usize| {
+                if shown < limit {
+                    println!("{}\t{}", term, df);
+                }
+                shown += 1;
+            };
+
+            if let Some(prefix) = prefix {
+                idx.terms_with_prefix(&prefix, &mut print);
+            } else if let Some(regex) = regex {
+                idx.terms_matching_regex(&regex, &mut print)?;
+            } else if let Some(wildcard) = wildcard {
+                idx.terms_matching_wildcard(&wildcard, &mut print)?;
+            } else {
+                let lower = from.as_deref().map_or(Bound::Unbounded, Bound::Included);
+                let upper = to.as_deref().map_or(Bound::Unbounded, Bound::Excluded);
+                idx.terms_in_range(lower, upper, &mut print);
+            }
+
+            if shown > limit {
+                println!("... {} more", shown - limit);
+            }
+        }
+        Commands::Serve {
+            index,
+            host,
+            merge_interval,
+            merge,
+            search,
+            rerank,
+            query_log,
+        } => {
             println!("🔄 Loading index from: {:?}", index.display());
 
             let start_load = Instant::now();
-            let idx = indexer::IndexStore::load(&index)?;
+            let idx = indexer::IndexStore::open(&index)?;
             let load_duration = start_load.elapsed();
 
-            println!("✅ Index loaded in {:.2?}", load_duration);
+            println!(
+                "✅ Index loaded in {:.2?} ({} segment(s), analyzer: {})",
+                load_duration,
+                idx.segment_count(),
+                idx.analyzer.name()
+            );
+            let options = search.options(&idx, rerank.load()?)?;
+            let suggester = suggest::Suggester::new(query_log.as_deref())?;
+            if query_log.is_some() {
+                println!(
+                    "🕘 Query log: {} distinct queries",
+                    suggester.logged_queries()
+                );
+            }
             println!("🌐 Server is running at: http://{}", host);
 
-            server::run_server(idx, host).await?;
+            server::run_server(
+                idx,
+                index,
+                host,
+                Duration::from_secs(merge_interval),
+                merge.policy(),
+                options,
+                suggester,
+            )
+            .await?;
+        }
+        Commands::Train {
+            index,
+            topics,
+            qrels,
+            out,
+            depth,
+            search,
+        } => {
+            let idx = indexer::IndexStore::open(&index)?;
+            let judgments = judgments::Judgments::load(&topics, &qrels)?;
+            let options = search.options(&idx, None)?;
+
+            let scorer = options.ranker.scorer(&options.params);
+            let mut examples = Vec::new();
+            for (qid, query) in &judgments.topics {
+                if !judgments.is_judged(qid) {
+                    continue;
+                }
+                let hits = search::feature_vectors(&idx, query, depth, &options, scorer.as_ref())
+                    .with_context(|| format!("topic {}", qid))?;
+                examples.push(
+                    hits.into_iter()
+                        .map(|(doc_id, features)| {
+                            let grade = judgments.grade(qid, &idx.doc(doc_id).url).max(0);
+                            (features, grade as f64)
+                        })
+                        .collect::<Vec<_>>(),
+                );
+            }
+            println!("📋 {} judged queries", examples.len());
+
+            let (model, accuracy) = rerank::train_linear(&examples)?;
+            std::fs::write(&out, serde_json::to_string_pretty(&model)? + "\n")?;
+            println!(
+                "✅ Model written to {:?} ({:.1}% of training pairs ordered correctly)",
+                out.display(),
+                accuracy * 100.0
+            );
+        }
+        Commands::Eval {
+            index,
+            topics,
+            qrels,
+            k,
+            depth,
+            per_query,
+            save,
+            baseline,
+            search,
+            rerank,
+        } => {
+            let idx = indexer::IndexStore::open(&index)?;
+            let judgments = judgments::Judgments::load(&topics, &qrels)?;
+            let options = search.options(&idx, rerank.load()?)?;
+
+            let report = eval::Report::run(&idx, &judgments, &options, k, depth)?;
+            match baseline {
+                Some(path) => report.print_diff(&eval::Report::load(&path)?)?,
+                None => report.print(per_query),
+            }
+            if let Some(path) = save {
+                report.save(&path)?;
+                println!("💾 Saved to {:?}", path.display());
+            }
         }
     }
 
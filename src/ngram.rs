@@ -0,0 +1,96 @@
+//! Character n-grams of dictionary terms, used to find the terms matching a
+//! wildcard pattern without scanning the whole dictionary.
+//!
+//! Terms are padded with `^` and `$` (neither can occur in a term, the
+//! tokenizer only keeps letters and digits) and cut into overlapping grams:
+//! "کتاب" → "^کت", "کتا", "تاب", "اب$". The literal pieces of a pattern give
+//! grams every match has to contain; terms sharing all of them are only
+//! candidates, since grams say nothing about order, and are checked against
+//! the full pattern.
+
+/// Characters per gram.
+pub const GRAM_LEN: usize = 3;
+
+/// Matches any run of characters in a wildcard pattern.
+pub const WILDCARD: char = '*';
+
+/// Matches exactly one character in a wildcard pattern.
+pub const ANY_CHAR: char = '?';
+
+/// Whether `word` is a wildcard pattern rather than plain text.
+pub fn is_pattern(word: &str) -> bool {
+    word.contains([WILDCARD, ANY_CHAR])
+}
+
+/// Distinct grams of `term`, sorted.
+pub fn term_grams(term: &str) -> Vec<String> {
+    let padded: Vec<char> = format!("^{}$", term).chars().collect();
+    let mut grams = grams_of(&padded);
+    grams.sort();
+    grams.dedup();
+    grams
+}
+
+/// Grams every term matching `pattern` contains. Empty when the literal
+/// pieces are too short to yield any.
+pub fn pattern_grams(pattern: &str) -> Vec<String> {
+    let padded: Vec<char> = format!("^{}$", pattern).chars().collect();
+    let mut grams: Vec<String> = padded
+        .split(|&c| c == WILDCARD || c == ANY_CHAR)
+        .flat_map(grams_of)
+        .collect();
+    grams.sort();
+    grams.dedup();
+    grams
+}
+
+fn grams_of(chars: &[char]) -> Vec<String> {
+    chars
+        .windows(GRAM_LEN)
+        .map(|w| w.iter().collect())
+        .collect()
+}
+
+/// Regular expression matching the same terms as `pattern` (unanchored;
+/// callers match whole terms).
+pub fn wildcard_regex(pattern: &str) -> String {
+    let mut out = String::new();
+    for c in pattern.chars() {
+        match c {
+            WILDCARD => out.push_str(".*"),
+            ANY_CHAR => out.push('.'),
+            c => out.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn terms_are_padded_into_trigrams() {
+        assert_eq!(term_grams("کتاب"), ["^کت", "اب$", "تاب", "کتا"]);
+        assert_eq!(term_grams("a"), ["^a$"]);
+        assert_eq!(term_grams("aaaa"), ["^aa", "aa$", "aaa"]);
+    }
+
+    #[test]
+    fn patterns_only_yield_grams_of_their_literal_pieces() {
+        assert_eq!(pattern_grams("کتاب*"), ["^کت", "تاب", "کتا"]);
+        assert_eq!(pattern_grams("*ستان"), ["ان$", "تان", "ستا"]);
+        assert_eq!(pattern_grams("ab*cd"), ["^ab", "cd$"]);
+        // too short between the wildcards to say anything
+        assert!(pattern_grams("*ab*").is_empty());
+        assert_eq!(pattern_grams("te?ts"), ["^te", "ts$"]);
+    }
+
+    #[test]
+    fn wildcards_become_escaped_regexes() {
+        assert!(is_pattern("کتاب*") && is_pattern("ک?اب") && !is_pattern("کتاب"));
+        assert_eq!(wildcard_regex("a*b"), "a.*b");
+        assert_eq!(wildcard_regex("te?t"), "te.t");
+        assert_eq!(wildcard_regex("c++*"), r"c\+\+.*");
+    }
+}
@@ -0,0 +1,172 @@
+//! PageRank over the links between indexed documents, computed at index
+//! time and stored with each document as a query-independent score.
+//!
+//! Scores are scaled to average 1 over the documents, so they mean the same
+//! in any size of index: an ordinary page has about 1, a page many pages
+//! link to has more. Links to pages outside the index and links of a page to
+//! itself are ignored; a page without outgoing links spreads its score over
+//! every page.
+
+use crate::parser::Link;
+use std::collections::HashMap;
+
+/// Probability of following a link rather than jumping to a random page.
+const DAMPING: f64 = 0.85;
+const MAX_ITERATIONS: usize = 100;
+/// Iteration stops once the scores move less than this per page on
+/// average, so the same precision is asked of any size of index.
+const TOLERANCE: f64 = 1e-6;
+
+pub struct PageRank {
+    scores: HashMap<String, f64>,
+    /// Links between distinct indexed pages.
+    pub links: usize,
+    pub iterations: usize,
+}
+
+impl PageRank {
+    /// Ranks the pages given as (URL, links on the page).
+    pub fn compute<'a>(pages: impl IntoIterator<Item = (&'a str, &'a [Link])>) -> PageRank {
+        let pages: Vec<(&str, &[Link])> = pages.into_iter().collect();
+        let ids: HashMap<&str, usize> = pages
+            .iter()
+            .enumerate()
+            .map(|(i, &(url, _))| (url, i))
+            .collect();
+        let out: Vec<Vec<usize>> = pages
+            .iter()
+            .enumerate()
+            .map(|(i, (_, links))| {
+                let mut targets: Vec<usize> = links
+                    .iter()
+                    .filter_map(|l| ids.get(l.url.as_str()).copied())
+                    .filter(|&t| t != i)
+                    .collect();
+                targets.sort_unstable();
+                targets.dedup();
+                targets
+            })
+            .collect();
+
+        // iterated at the scale the scores are kept at, summing to n
+        let n = pages.len();
+        let mut rank = vec![1.0; n];
+        let mut iterations = 0;
+        let mut converged = n == 0;
+        while !converged && iterations < MAX_ITERATIONS {
+            iterations += 1;
+            let dangling: f64 = (0..n).filter(|&i| out[i].is_empty()).map(|i| rank[i]).sum();
+            let mut next = vec![1.0 - DAMPING + DAMPING * dangling / n as f64; n];
+            for (i, targets) in out.iter().enumerate() {
+                let share = DAMPING * rank[i] / targets.len().max(1) as f64;
+                for &t in targets {
+                    next[t] += share;
+                }
+            }
+            let change: f64 = rank.iter().zip(&next).map(|(a, b)| (a - b).abs()).sum();
+            rank = next;
+            converged = change < TOLERANCE * n as f64;
+        }
+        if !converged {
+            eprintln!(
+                "⚠️ PageRank stopped after {} iterations without converging",
+                MAX_ITERATIONS
+            );
+        }
+
+        PageRank {
+            scores: pages
+                .iter()
+                .zip(rank)
+                .map(|(&(url, _), r)| (url.to_string(), r))
+                .collect(),
+            links: out.iter().map(Vec::len).sum(),
+            iterations,
+        }
+    }
+
+    /// Score of `url`; 1 for a page that was not ranked.
+    pub fn get(&self, url: &str) -> f64 {
+        self.scores.get(url).copied().unwrap_or(1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(url: &str) -> Link {
+        Link {
+            url: url.to_string(),
+            text: String::new(),
+        }
+    }
+
+    fn rank(graph: &[(&str, &[&str])]) -> PageRank {
+        let pages: Vec<(&str, Vec<Link>)> = graph
+            .iter()
+            .map(|&(url, targets)| (url, targets.iter().map(|t| link(t)).collect()))
+            .collect();
+        PageRank::compute(pages.iter().map(|(url, links)| (*url, links.as_slice())))
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-4,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn scores_average_one() {
+        let pr = rank(&[
+            ("a", &["b", "c"]),
+            ("b", &["c"]),
+            ("c", &["a"]),
+            ("d", &["c"]),
+            ("e", &[]),
+        ]);
+        let total: f64 = ["a", "b", "c", "d", "e"].iter().map(|u| pr.get(u)).sum();
+        assert_close(total, 5.0);
+        assert!(pr.get("c") > pr.get("a"));
+        assert!(pr.iterations < MAX_ITERATIONS);
+    }
+
+    #[test]
+    fn a_cycle_ranks_every_page_the_same() {
+        let pr = rank(&[("a", &["b"]), ("b", &["c"]), ("c", &["a"])]);
+        for url in ["a", "b", "c"] {
+            assert_close(pr.get(url), 1.0);
+        }
+        assert_eq!(pr.links, 3);
+    }
+
+    #[test]
+    fn dangling_pages_spread_their_score_over_every_page() {
+        // a = 0.15 + 0.85 * b / 2, b = 0.15 + 0.85 * a + 0.85 * b / 2
+        let pr = rank(&[("a", &["b"]), ("b", &[])]);
+        assert_close(pr.get("a"), 1.0 / 1.425);
+        assert_close(pr.get("b"), 1.85 / 1.425);
+    }
+
+    #[test]
+    fn a_sink_keeps_all_but_the_random_jumps() {
+        // b and c only link to each other, so a gets nothing but jumps:
+        // a = 0.15, b = 0.15 + 0.85 * (a + c), c = 0.15 + 0.85 * b
+        let pr = rank(&[("a", &["b"]), ("b", &["c"]), ("c", &["b"])]);
+        assert_close(pr.get("a"), 0.15);
+        assert_close(pr.get("b"), 0.405 / 0.2775);
+        assert_close(pr.get("c"), 0.15 + 0.85 * 0.405 / 0.2775);
+    }
+
+    #[test]
+    fn self_outside_and_repeated_links_are_ignored() {
+        let pr = rank(&[("a", &["a", "b", "b", "http://elsewhere/"]), ("b", &["a"])]);
+        assert_eq!(pr.links, 2);
+        assert_close(pr.get("a"), 1.0);
+        assert_close(pr.get("b"), 1.0);
+        assert_eq!(pr.get("not-indexed"), 1.0);
+    }
+}
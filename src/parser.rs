@@ -1,21 +1,33 @@
 use scraper::{Html, Selector};
-use std::fs;
+use serde::{Deserialize, Serialize};
 
 pub struct Page {
     pub url: String,
     pub title: String,
+    /// Text of the `h1`-`h6` elements.
+    pub headings: String,
+    /// Body text outside the headings.
     pub body: String,
+    /// The page's `<a href>` links, without duplicates.
+    pub links: Vec<Link>,
 }
 
-pub fn parse_html_file(path: &std::path::Path) -> anyhow::Result<Page> {
-    let html = fs::read_to_string(path)?;
-    parse_html(&html, &path.to_string_lossy())
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Link {
+    /// Target, resolved against the linking page's URL, without fragment.
+    pub url: String,
+    /// Text between `<a>` and `</a>`, whitespace collapsed.
+    pub text: String,
 }
 
+const HEADING_TAGS: [&str; 6] = ["h1", "h2", "h3", "h4", "h5", "h6"];
+
 pub fn parse_html(html: &str, url: &str) -> anyhow::Result<Page> {
     let document = Html::parse_document(html);
     let selector_title = Selector::parse("title").unwrap();
     let selector_body = Selector::parse("body").unwrap();
+    let selector_headings = Selector::parse(&HEADING_TAGS.join(", ")).unwrap();
+    let selector_links = Selector::parse("a[href]").unwrap();
 
     let title = document
         .select(&selector_title)
@@ -23,15 +35,140 @@ pub fn parse_html(html: &str, url: &str) -> anyhow::Result<Page> {
         .map(|n| n.text().collect::<Vec<_>>().join(" "))
         .unwrap_or_else(|| "".into());
 
-    let body = document
+    let headings = document
+        .select(&selector_headings)
+        .map(|n| n.text().collect::<Vec<_>>().join(" "))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let root = document
         .select(&selector_body)
         .next()
-        .map(|n| n.text().collect::<Vec<_>>().join(" "))
-        .unwrap_or_else(|| document.root_element().text().collect::<Vec<_>>().join(" "));
+        .unwrap_or_else(|| document.root_element());
+    let body = root
+        .descendants()
+        .filter_map(|node| {
+            let text = node.value().as_text()?;
+            let in_heading = node.ancestors().any(|a| {
+                a.value()
+                    .as_element()
+                    .is_some_and(|e| HEADING_TAGS.contains(&e.name()))
+            });
+            (!in_heading).then_some(&**text)
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let mut links: Vec<Link> = Vec::new();
+    for a in document.select(&selector_links) {
+        let Some(target) = a.value().attr("href").and_then(|h| resolve_link(url, h)) else {
+            continue;
+        };
+        let link = Link {
+            url: target,
+            text: a
+                .text()
+                .flat_map(str::split_whitespace)
+                .collect::<Vec<_>>()
+                .join(" "),
+        };
+        if !links.contains(&link) {
+            links.push(link);
+        }
+    }
 
     Ok(Page {
         url: url.to_string(),
         title,
+        headings,
         body,
+        links,
     })
 }
+
+/// Resolves `href` against the URL of the page it appears on, which may be
+/// a web URL or, for crawled files, a file path. Returns `None` for links
+/// that point nowhere indexable (`mailto:`, `javascript:`, a fragment of
+/// the same page).
+pub fn resolve_link(base: &str, href: &str) -> Option<String> {
+    let href = href.trim();
+    let href = href.split('#').next().unwrap_or("");
+    if href.is_empty() {
+        return None;
+    }
+    if let Some((scheme, _)) = href.split_once(':') {
+        let is_scheme = scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+        if is_scheme && !scheme.is_empty() {
+            return href.contains("://").then(|| href.to_string());
+        }
+    }
+
+    // a web URL's path comes after its origin ("https://host"); a file
+    // path has no origin
+    let (origin, base_path) = match base.split_once("://") {
+        Some((scheme, rest)) => {
+            let slash = rest.find('/').unwrap_or(rest.len());
+            (format!("{}://{}", scheme, &rest[..slash]), &rest[slash..])
+        }
+        None => (String::new(), base),
+    };
+    if let Some(rest) = href.strip_prefix("//") {
+        let scheme = base.split_once("://").map_or("https", |(s, _)| s);
+        return Some(format!("{}://{}", scheme, rest));
+    }
+
+    let (path, query) = match href.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (href, None),
+    };
+    let base_path = base_path.split(['?', '#']).next().unwrap_or("");
+    let mut joined = if path.starts_with('/') {
+        path.to_string()
+    } else if path.is_empty() {
+        base_path.to_string()
+    } else {
+        let dir = base_path.rfind('/').map_or("", |i| &base_path[..=i]);
+        format!("{}{}", dir, path)
+    };
+    if !origin.is_empty() && !joined.starts_with('/') {
+        joined.insert(0, '/');
+    }
+
+    let mut out = format!("{}{}", origin, normalize_path(&joined));
+    if let Some(query) = query {
+        out.push('?');
+        out.push_str(query);
+    }
+    Some(out)
+}
+
+/// Removes `.` and empty segments and resolves `..` ones. A `..` above
+/// the start of a relative path is kept; above the root it is dropped.
+fn normalize_path(path: &str) -> String {
+    let absolute = path.starts_with('/');
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                if segments.last().is_some_and(|&s| s != "..") {
+                    segments.pop();
+                } else if !absolute {
+                    segments.push("..");
+                }
+            }
+            _ => segments.push(segment),
+        }
+    }
+    let mut out = segments.join("/");
+    if absolute {
+        out.insert(0, '/');
+    }
+    let last = path.rsplit('/').next().unwrap_or("");
+    if (last.is_empty() || last == "." || last == "..") && !out.ends_with('/') && !out.is_empty() {
+        out.push('/');
+    }
+    out
+}
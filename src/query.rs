@@ -0,0 +1,491 @@
+use crate::analysis::Analyzer;
+use crate::field::Field;
+use crate::ngram::{self, ANY_CHAR, WILDCARD};
+
+/// Parsed boolean query.
+///
+/// Grammar (lowest to highest precedence):
+///
+/// ```text
+/// or    := and ( "OR" and )*
+/// and   := unary ( ["AND"] unary )*
+/// unary := ("NOT" | "-") unary | field ":" unary | "(" or ")" | '"' words '"' | word
+/// ```
+///
+/// `field` is one of the `Field` names, e.g. `title:رمان` or
+/// `title:"رمان فارسی"`. A word containing `*` (any run of characters) or
+/// `?` (one character) is a wildcard pattern matched against the indexed
+/// terms: `برنامه*`, `*کتاب*`, `*ستان`, `te?t`.
+///
+/// Adjacent clauses without an operator are combined with AND, so plain
+/// queries keep their old "every term must match" behaviour.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    Term(String),
+    /// Terms with their offset from the first term; all have to appear at
+    /// those relative positions. Offsets skip over dropped stopwords, so
+    /// "بانک ملی و تجارت" still requires exactly one word between ملی and
+    /// تجارت.
+    Phrase(Vec<(String, usize)>),
+    And(Vec<Query>),
+    Or(Vec<Query>),
+    Not(Box<Query>),
+    /// Sub-query that only matches inside one field.
+    Field(Field, Box<Query>),
+    /// Sub-query whose term scores are multiplied by the factor. Not part
+    /// of the syntax; added by query expansion.
+    Boost(f64, Box<Query>),
+    /// Normalized wildcard pattern; replaced by the terms it matches before
+    /// the query is run.
+    Wildcard(String),
+    /// Sub-query whose matches all get the same score, whatever terms they
+    /// matched and however often. Not part of the syntax.
+    ConstantScore(f64, Box<Query>),
+}
+
+impl Query {
+    /// Terms that contribute to ranking, i.e. every term not under a NOT.
+    pub fn positive_terms(&self) -> Vec<String> {
+        let mut out = Vec::new();
+        for (term, _, _) in self.scored_terms() {
+            if !out.contains(&term) {
+                out.push(term);
+            }
+        }
+        out
+    }
+
+    /// Positive terms with the field they are restricted to, if any, and
+    /// their boost. A term reachable both directly and through a boost
+    /// keeps the higher factor; terms only under a `ConstantScore` get 0.
+    pub fn scored_terms(&self) -> Vec<(String, Option<Field>, f64)> {
+        let mut out = Vec::new();
+        self.collect_positive(None, 1.0, &mut out);
+        out
+    }
+
+    /// Copy of the query with every term (phrase terms included) passed
+    /// through `f`.
+    pub fn map_terms(&self, f: &impl Fn(&str) -> String) -> Query {
+        match self {
+            Query::Term(t) => Query::Term(f(t)),
+            Query::Phrase(terms) => {
+                Query::Phrase(terms.iter().map(|(t, offset)| (f(t), *offset)).collect())
+            }
+            Query::And(children) => Query::And(children.iter().map(|c| c.map_terms(f)).collect()),
+            Query::Or(children) => Query::Or(children.iter().map(|c| c.map_terms(f)).collect()),
+            Query::Not(inner) => Query::Not(Box::new(inner.map_terms(f))),
+            Query::Field(field, inner) => Query::Field(*field, Box::new(inner.map_terms(f))),
+            Query::Boost(weight, inner) => Query::Boost(*weight, Box::new(inner.map_terms(f))),
+            Query::ConstantScore(score, inner) => {
+                Query::ConstantScore(*score, Box::new(inner.map_terms(f)))
+            }
+            Query::Wildcard(_) => self.clone(),
+        }
+    }
+
+    /// Copy of the query with every term outside NOT and phrases replaced
+    /// by `f(term)`.
+    pub fn expand_terms(&self, f: &mut impl FnMut(&str) -> Query) -> Query {
+        match self {
+            Query::Term(t) => f(t),
+            Query::Phrase(_) | Query::Not(_) | Query::Wildcard(_) => self.clone(),
+            Query::And(children) => {
+                Query::And(children.iter().map(|c| c.expand_terms(f)).collect())
+            }
+            Query::Or(children) => Query::Or(children.iter().map(|c| c.expand_terms(f)).collect()),
+            Query::Field(field, inner) => Query::Field(*field, Box::new(inner.expand_terms(f))),
+            Query::Boost(weight, inner) => Query::Boost(*weight, Box::new(inner.expand_terms(f))),
+            Query::ConstantScore(score, inner) => {
+                Query::ConstantScore(*score, Box::new(inner.expand_terms(f)))
+            }
+        }
+    }
+
+    /// Positive `ConstantScore` clauses with the field they are restricted
+    /// to and their score.
+    pub fn constant_clauses(&self) -> Vec<(&Query, Option<Field>, f64)> {
+        let mut out = Vec::new();
+        self.collect_constant(None, &mut out);
+        out
+    }
+
+    fn collect_constant<'a>(
+        &'a self,
+        field: Option<Field>,
+        out: &mut Vec<(&'a Query, Option<Field>, f64)>,
+    ) {
+        match self {
+            Query::And(children) | Query::Or(children) => {
+                for c in children {
+                    c.collect_constant(field, out);
+                }
+            }
+            Query::Field(f, inner) => inner.collect_constant(Some(*f), out),
+            Query::Boost(_, inner) => inner.collect_constant(field, out),
+            Query::ConstantScore(score, inner) => out.push((inner, field, *score)),
+            Query::Term(_) | Query::Phrase(_) | Query::Not(_) | Query::Wildcard(_) => {}
+        }
+    }
+
+    fn collect_positive(
+        &self,
+        field: Option<Field>,
+        boost: f64,
+        out: &mut Vec<(String, Option<Field>, f64)>,
+    ) {
+        let mut add = |t: &String| match out.iter_mut().find(|e| &e.0 == t && e.1 == field) {
+            Some(entry) => entry.2 = entry.2.max(boost),
+            None => out.push((t.clone(), field, boost)),
+        };
+        match self {
+            Query::Term(t) => add(t),
+            Query::Phrase(terms) => {
+                for (t, _) in terms {
+                    add(t);
+                }
+            }
+            Query::And(children) | Query::Or(children) => {
+                for c in children {
+                    c.collect_positive(field, boost, out);
+                }
+            }
+            Query::Not(_) | Query::Wildcard(_) => {}
+            Query::Field(f, inner) => inner.collect_positive(Some(*f), boost, out),
+            Query::Boost(b, inner) => inner.collect_positive(field, boost * b, out),
+            // still listed (e.g. for highlighting), but scored separately
+            Query::ConstantScore(_, inner) => inner.collect_positive(field, 0.0, out),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Phrase(String),
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+}
+
+fn lex(input: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+
+    let flush = |word: &mut String, tokens: &mut Vec<Token>| {
+        if word.is_empty() {
+            return;
+        }
+        let tok = match word.as_str() {
+            "AND" | "&&" => Token::And,
+            "OR" | "||" => Token::Or,
+            "NOT" => Token::Not,
+            _ => Token::Word(word.clone()),
+        };
+        tokens.push(tok);
+        word.clear();
+    };
+
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                flush(&mut word, &mut tokens);
+                // an unterminated quote runs to the end of the input
+                let phrase: String = chars.by_ref().take_while(|&c| c != '"').collect();
+                tokens.push(Token::Phrase(phrase));
+            }
+            '(' | ')' => {
+                flush(&mut word, &mut tokens);
+                tokens.push(if c == '(' {
+                    Token::LParen
+                } else {
+                    Token::RParen
+                });
+            }
+            '-' if word.is_empty() => tokens.push(Token::Not),
+            c if c.is_whitespace() => flush(&mut word, &mut tokens),
+            _ => word.push(c),
+        }
+    }
+    flush(&mut word, &mut tokens);
+    tokens
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    analyzer: &'a dyn Analyzer,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn parse_or(&mut self) -> Option<Query> {
+        let mut clauses = Vec::new();
+        clauses.extend(self.parse_and());
+        while let Some(Token::Or) = self.peek() {
+            self.next();
+            clauses.extend(self.parse_and());
+        }
+        combine(clauses, Query::Or)
+    }
+
+    fn parse_and(&mut self) -> Option<Query> {
+        let mut clauses = Vec::new();
+        loop {
+            match self.peek() {
+                None | Some(Token::Or) | Some(Token::RParen) => break,
+                Some(Token::And) => {
+                    self.next();
+                }
+                _ => clauses.extend(self.parse_unary()),
+            }
+        }
+        combine(clauses, Query::And)
+    }
+
+    /// Query for a plain word: its analyzed terms, or a wildcard pattern.
+    fn parse_word(&self, word: &str) -> Option<Query> {
+        if ngram::is_pattern(word) {
+            // not tokenized or stemmed, only normalized like indexed text
+            let pattern = self.analyzer.normalize(word).to_lowercase();
+            return pattern
+                .chars()
+                .any(|c| c != WILDCARD && c != ANY_CHAR)
+                .then_some(Query::Wildcard(pattern));
+        }
+        combine(
+            self.analyzer
+                .terms(word)
+                .into_iter()
+                .map(Query::Term)
+                .collect(),
+            Query::And,
+        )
+    }
+
+    fn parse_unary(&mut self) -> Option<Query> {
+        match self.next()? {
+            Token::Not => self.parse_unary().map(|q| Query::Not(Box::new(q))),
+            Token::LParen => {
+                let inner = self.parse_or();
+                if let Some(Token::RParen) = self.peek() {
+                    self.next();
+                }
+                inner
+            }
+            Token::Word(w) => {
+                if let (Some(field), rest) = split_field(&w) {
+                    // `title:` directly followed by a phrase or group
+                    let inner = match (rest.is_empty(), self.peek()) {
+                        (true, Some(Token::Phrase(_) | Token::LParen)) => self.parse_unary(),
+                        _ => self.parse_word(rest),
+                    };
+                    return inner.map(|q| Query::Field(field, Box::new(q)));
+                }
+                self.parse_word(&w)
+            }
+            Token::Phrase(text) => {
+                let mut tokens = self.analyzer.analyze(&text);
+                match tokens.len() {
+                    0 => None,
+                    1 => tokens.pop().map(|t| Query::Term(t.text)),
+                    _ => {
+                        let first = tokens[0].position;
+                        Some(Query::Phrase(
+                            tokens
+                                .into_iter()
+                                .map(|t| (t.text, t.position - first))
+                                .collect(),
+                        ))
+                    }
+                }
+            }
+            // stray ')' / operators are ignored rather than failing the query
+            _ => None,
+        }
+    }
+}
+
+/// Splits a `field:` prefix off a query word, if it names a known field.
+pub fn split_field(word: &str) -> (Option<Field>, &str) {
+    match word.split_once(':') {
+        Some((name, rest)) => match Field::from_name(name) {
+            Some(field) => (Some(field), rest),
+            None => (None, word),
+        },
+        None => (None, word),
+    }
+}
+
+fn combine(mut clauses: Vec<Query>, f: fn(Vec<Query>) -> Query) -> Option<Query> {
+    match clauses.len() {
+        0 => None,
+        1 => clauses.pop(),
+        _ => Some(f(clauses)),
+    }
+}
+
+/// Parses a raw user query. Returns `None` when nothing searchable is left
+/// after analysis (e.g. empty input or only punctuation). Words go through
+/// `analyzer`, which has to be the one the index was built with.
+pub fn parse(input: &str, analyzer: &dyn Analyzer) -> Option<Query> {
+    let mut parser = Parser {
+        tokens: lex(input),
+        pos: 0,
+        analyzer,
+    };
+    let mut clauses = Vec::new();
+    while parser.pos < parser.tokens.len() {
+        clauses.extend(parser.parse_or());
+        // unmatched ')' at top level: skip it and keep going
+        if let Some(Token::RParen) = parser.peek() {
+            parser.next();
+        }
+    }
+    combine(clauses, Query::And)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::AnalysisConfig;
+
+    fn standard() -> Box<dyn Analyzer> {
+        AnalysisConfig {
+            analyzer: "standard".to_string(),
+            ..AnalysisConfig::default()
+        }
+        .build()
+        .unwrap()
+    }
+
+    fn term(t: &str) -> Query {
+        Query::Term(t.to_string())
+    }
+
+    fn not(q: Query) -> Query {
+        Query::Not(Box::new(q))
+    }
+
+    fn p(input: &str) -> Option<Query> {
+        parse(input, standard().as_ref())
+    }
+
+    #[test]
+    fn adjacent_words_are_anded() {
+        assert_eq!(
+            p("Rust search"),
+            Some(Query::And(vec![term("rust"), term("search")]))
+        );
+        assert_eq!(
+            p("a AND b && c"),
+            Some(Query::And(vec![term("a"), term("b"), term("c")]))
+        );
+        assert_eq!(p("solo"), Some(term("solo")));
+    }
+
+    #[test]
+    fn or_binds_looser_than_and() {
+        assert_eq!(
+            p("a OR b c"),
+            Some(Query::Or(vec![
+                term("a"),
+                Query::And(vec![term("b"), term("c")])
+            ]))
+        );
+        assert_eq!(
+            p("a AND (b || c)"),
+            Some(Query::And(vec![
+                term("a"),
+                Query::Or(vec![term("b"), term("c")])
+            ]))
+        );
+        // lowercase "or" is a word, not an operator
+        assert_eq!(
+            p("a or b"),
+            Some(Query::And(vec![term("a"), term("or"), term("b")]))
+        );
+    }
+
+    #[test]
+    fn not_and_minus_negate_the_next_clause() {
+        assert_eq!(p("a -b"), Some(Query::And(vec![term("a"), not(term("b"))])));
+        assert_eq!(
+            p("a NOT (b OR c)"),
+            Some(Query::And(vec![
+                term("a"),
+                not(Query::Or(vec![term("b"), term("c")]))
+            ]))
+        );
+        // a hyphen inside a word is not an operator
+        assert_eq!(p("e-mail"), Some(Query::And(vec![term("e"), term("mail")])));
+        assert_eq!(
+            p("a b -c").unwrap().positive_terms(),
+            ["a".to_string(), "b".to_string()]
+        );
+    }
+
+    #[test]
+    fn malformed_input_is_forgiven() {
+        assert_eq!(p("(a OR b"), Some(Query::Or(vec![term("a"), term("b")])));
+        assert_eq!(p("a ) b"), Some(Query::And(vec![term("a"), term("b")])));
+        assert_eq!(p("a OR"), Some(term("a")));
+        assert_eq!(p(""), None);
+        assert_eq!(p("OR AND ( )"), None);
+        assert_eq!(p("!!"), None);
+    }
+
+    #[test]
+    fn quotes_make_phrases_with_offsets() {
+        let phrase = |terms: &[(&str, usize)]| {
+            Query::Phrase(terms.iter().map(|&(t, o)| (t.to_string(), o)).collect())
+        };
+        assert_eq!(p("\"new york\""), Some(phrase(&[("new", 0), ("york", 1)])));
+        assert_eq!(
+            p("times \"new york\" -\"york new\""),
+            Some(Query::And(vec![
+                term("times"),
+                phrase(&[("new", 0), ("york", 1)]),
+                not(phrase(&[("york", 0), ("new", 1)]))
+            ]))
+        );
+        // operators inside quotes are words; an open quote runs to the end
+        assert_eq!(
+            p("a \"b OR c"),
+            Some(Query::And(vec![
+                term("a"),
+                phrase(&[("b", 0), ("or", 1), ("c", 2)])
+            ]))
+        );
+        assert_eq!(p("\"single\""), Some(term("single")));
+        assert_eq!(p("\"\""), None);
+    }
+
+    #[test]
+    fn phrase_offsets_skip_stopwords() {
+        let analyzer = AnalysisConfig {
+            stopwords: vec!["و".to_string()],
+            ..AnalysisConfig::default()
+        }
+        .build()
+        .unwrap();
+        let parsed = parse("\"بانک ملی و تجارت\"", analyzer.as_ref()).unwrap();
+        let Query::Phrase(terms) = parsed else {
+            panic!("not a phrase: {:?}", parsed);
+        };
+        let offsets: Vec<usize> = terms.iter().map(|(_, o)| *o).collect();
+        assert_eq!(offsets, [0, 1, 3]);
+    }
+}
@@ -0,0 +1,382 @@
+//! Ranking functions.
+//!
+//! A `Scorer` turns the statistics of one query term in one document into
+//! a score; a document's score is the sum over the query terms it contains.
+//! Field weights are applied before a scorer sees the counts, so every
+//! model ranks the same weighted bag of fields: BM25 and BM25+ normalize
+//! each field against its own average length (BM25F), the others see one
+//! document whose length is the weighted sum of its field lengths.
+
+use crate::field::{Field, FieldWeights, FIELD_COUNT};
+use std::str::FromStr;
+
+/// Statistics of the whole index.
+pub struct CollectionStats {
+    pub doc_count: usize,
+    pub avg_field_len: [f64; FIELD_COUNT],
+    pub weights: FieldWeights,
+}
+
+impl CollectionStats {
+    /// Average document length, fields weighted.
+    pub fn avg_len(&self) -> f64 {
+        Field::ALL
+            .iter()
+            .map(|&f| self.weights.get(f) * self.avg_field_len[f.index()])
+            .sum()
+    }
+}
+
+/// Statistics of one query term across the index.
+pub struct TermStats {
+    pub df: usize,
+    /// Occurrences in all documents, fields weighted.
+    pub cf: f64,
+}
+
+/// One query term in one document.
+pub struct DocTerm {
+    /// Occurrences per field times the field's weight. Fields outside a
+    /// `field:` restriction are zero.
+    pub field_tf: [f64; FIELD_COUNT],
+    pub field_len: [f64; FIELD_COUNT],
+}
+
+impl DocTerm {
+    pub fn tf(&self) -> f64 {
+        self.field_tf.iter().sum()
+    }
+
+    /// Document length, fields weighted like `CollectionStats::avg_len`.
+    pub fn doc_len(&self, collection: &CollectionStats) -> f64 {
+        Field::ALL
+            .iter()
+            .map(|&f| collection.weights.get(f) * self.field_len[f.index()])
+            .sum()
+    }
+
+    /// Term frequency with each field normalized against its average
+    /// length, as in BM25F.
+    fn bm25f_tf(&self, collection: &CollectionStats, b: f64) -> f64 {
+        (0..FIELD_COUNT)
+            .filter(|&f| self.field_tf[f] > 0.0)
+            .map(|f| {
+                let avg = collection.avg_field_len[f].max(1.0);
+                self.field_tf[f] / (1.0 - b + b * self.field_len[f] / avg)
+            })
+            .sum()
+    }
+}
+
+pub trait Scorer: Send + Sync {
+    /// Score of a term in a document that contains it.
+    fn score(&self, term: &TermStats, doc: &DocTerm, collection: &CollectionStats) -> f64;
+
+    /// The factor a term's score is scaled by for its document frequency,
+    /// for models whose score has one; `None` for the others.
+    fn idf(&self, _doc_count: usize, _df: usize) -> Option<f64> {
+        None
+    }
+}
+
+/// IDF of BM25, which stays positive for terms in most documents.
+pub fn bm25_idf(doc_count: usize, df: usize) -> f64 {
+    let df = df as f64;
+    ((doc_count as f64 - df + 0.5) / (df + 0.5) + 1.0).ln()
+}
+
+pub struct Bm25 {
+    k1: f64,
+    b: f64,
+}
+
+impl Scorer for Bm25 {
+    fn score(&self, term: &TermStats, doc: &DocTerm, collection: &CollectionStats) -> f64 {
+        let tf = doc.bm25f_tf(collection, self.b);
+        bm25_idf(collection.doc_count, term.df) * tf * (self.k1 + 1.0) / (tf + self.k1)
+    }
+
+    fn idf(&self, doc_count: usize, df: usize) -> Option<f64> {
+        Some(bm25_idf(doc_count, df))
+    }
+}
+
+/// BM25 with a floor `delta` on a matching term's score, so long
+/// documents are not scored as if they barely contained the term
+/// (Lv & Zhai, 2011).
+pub struct Bm25Plus {
+    k1: f64,
+    b: f64,
+    delta: f64,
+}
+
+impl Scorer for Bm25Plus {
+    fn score(&self, term: &TermStats, doc: &DocTerm, collection: &CollectionStats) -> f64 {
+        let tf = doc.bm25f_tf(collection, self.b);
+        bm25_idf(collection.doc_count, term.df)
+            * (tf * (self.k1 + 1.0) / (tf + self.k1) + self.delta)
+    }
+
+    fn idf(&self, doc_count: usize, df: usize) -> Option<f64> {
+        Some(bm25_idf(doc_count, df))
+    }
+}
+
+/// Vector-space model: sqrt(tf) · idf² over the document vector's norm,
+/// approximated by the square root of the document's length, since the
+/// exact norm would need every term of the document. The query norm is the
+/// same for all documents and left out.
+pub struct TfIdf;
+
+impl Scorer for TfIdf {
+    fn score(&self, term: &TermStats, doc: &DocTerm, collection: &CollectionStats) -> f64 {
+        let idf = 1.0 + ((collection.doc_count as f64 + 1.0) / (term.df as f64 + 1.0)).ln();
+        doc.tf().sqrt() * idf * idf / doc.doc_len(collection).max(1.0).sqrt()
+    }
+}
+
+/// Query likelihood with Dirichlet smoothing. Only terms a document
+/// contains are scored, so (as in Lucene) a term's score is floored at 0
+/// instead of letting the length penalty make it negative.
+pub struct Dirichlet {
+    mu: f64,
+}
+
+impl Scorer for Dirichlet {
+    fn score(&self, term: &TermStats, doc: &DocTerm, collection: &CollectionStats) -> f64 {
+        let total_len = collection.avg_len() * collection.doc_count.max(1) as f64;
+        let p_collection = (term.cf / total_len.max(1.0)).max(f64::MIN_POSITIVE);
+        let len = doc.doc_len(collection);
+        let score =
+            (1.0 + doc.tf() / (self.mu * p_collection)).ln() + (self.mu / (len + self.mu)).ln();
+        score.max(0.0)
+    }
+}
+
+/// Divergence from randomness, PL2: Poisson model of how a term is spread
+/// over documents, Laplace after-effect and length normalization 2 with
+/// parameter `c`.
+pub struct Dfr {
+    c: f64,
+}
+
+impl Scorer for Dfr {
+    fn score(&self, term: &TermStats, doc: &DocTerm, collection: &CollectionStats) -> f64 {
+        let len = doc.doc_len(collection).max(1.0);
+        let tfn = doc.tf() * (1.0 + self.c * collection.avg_len() / len).log2();
+        let lambda = term.cf / collection.doc_count.max(1) as f64;
+        if tfn <= 0.0 || lambda <= 0.0 {
+            return 0.0;
+        }
+        let info = tfn * (tfn / lambda).log2()
+            + (lambda - tfn) * std::f64::consts::LOG2_E
+            + 0.5 * (2.0 * std::f64::consts::PI * tfn).log2();
+        (info / (tfn + 1.0)).max(0.0)
+    }
+}
+
+/// Free parameters of the ranking models, each used by the models named.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RankingParams {
+    /// BM25, BM25+: how quickly repeated occurrences stop adding to the
+    /// score.
+    pub k1: f64,
+    /// BM25, BM25+: how strongly scores are normalized by length, 0 to 1.
+    pub b: f64,
+    /// BM25+: floor on the score of a matching term.
+    pub delta: f64,
+    /// Dirichlet: smoothing, in tokens of the collection model.
+    pub mu: f64,
+    /// DFR: length normalization.
+    pub c: f64,
+}
+
+impl Default for RankingParams {
+    fn default() -> Self {
+        RankingParams {
+            k1: 1.2,
+            b: 0.75,
+            delta: 1.0,
+            mu: 2000.0,
+            c: 1.0,
+        }
+    }
+}
+
+impl RankingParams {
+    pub fn validate(&self) -> Result<(), String> {
+        let checks = [
+            ("k1", self.k1 >= 0.0),
+            ("b", (0.0..=1.0).contains(&self.b)),
+            ("delta", self.delta >= 0.0),
+            ("mu", self.mu > 0.0),
+            ("c", self.c > 0.0),
+        ];
+        match checks.iter().find(|(_, ok)| !ok) {
+            Some((name, _)) => Err(format!(
+                "`{}` is out of range (k1 and delta at least 0, b from 0 to 1, mu and c above 0)",
+                name
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// The parameters `ranker` uses, by name.
+    pub fn of(&self, ranker: Ranker) -> Vec<(&'static str, f64)> {
+        match ranker {
+            Ranker::Bm25 => vec![("k1", self.k1), ("b", self.b)],
+            Ranker::Bm25Plus => vec![("k1", self.k1), ("b", self.b), ("delta", self.delta)],
+            Ranker::TfIdf => vec![],
+            Ranker::Dirichlet => vec![("mu", self.mu)],
+            Ranker::Dfr => vec![("c", self.c)],
+        }
+    }
+}
+
+/// A ranking model, chosen by name on the command line or per request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ranker {
+    Bm25,
+    Bm25Plus,
+    TfIdf,
+    Dirichlet,
+    Dfr,
+}
+
+impl Ranker {
+    pub const ALL: [Ranker; 5] = [
+        Ranker::Bm25,
+        Ranker::Bm25Plus,
+        Ranker::TfIdf,
+        Ranker::Dirichlet,
+        Ranker::Dfr,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Ranker::Bm25 => "bm25",
+            Ranker::Bm25Plus => "bm25plus",
+            Ranker::TfIdf => "tfidf",
+            Ranker::Dirichlet => "dirichlet",
+            Ranker::Dfr => "dfr",
+        }
+    }
+
+    pub fn scorer(self, params: &RankingParams) -> Box<dyn Scorer> {
+        let &RankingParams {
+            k1,
+            b,
+            delta,
+            mu,
+            c,
+        } = params;
+        match self {
+            Ranker::Bm25 => Box::new(Bm25 { k1, b }),
+            Ranker::Bm25Plus => Box::new(Bm25Plus { k1, b, delta }),
+            Ranker::TfIdf => Box::new(TfIdf),
+            Ranker::Dirichlet => Box::new(Dirichlet { mu }),
+            Ranker::Dfr => Box::new(Dfr { c }),
+        }
+    }
+}
+
+impl FromStr for Ranker {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        Ranker::ALL
+            .into_iter()
+            .find(|r| r.name().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| {
+                let names: Vec<&str> = Ranker::ALL.iter().map(|r| r.name()).collect();
+                format!(
+                    "unknown ranker {:?} (expected one of: {})",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collection() -> CollectionStats {
+        CollectionStats {
+            doc_count: 100,
+            avg_field_len: [0.0, 0.0, 100.0, 0.0, 0.0],
+            weights: FieldWeights::default(),
+        }
+    }
+
+    fn body(tf: f64, len: f64) -> DocTerm {
+        let mut doc = DocTerm {
+            field_tf: [0.0; FIELD_COUNT],
+            field_len: [0.0; FIELD_COUNT],
+        };
+        doc.field_tf[Field::Body.index()] = tf;
+        doc.field_len[Field::Body.index()] = len;
+        doc
+    }
+
+    #[test]
+    fn parameters_reach_the_scorers() {
+        let term = TermStats { df: 10, cf: 20.0 };
+        let collection = collection();
+        let score = |ranker: Ranker, params: &RankingParams, doc: &DocTerm| {
+            ranker.scorer(params).score(&term, doc, &collection)
+        };
+
+        // without length normalization, length doesn't matter
+        let flat = RankingParams {
+            b: 0.0,
+            ..RankingParams::default()
+        };
+        assert_eq!(
+            score(Ranker::Bm25, &flat, &body(2.0, 50.0)),
+            score(Ranker::Bm25, &flat, &body(2.0, 500.0))
+        );
+        let defaults = RankingParams::default();
+        assert!(
+            score(Ranker::Bm25, &defaults, &body(2.0, 50.0))
+                > score(Ranker::Bm25, &defaults, &body(2.0, 500.0))
+        );
+
+        // BM25+ is BM25 plus delta · idf
+        let doc = body(3.0, 120.0);
+        let plus = score(Ranker::Bm25Plus, &defaults, &doc);
+        let plain = score(Ranker::Bm25, &defaults, &doc);
+        assert!((plus - plain - defaults.delta * bm25_idf(100, 10)).abs() < 1e-9);
+
+        let smoother = RankingParams {
+            mu: 20000.0,
+            ..defaults
+        };
+        assert_ne!(
+            score(Ranker::Dirichlet, &defaults, &doc),
+            score(Ranker::Dirichlet, &smoother, &doc)
+        );
+    }
+
+    #[test]
+    fn out_of_range_parameters_are_rejected() {
+        assert!(RankingParams::default().validate().is_ok());
+        for params in [
+            RankingParams {
+                b: 1.5,
+                ..RankingParams::default()
+            },
+            RankingParams {
+                k1: -1.0,
+                ..RankingParams::default()
+            },
+            RankingParams {
+                mu: 0.0,
+                ..RankingParams::default()
+            },
+        ] {
+            assert!(params.validate().is_err(), "{:?}", params);
+        }
+    }
+}
@@ -0,0 +1,538 @@
+//! Learning-to-rank: a second stage that reorders the best first-stage hits
+//! with a model over per-document features.
+//!
+//! Models are JSON files, either linear:
+//!
+//! ```text
+//! {"model": "linear", "bias": 0.0, "weights": {"bm25_title": 0.8, "proximity": 2.1}}
+//! ```
+//!
+//! or gradient-boosted trees, whose leaf values are summed (a feature value
+//! below `threshold` goes left):
+//!
+//! ```text
+//! {"model": "trees", "base": 0.0, "trees": [
+//!   {"feature": "bm25_title", "threshold": 1.5, "left": {"value": -0.2}, "right": {"value": 0.4}}
+//! ]}
+//! ```
+//!
+//! Features a linear model leaves out have weight 0.
+
+use crate::field::{Field, FieldWeights, FIELD_COUNT};
+use crate::indexer::IndexStore;
+use crate::query::Query;
+use crate::ranking::{Ranker, RankingParams, TermStats};
+use crate::search::{collection_stats, doc_term, find_posting, proximity_distance, PostingsMap};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+/// Feature names, in the order of a feature vector.
+pub const FEATURES: [&str; 11] = [
+    "score",
+    "bm25_title",
+    "bm25_headings",
+    "bm25_body",
+    "bm25_url",
+    "bm25_anchor",
+    "proximity",
+    "title_hits",
+    "doc_len",
+    "url_depth",
+    "pagerank",
+];
+
+/// First-stage score.
+const SCORE: usize = 0;
+/// BM25 of the query restricted to each field, in `Field::ALL` order.
+const BM25_FIELDS: usize = 1;
+/// 1 / (1 + distance) of the typed words, 0 if none occur together.
+const PROXIMITY: usize = BM25_FIELDS + FIELD_COUNT;
+/// Typed words occurring in the title.
+const TITLE_HITS: usize = PROXIMITY + 1;
+/// ln(1 + document length).
+const DOC_LEN: usize = TITLE_HITS + 1;
+/// Path segments in the URL.
+const URL_DEPTH: usize = DOC_LEN + 1;
+/// ln(1 + PageRank).
+const PAGERANK: usize = URL_DEPTH + 1;
+
+/// One query's data that features are computed from.
+pub struct FeatureContext<'a> {
+    pub index: &'a IndexStore,
+    pub query: &'a Query,
+    pub lists: &'a PostingsMap,
+    pub stats: &'a HashMap<String, TermStats>,
+    pub qterms: &'a [String],
+    pub weights: &'a FieldWeights,
+}
+
+/// Feature vectors of `hits`, in the same order.
+pub fn features(ctx: &FeatureContext, hits: &[(usize, f64)]) -> Vec<Vec<f64>> {
+    let mut out: Vec<Vec<f64>> = hits
+        .iter()
+        .map(|&(doc_id, score)| {
+            let mut f = vec![0.0; FEATURES.len()];
+            f[SCORE] = score;
+            f[PROXIMITY] = proximity_distance(ctx.lists, ctx.qterms, doc_id)
+                .map_or(0.0, |d| 1.0 / (1.0 + d as f64));
+            f[TITLE_HITS] = ctx
+                .qterms
+                .iter()
+                .filter(|t| {
+                    find_posting(ctx.lists, t, doc_id)
+                        .is_some_and(|p| p.field_tf[Field::Title.index()] > 0)
+                })
+                .count() as f64;
+            let len: usize = Field::ALL
+                .iter()
+                .map(|&field| ctx.index.field_len(doc_id, field))
+                .sum();
+            f[DOC_LEN] = (1.0 + len as f64).ln();
+            f[URL_DEPTH] = url_depth(&ctx.index.doc(doc_id).url) as f64;
+            f[PAGERANK] = ctx.index.pagerank(doc_id).ln_1p();
+            f
+        })
+        .collect();
+
+    // the features a model was trained on don't change with the tuning of
+    // the first stage
+    let bm25 = Ranker::Bm25.scorer(&RankingParams::default());
+    let collection = collection_stats(ctx.index, ctx.weights);
+    for (term, scope, boost) in ctx.query.scored_terms() {
+        if boost == 0.0 {
+            continue;
+        }
+        let Some(stats) = ctx.stats.get(&term) else {
+            continue;
+        };
+        for (f, &(doc_id, _)) in out.iter_mut().zip(hits) {
+            let Some(p) = find_posting(ctx.lists, &term, doc_id) else {
+                continue;
+            };
+            for field in Field::ALL {
+                if scope.is_some_and(|s| s != field) {
+                    continue;
+                }
+                let doc = doc_term(ctx.index, p, Some(field), ctx.weights);
+                if doc.tf() > 0.0 {
+                    f[BM25_FIELDS + field.index()] += boost * bm25.score(stats, &doc, &collection);
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Non-empty path segments of `url`, after the host if it has a scheme.
+fn url_depth(url: &str) -> usize {
+    let path = match url.split_once("://") {
+        Some((_, rest)) => rest.split_once('/').map_or("", |(_, path)| path),
+        None => url,
+    };
+    path.split(['/', '?', '#'])
+        .filter(|s| !s.is_empty())
+        .count()
+}
+
+/// A model as written in a model file.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "model", rename_all = "lowercase")]
+pub enum ModelFile {
+    Linear {
+        #[serde(default)]
+        bias: f64,
+        weights: BTreeMap<String, f64>,
+    },
+    Trees {
+        #[serde(default)]
+        base: f64,
+        trees: Vec<TreeNode>,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum TreeNode {
+    Leaf {
+        value: f64,
+    },
+    Split {
+        feature: String,
+        threshold: f64,
+        left: Box<TreeNode>,
+        right: Box<TreeNode>,
+    },
+}
+
+/// A model with its feature names resolved to vector positions.
+enum Model {
+    Linear { bias: f64, weights: Vec<f64> },
+    Trees { base: f64, trees: Vec<Node> },
+}
+
+enum Node {
+    Leaf(f64),
+    Split {
+        feature: usize,
+        threshold: f64,
+        left: Box<Node>,
+        right: Box<Node>,
+    },
+}
+
+fn feature_index(name: &str) -> anyhow::Result<usize> {
+    FEATURES.iter().position(|&f| f == name).with_context(|| {
+        format!(
+            "unknown feature {:?} (expected one of: {})",
+            name,
+            FEATURES.join(", ")
+        )
+    })
+}
+
+impl Model {
+    fn compile(file: ModelFile) -> anyhow::Result<Model> {
+        Ok(match file {
+            ModelFile::Linear { bias, weights } => {
+                let mut resolved = vec![0.0; FEATURES.len()];
+                for (name, weight) in weights {
+                    resolved[feature_index(&name)?] = weight;
+                }
+                Model::Linear {
+                    bias,
+                    weights: resolved,
+                }
+            }
+            ModelFile::Trees { base, trees } => Model::Trees {
+                base,
+                trees: trees
+                    .into_iter()
+                    .map(Node::compile)
+                    .collect::<anyhow::Result<_>>()?,
+            },
+        })
+    }
+
+    fn score(&self, features: &[f64]) -> f64 {
+        match self {
+            Model::Linear { bias, weights } => {
+                bias + weights
+                    .iter()
+                    .zip(features)
+                    .map(|(w, x)| w * x)
+                    .sum::<f64>()
+            }
+            Model::Trees { base, trees } => {
+                base + trees.iter().map(|t| t.eval(features)).sum::<f64>()
+            }
+        }
+    }
+}
+
+impl Node {
+    fn compile(node: TreeNode) -> anyhow::Result<Node> {
+        Ok(match node {
+            TreeNode::Leaf { value } => Node::Leaf(value),
+            TreeNode::Split {
+                feature,
+                threshold,
+                left,
+                right,
+            } => Node::Split {
+                feature: feature_index(&feature)?,
+                threshold,
+                left: Box::new(Node::compile(*left)?),
+                right: Box::new(Node::compile(*right)?),
+            },
+        })
+    }
+
+    fn eval(&self, features: &[f64]) -> f64 {
+        let mut node = self;
+        loop {
+            match node {
+                Node::Leaf(value) => return *value,
+                Node::Split {
+                    feature,
+                    threshold,
+                    left,
+                    right,
+                } => {
+                    node = if features[*feature] < *threshold {
+                        left
+                    } else {
+                        right
+                    };
+                }
+            }
+        }
+    }
+}
+
+pub struct Reranker {
+    model: Model,
+    /// First-stage hits reordered per query.
+    pub depth: usize,
+}
+
+impl Reranker {
+    pub fn load(path: &Path, depth: usize) -> anyhow::Result<Reranker> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("reading model file {:?}", path))?;
+        let file: ModelFile = serde_json::from_str(&text)
+            .with_context(|| format!("parsing model file {:?}", path))?;
+        Ok(Reranker {
+            model: Model::compile(file)?,
+            depth,
+        })
+    }
+
+    /// Rescores the best `depth` hits with the model and sorts them by the
+    /// new score. Hits below the cut keep their first-stage order after
+    /// them; their first-stage scores are shifted to start 1 below the
+    /// lowest model score, so the scores still sort like the hits.
+    pub fn rerank(&self, ctx: &FeatureContext, hits: &mut [(usize, f64)]) {
+        let depth = self.depth.min(hits.len());
+        let (top, tail) = hits.split_at_mut(depth);
+        let features = features(ctx, top);
+        for (hit, f) in top.iter_mut().zip(features) {
+            hit.1 = self.model.score(&f);
+        }
+        top.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+        if let (Some(&(_, lowest)), Some(&(_, first))) = (top.last(), tail.first()) {
+            let shift = lowest - 1.0 - first;
+            for hit in tail {
+                hit.1 += shift;
+            }
+        }
+    }
+}
+
+/// Training examples of one query: feature vectors and relevance grades.
+pub type QueryExamples = Vec<(Vec<f64>, f64)>;
+
+const TRAIN_EPOCHS: usize = 500;
+const LEARNING_RATE: f64 = 0.1;
+const L2: f64 = 1e-3;
+
+/// Fits a linear model by pairwise logistic regression (RankNet with a
+/// linear scorer): for every pair of documents of a query with different
+/// grades, the better one should score higher. Returns the model and the
+/// fraction of training pairs it orders correctly.
+pub fn train_linear(queries: &[QueryExamples]) -> anyhow::Result<(ModelFile, f64)> {
+    let n = FEATURES.len();
+    let all: Vec<&Vec<f64>> = queries.iter().flatten().map(|(f, _)| f).collect();
+    if all.is_empty() {
+        anyhow::bail!("no training examples");
+    }
+
+    // standardized features keep one learning rate sensible for all of them
+    let mut mean = vec![0.0; n];
+    let mut std = vec![0.0; n];
+    for f in &all {
+        for i in 0..n {
+            mean[i] += f[i] / all.len() as f64;
+        }
+    }
+    for f in &all {
+        for i in 0..n {
+            std[i] += (f[i] - mean[i]).powi(2) / all.len() as f64;
+        }
+    }
+    for s in &mut std {
+        *s = if *s > 0.0 { s.sqrt() } else { 1.0 };
+    }
+
+    let mut diffs: Vec<Vec<f64>> = Vec::new();
+    for examples in queries {
+        for (better, g1) in examples {
+            for (worse, g2) in examples {
+                if g1 > g2 {
+                    diffs.push((0..n).map(|i| (better[i] - worse[i]) / std[i]).collect());
+                }
+            }
+        }
+    }
+    if diffs.is_empty() {
+        anyhow::bail!("no query has judged documents of different grades among its hits");
+    }
+
+    let mut w = vec![0.0; n];
+    for _ in 0..TRAIN_EPOCHS {
+        let mut grad: Vec<f64> = w.iter().map(|wi| L2 * wi).collect();
+        for d in &diffs {
+            let margin: f64 = w.iter().zip(d).map(|(wi, di)| wi * di).sum();
+            // derivative of ln(1 + e^-margin)
+            let g = -1.0 / (1.0 + margin.exp());
+            for i in 0..n {
+                grad[i] += g * d[i] / diffs.len() as f64;
+            }
+        }
+        for i in 0..n {
+            w[i] -= LEARNING_RATE * grad[i];
+        }
+    }
+
+    let correct = diffs
+        .iter()
+        .filter(|d| w.iter().zip(d.iter()).map(|(wi, di)| wi * di).sum::<f64>() > 0.0)
+        .count();
+
+    // undo the standardization so the model applies to raw features
+    let mut weights = BTreeMap::new();
+    let mut bias = 0.0;
+    for i in 0..n {
+        weights.insert(FEATURES[i].to_string(), w[i] / std[i]);
+        bias -= w[i] * mean[i] / std[i];
+    }
+    Ok((
+        ModelFile::Linear { bias, weights },
+        correct as f64 / diffs.len() as f64,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::search;
+    use crate::search::tests::{index, options};
+
+    fn model(json: &str) -> anyhow::Result<Model> {
+        Model::compile(serde_json::from_str(json).unwrap())
+    }
+
+    fn vector(values: &[(&str, f64)]) -> Vec<f64> {
+        let mut f = vec![0.0; FEATURES.len()];
+        for &(name, value) in values {
+            f[feature_index(name).unwrap()] = value;
+        }
+        f
+    }
+
+    #[test]
+    fn models_score_feature_vectors() {
+        let f = vector(&[("url_depth", 2.0), ("pagerank", 0.5), ("score", 7.0)]);
+        let linear = model(
+            r#"{"model": "linear", "bias": 1.0, "weights": {"url_depth": 2.0, "pagerank": -1.0}}"#,
+        )
+        .unwrap();
+        assert_eq!(linear.score(&f), 4.5);
+
+        let trees = model(
+            r#"{"model": "trees", "base": 0.1, "trees": [
+                {"feature": "url_depth", "threshold": 1.5, "left": {"value": -0.2}, "right": {"value": 0.4}}
+            ]}"#,
+        )
+        .unwrap();
+        assert!((trees.score(&f) - 0.5).abs() < 1e-12);
+        assert!((trees.score(&vector(&[("url_depth", 1.0)])) + 0.1).abs() < 1e-12);
+    }
+
+    #[test]
+    fn unknown_features_are_rejected() {
+        let err = model(r#"{"model": "linear", "weights": {"clicks": 1.0}}"#)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("unknown feature \"clicks\""));
+        let err = model(
+            r#"{"model": "trees", "trees": [
+                {"feature": "clicks", "threshold": 1, "left": {"value": 0}, "right": {"value": 1}}
+            ]}"#,
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("unknown feature \"clicks\""));
+    }
+
+    #[test]
+    fn url_depth_counts_path_segments() {
+        assert_eq!(url_depth("https://h/a/b?x"), 3);
+        assert_eq!(url_depth("https://h"), 0);
+        assert_eq!(url_depth("https://h/"), 0);
+        assert_eq!(url_depth("/tmp/corpus/sub/c.html"), 4);
+        assert_eq!(url_depth("a.html"), 1);
+    }
+
+    #[test]
+    fn reranking_reorders_only_the_top_hits() {
+        let (dir, store) = index(
+            "rerank",
+            &[
+                ("a", &["rust", "rust", "rust"]),
+                ("x/y/b", &["rust", "rust", "go"]),
+                ("x/y/z/c", &["rust", "go", "go", "go"]),
+                ("d", &["rust", "go", "go", "go", "go", "go"]),
+            ],
+        );
+        let mut options = options();
+        let scorer = options.ranker.scorer(&options.params);
+        let urls = |hits: &[(usize, f64)]| -> Vec<String> {
+            hits.iter().map(|&(id, _)| store.doc(id).url).collect()
+        };
+        let first_stage = search(&store, "rust", 10, &options, scorer.as_ref())
+            .unwrap()
+            .hits;
+        assert_eq!(urls(&first_stage), ["a", "x/y/b", "x/y/z/c", "d"]);
+
+        // deeper URLs first, but only among the best two
+        options.reranker = Some(Reranker {
+            model: model(r#"{"model": "linear", "weights": {"url_depth": 1.0}}"#).unwrap(),
+            depth: 2,
+        });
+        let reranked = search(&store, "rust", 10, &options, scorer.as_ref())
+            .unwrap()
+            .hits;
+        assert_eq!(urls(&reranked), ["x/y/b", "a", "x/y/z/c", "d"]);
+        let scores: Vec<f64> = reranked.iter().map(|&(_, score)| score).collect();
+        assert_eq!(&scores[..2], [3.0, 1.0]);
+        assert!(scores.windows(2).all(|w| w[0] > w[1]));
+        // the tail keeps its first-stage gaps
+        let gap = first_stage[2].1 - first_stage[3].1;
+        assert!((scores[2] - scores[3] - gap).abs() < 1e-9);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn training_separates_by_the_informative_feature() {
+        // relevant documents have the query in the title; length is noise
+        let example = |title_hits: f64, doc_len: f64, grade: f64| {
+            (
+                vector(&[("title_hits", title_hits), ("doc_len", doc_len)]),
+                grade,
+            )
+        };
+        let queries = vec![
+            vec![
+                example(1.0, 3.0, 1.0),
+                example(0.0, 5.0, 0.0),
+                example(0.0, 2.0, 0.0),
+            ],
+            vec![
+                example(0.0, 4.0, 0.0),
+                example(1.0, 6.0, 2.0),
+                example(0.0, 1.0, 0.0),
+            ],
+        ];
+        let (file, accuracy) = train_linear(&queries).unwrap();
+        assert_eq!(accuracy, 1.0);
+        let ModelFile::Linear { weights, .. } = &file else {
+            panic!("expected a linear model");
+        };
+        assert!(weights["title_hits"] > 0.0);
+        assert!(weights["title_hits"] > weights["doc_len"].abs());
+
+        let trained = Model::compile(file).unwrap();
+        for examples in &queries {
+            let best = examples
+                .iter()
+                .max_by(|a, b| trained.score(&a.0).total_cmp(&trained.score(&b.0)))
+                .unwrap();
+            assert!(best.1 > 0.0);
+        }
+        assert!(train_linear(&[]).is_err());
+        assert!(train_linear(&[vec![example(1.0, 1.0, 1.0)]]).is_err());
+    }
+}
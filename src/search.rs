@@ -1,86 +1,594 @@
-use crate::indexer::IndexStore;
-use crate::normalize::tokenize;
+use crate::automaton::DamerauAutomaton;
+use crate::field::{Field, FieldWeights, FIELD_COUNT};
+use crate::indexer::{IndexStore, Posting};
+use crate::query::{self, Query};
+use crate::ranking::{CollectionStats, DocTerm, Ranker, RankingParams, Scorer, TermStats};
+use crate::rerank::{self, FeatureContext, Reranker};
+use crate::synonyms::{Expansion, Synonyms};
+use crate::translit::Transliterator;
+use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
 
-const K1: f64 = 1.2;
-const B: f64 = 0.75;
-const TITLE_WEIGHT: f64 = 5.0;
+/// How the terms a wildcard pattern expands to are scored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum WildcardScoring {
+    /// Scored by the ranker, but with BM25 and BM25+ every expanded term is
+    /// weighted by the IDF of the most frequent one, so a rare term picked
+    /// up by `*` can't outscore the rest of the query.
+    Blended,
+    /// Every match of the pattern gets the same score.
+    Constant,
+}
 
-pub fn search(index: &IndexStore, query: &str, top_k: usize) -> Vec<(usize, f64)> {
-    let qterms = tokenize(query);
-    if qterms.is_empty() {
-        return vec![];
-    }
+/// Score a `Constant` wildcard adds to each matching document.
+const CONSTANT_WILDCARD_SCORE: f64 = 1.0;
 
-    let mut sets: Vec<HashSet<usize>> = Vec::new();
-    for t in &qterms {
-        if let Some(postings) = index.dict.get(t) {
-            sets.push(postings.iter().map(|p| p.doc_id).collect());
-        } else {
-            return vec![];
+#[derive(Debug, Clone, Copy)]
+pub struct WildcardOptions {
+    /// Most terms a pattern expands to; the most frequent are kept.
+    pub max_terms: usize,
+    pub scoring: WildcardScoring,
+}
+
+impl Default for WildcardOptions {
+    fn default() -> Self {
+        WildcardOptions {
+            max_terms: 64,
+            scoring: WildcardScoring::Blended,
         }
     }
+}
+
+/// Query-time settings of a server, shared by every search.
+pub struct SearchOptions {
+    pub weights: FieldWeights,
+    pub synonyms: Synonyms,
+    /// Also look for rare Latin-script words in Persian script and vice
+    /// versa.
+    pub transliteration: Option<Transliterator>,
+    pub wildcards: WildcardOptions,
+    /// Ranking model of requests that don't pick one.
+    pub ranker: Ranker,
+    /// Parameters of the ranking models, unless a request sets its own.
+    pub params: RankingParams,
+    /// Weight of ln(1 + PageRank) added to every matching document's
+    /// score; 0 ranks by the text alone.
+    pub pagerank_weight: f64,
+    /// Second stage reordering the best first-stage hits.
+    pub reranker: Option<Reranker>,
+}
+
+pub struct SearchResults {
+    /// (doc id, score), best first.
+    pub hits: Vec<(usize, f64)>,
+    /// The query that was run, synonyms included. `None` if nothing in the
+    /// input was searchable.
+    pub query: Option<Query>,
+    /// Transliterations and synonyms added to the query.
+    pub expansions: Vec<Expansion>,
+    /// Nothing was searched because every word was a stopword.
+    pub only_stopwords: bool,
+}
+
+/// A query as it is run.
+struct Prepared {
+    /// The words the user typed, in order.
+    qterms: Vec<String>,
+    /// Parsed and expanded with transliterations, synonyms and the terms
+    /// wildcards match.
+    expanded: Query,
+    expansions: Vec<Expansion>,
+}
+
+fn prepare(
+    index: &IndexStore,
+    query: &str,
+    options: &SearchOptions,
+    scorer: &dyn Scorer,
+) -> anyhow::Result<Option<Prepared>> {
+    let Some(parsed) = query::parse(query, index.analyzer.as_ref()) else {
+        return Ok(None);
+    };
+    let (expanded, expansions) = expand(index, &parsed, options, scorer)?;
+    Ok(Some(Prepared {
+        qterms: parsed.positive_terms(),
+        expanded,
+        expansions,
+    }))
+}
+
+/// `parsed` with the transliterations, synonyms and wildcard terms
+/// `options` add, and the transliterations and synonyms that were added.
+/// Wildcard terms are weighted for `scorer`.
+fn expand(
+    index: &IndexStore,
+    parsed: &Query,
+    options: &SearchOptions,
+    scorer: &dyn Scorer,
+) -> anyhow::Result<(Query, Vec<Expansion>)> {
+    let (expanded, mut expansions) = match &options.transliteration {
+        Some(transliterator) => transliterator.expand(index, parsed),
+        None => (parsed.clone(), vec![]),
+    };
+    // synonyms of a transliteration are found too, at a lower weight still
+    let (expanded, synonyms) = options.synonyms.expand(&expanded);
+    expansions.extend(synonyms);
+    let expanded = expand_wildcards(index, &expanded, &options.wildcards, scorer)?;
+    Ok((expanded, expansions))
+}
+
+/// Parses `query`, expands it with transliterations and synonyms, ranks
+/// the matching documents with `scorer` and, if the options have a
+/// reranker, reorders the best of them. Fails if a wildcard pattern is
+/// too complex to expand.
+pub fn search(
+    index: &IndexStore,
+    query: &str,
+    top_k: usize,
+    options: &SearchOptions,
+    scorer: &dyn Scorer,
+) -> anyhow::Result<SearchResults> {
+    let Some(prepared) = prepare(index, query, options, scorer)? else {
+        return Ok(SearchResults {
+            hits: vec![],
+            query: None,
+            expansions: vec![],
+            only_stopwords: index.only_stopwords(query),
+        });
+    };
+    let (mut hits, matched) = rank(index, &prepared, top_k, options, scorer);
+    if let Some(reranker) = &options.reranker {
+        reranker.rerank(
+            &feature_context(index, &prepared, &matched, options),
+            &mut hits,
+        );
+    }
 
-    let candidates: HashSet<usize> = sets
+    Ok(SearchResults {
+        hits,
+        query: Some(prepared.expanded),
+        expansions: prepared.expansions,
+        only_stopwords: false,
+    })
+}
+
+/// The best `depth` first-stage hits of `query` with their reranking
+/// features, for training a reranker.
+pub fn feature_vectors(
+    index: &IndexStore,
+    query: &str,
+    depth: usize,
+    options: &SearchOptions,
+    scorer: &dyn Scorer,
+) -> anyhow::Result<Vec<(usize, Vec<f64>)>> {
+    let Some(prepared) = prepare(index, query, options, scorer)? else {
+        return Ok(vec![]);
+    };
+    let (hits, matched) = rank(index, &prepared, depth, options, scorer);
+    let features = rerank::features(&feature_context(index, &prepared, &matched, options), &hits);
+    Ok(hits
         .into_iter()
-        .reduce(|a, b| a.intersection(&b).cloned().collect())
-        .unwrap_or_default();
+        .map(|(doc_id, _)| doc_id)
+        .zip(features)
+        .collect())
+}
 
-    if candidates.is_empty() {
-        return vec![];
+fn feature_context<'a>(
+    index: &'a IndexStore,
+    prepared: &'a Prepared,
+    matched: &'a MatchedPostings,
+    options: &'a SearchOptions,
+) -> FeatureContext<'a> {
+    FeatureContext {
+        index,
+        query: &prepared.expanded,
+        lists: &matched.lists,
+        stats: &matched.stats,
+        qterms: &prepared.qterms,
+        weights: &options.weights,
     }
+}
 
-    let avg_len =
-        index.docs.iter().map(|d| d.length).sum::<usize>() as f64 / index.doc_count.max(1) as f64;
+/// Ranks the documents matching the query, summing `scorer`'s score of
+/// every query term a document contains, plus a boost for the typed words
+/// appearing close together and the document's weighted PageRank. Also
+/// returns the postings the ranking looked at, for reranking.
+fn rank(
+    index: &IndexStore,
+    prepared: &Prepared,
+    top_k: usize,
+    options: &SearchOptions,
+    scorer: &dyn Scorer,
+) -> (Vec<(usize, f64)>, MatchedPostings) {
+    let weights = &options.weights;
+    let Prepared {
+        qterms,
+        expanded: query,
+        ..
+    } = prepared;
 
-    let mut scores: HashMap<usize, f64> = HashMap::new();
+    let candidates: HashSet<usize> = match evaluate(index, query, None) {
+        DocSet::Include(docs) => docs.into_iter().collect(),
+        // a purely negative query ("-foo") has nothing to rank
+        DocSet::Exclude(_) => return Default::default(),
+    };
+
+    if candidates.is_empty() {
+        return Default::default();
+    }
 
-    for term in &qterms {
-        if let Some(postings) = index.dict.get(term) {
-            let df = postings.len() as f64;
-            let idf = ((index.doc_count as f64 - df + 0.5) / (df + 0.5) + 1.0).ln();
+    let matched = MatchedPostings::collect(index, query, qterms, &candidates, weights);
+    let mut scores: HashMap<usize, f64> = HashMap::new();
+    let collection = collection_stats(index, weights);
 
+    for (term, field, boost) in query.scored_terms() {
+        if boost == 0.0 {
+            continue;
+        }
+        if let (Some(postings), Some(stats)) = (matched.lists.get(&term), matched.stats.get(&term))
+        {
             for p in postings {
-                if !candidates.contains(&p.doc_id) {
+                let doc = doc_term(index, p, field, weights);
+                if doc.tf() == 0.0 {
                     continue;
                 }
-
-                let doc_len = index.docs[p.doc_id].length as f64;
-                let tf = p.tf as f64;
-
-                let denom = tf + K1 * (1.0 - B + B * doc_len / avg_len.max(1.0));
-                let score = idf * ((tf * (K1 + 1.0)) / denom);
+                let score = boost * scorer.score(stats, &doc, &collection);
 
                 *scores.entry(p.doc_id).or_insert(0.0) += score;
             }
         }
     }
 
+    for (clause, field, score) in query.constant_clauses() {
+        if let DocSet::Include(docs) = evaluate(index, clause, field) {
+            for doc_id in docs.into_iter().filter(|d| candidates.contains(d)) {
+                *scores.entry(doc_id).or_insert(0.0) += score;
+            }
+        }
+    }
+
+    // candidates matched only through OR branches may not have scored yet
     for &doc_id in &candidates {
-        let title_tokens = tokenize(&index.docs[doc_id].title);
-        let mut hits = 0;
+        scores.entry(doc_id).or_insert(0.0);
+    }
+
+    apply_proximity_boost(&matched.lists, qterms, &candidates, &mut scores);
+
+    if options.pagerank_weight != 0.0 {
+        for (&doc_id, score) in scores.iter_mut() {
+            *score += options.pagerank_weight * index.pagerank(doc_id).ln_1p();
+        }
+    }
+
+    let mut results: Vec<_> = scores.into_iter().collect();
+    results.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+    results.truncate(top_k);
+    (results, matched)
+}
+
+pub fn collection_stats(index: &IndexStore, weights: &FieldWeights) -> CollectionStats {
+    CollectionStats {
+        doc_count: index.doc_count,
+        avg_field_len: Field::ALL.map(|f| index.avg_field_len(f)),
+        weights: *weights,
+    }
+}
+
+/// Statistics of a term from one pass over its postings.
+pub fn term_stats(postings: impl Iterator<Item = Posting>, weights: &FieldWeights) -> TermStats {
+    let mut stats = TermStats { df: 0, cf: 0.0 };
+    for p in postings {
+        stats.df += 1;
+        stats.cf += Field::ALL
+            .iter()
+            .map(|&f| weights.get(f) * p.field_tf[f.index()] as f64)
+            .sum::<f64>();
+    }
+    stats
+}
+
+/// Weighted term frequencies of the fields in scope and the field lengths
+/// of the posting's document.
+pub fn doc_term(
+    index: &IndexStore,
+    p: &Posting,
+    scope: Option<Field>,
+    weights: &FieldWeights,
+) -> DocTerm {
+    let mut doc = DocTerm {
+        field_tf: [0.0; FIELD_COUNT],
+        field_len: [0.0; FIELD_COUNT],
+    };
+    for field in Field::ALL {
+        let i = field.index();
+        doc.field_len[i] = index.field_len(p.doc_id, field) as f64;
+        if scope.is_none_or(|f| f == field) {
+            doc.field_tf[i] = weights.get(field) * p.field_tf[i] as f64;
+        }
+    }
+    doc
+}
+
+/// Replaces wildcard patterns with the terms they match, scored as
+/// `options` says and weighted for `scorer`.
+fn expand_wildcards(
+    index: &IndexStore,
+    q: &Query,
+    options: &WildcardOptions,
+    scorer: &dyn Scorer,
+) -> anyhow::Result<Query> {
+    let expand = |inner: &Query| expand_wildcards(index, inner, options, scorer).map(Box::new);
+    let expand_all = |children: &[Query]| {
+        children
+            .iter()
+            .map(|c| expand_wildcards(index, c, options, scorer))
+            .collect::<anyhow::Result<Vec<_>>>()
+    };
+    Ok(match q {
+        Query::Wildcard(pattern) => {
+            let mut terms: Vec<(String, usize)> = Vec::new();
+            index.terms_matching_wildcard(pattern, |t, df| {
+                terms.push((t.to_string(), df));
+            })?;
+            terms.sort_by_key(|&(_, df)| std::cmp::Reverse(df));
+            terms.truncate(options.max_terms);
+
+            match options.scoring {
+                WildcardScoring::Blended => {
+                    let idf = |df| scorer.idf(index.doc_count, df);
+                    let shared_idf = idf(terms.first().map_or(0, |t| t.1));
+                    Query::Or(
+                        terms
+                            .into_iter()
+                            .map(|(t, df)| {
+                                let boost = match (shared_idf, idf(df)) {
+                                    (Some(shared), Some(own)) => shared / own.max(f64::EPSILON),
+                                    // the model has no IDF factor to even out
+                                    _ => 1.0,
+                                };
+                                Query::Boost(boost, Box::new(Query::Term(t)))
+                            })
+                            .collect(),
+                    )
+                }
+                WildcardScoring::Constant => Query::ConstantScore(
+                    CONSTANT_WILDCARD_SCORE,
+                    Box::new(Query::Or(
+                        terms.into_iter().map(|(t, _)| Query::Term(t)).collect(),
+                    )),
+                ),
+            }
+        }
+        Query::Term(_) | Query::Phrase(_) => q.clone(),
+        Query::And(children) => Query::And(expand_all(children)?),
+        Query::Or(children) => Query::Or(expand_all(children)?),
+        Query::Not(inner) => Query::Not(expand(inner)?),
+        Query::Field(field, inner) => Query::Field(*field, expand(inner)?),
+        Query::Boost(weight, inner) => Query::Boost(*weight, expand(inner)?),
+        Query::ConstantScore(score, inner) => Query::ConstantScore(*score, expand(inner)?),
+    })
+}
+
+/// Number of live documents matching `query`, expanded like `search`
+/// would, without scoring them.
+pub fn count_matches(
+    index: &IndexStore,
+    query: &Query,
+    options: &SearchOptions,
+) -> anyhow::Result<usize> {
+    // the scorer only weights wildcard terms, which doesn't change matches
+    let scorer = options.ranker.scorer(&options.params);
+    let (query, _) = &expand(index, query, options, scorer.as_ref())?;
+    Ok(match evaluate(index, query, None) {
+        DocSet::Include(docs) => docs.len(),
+        DocSet::Exclude(_) => 0,
+    })
+}
+
+/// Result of evaluating a query node: either the sorted doc ids that match,
+/// or (for a bare NOT) the sorted doc ids that must be excluded.
+enum DocSet {
+    Include(Vec<usize>),
+    Exclude(Vec<usize>),
+}
+
+/// Postings of terms, keyed by term.
+pub type PostingsMap = HashMap<String, Vec<Posting>>;
+
+/// The postings of the query's terms in the documents that matched it, and
+/// the terms' statistics over the whole index. Postings lists are streamed
+/// from the segments, so only the matching documents' postings are kept.
+#[derive(Default)]
+pub struct MatchedPostings {
+    pub lists: PostingsMap,
+    pub stats: HashMap<String, TermStats>,
+}
+
+impl MatchedPostings {
+    fn collect(
+        index: &IndexStore,
+        query: &Query,
+        qterms: &[String],
+        candidates: &HashSet<usize>,
+        weights: &FieldWeights,
+    ) -> Self {
+        let mut matched = MatchedPostings::default();
+        let terms = query.scored_terms().into_iter().map(|(t, _, _)| t);
+        for term in terms.chain(qterms.iter().cloned()) {
+            if matched.lists.contains_key(&term) {
+                continue;
+            }
+            let stats = term_stats(index.postings(&term), weights);
+            let postings = index
+                .postings(&term)
+                .filter(|p| candidates.contains(&p.doc_id))
+                .collect();
+            matched.stats.insert(term.clone(), stats);
+            matched.lists.insert(term, postings);
+        }
+        matched
+    }
+}
 
-        for t in &qterms {
-            if title_tokens.contains(t) {
-                hits += 1;
+/// Matches `q` against the postings. With `field` set, terms and phrases
+/// only match inside that field.
+fn evaluate(index: &IndexStore, q: &Query, field: Option<Field>) -> DocSet {
+    match q {
+        Query::Term(t) => DocSet::Include(
+            index
+                .postings(t)
+                .filter(|p| field.is_none_or(|f| p.field_tf[f.index()] > 0))
+                .map(|p| p.doc_id)
+                .filter(|&id| index.is_live(id))
+                .collect(),
+        ),
+        Query::Phrase(terms) => DocSet::Include(phrase_docs(index, terms, field)),
+        Query::Field(f, inner) => evaluate(index, inner, Some(*f)),
+        Query::Boost(_, inner) | Query::ConstantScore(_, inner) => evaluate(index, inner, field),
+        Query::Wildcard(_) => DocSet::Include(vec![]),
+        Query::Not(inner) => match evaluate(index, inner, field) {
+            DocSet::Include(docs) => DocSet::Exclude(docs),
+            DocSet::Exclude(docs) => DocSet::Include(docs),
+        },
+        Query::And(children) => {
+            let mut include: Option<Vec<usize>> = None;
+            let mut exclude: Vec<usize> = Vec::new();
+            for c in children {
+                match evaluate(index, c, field) {
+                    DocSet::Include(docs) => {
+                        include = Some(match include {
+                            Some(acc) => intersect(&acc, &docs),
+                            None => docs,
+                        });
+                    }
+                    DocSet::Exclude(docs) => exclude = union(&exclude, &docs),
+                }
+            }
+            match include {
+                Some(acc) => DocSet::Include(difference(&acc, &exclude)),
+                None => DocSet::Exclude(exclude),
+            }
+        }
+        Query::Or(children) => {
+            let mut include: Vec<usize> = Vec::new();
+            for c in children {
+                // "a OR -b" would mean "everything but b"; treat the negative
+                // branch as matching nothing instead of the whole collection
+                if let DocSet::Include(docs) = evaluate(index, c, field) {
+                    include = union(&include, &docs);
+                }
             }
+            DocSet::Include(include)
         }
+    }
+}
+
+/// Positional intersection: docs where `terms` occur at their offsets from
+/// the first term (starting inside `field`, if given; the position gap
+/// between fields keeps a phrase from spanning two of them).
+fn phrase_docs(index: &IndexStore, terms: &[(String, usize)], field: Option<Field>) -> Vec<usize> {
+    let Some(((first_term, _), rest)) = terms.split_first() else {
+        return vec![];
+    };
+    let mut others: Vec<_> = rest
+        .iter()
+        .map(|(t, _)| index.postings(t).peekable())
+        .collect();
+    let mut out = Vec::new();
+
+    // postings are sorted by doc_id, so walk all lists in lockstep
+    'docs: for first in index.postings(first_term) {
+        let doc_id = first.doc_id;
+        let mut aligned: Vec<Posting> = Vec::with_capacity(others.len());
 
-        if hits > 0 {
-            *scores.entry(doc_id).or_insert(0.0) += hits as f64 * TITLE_WEIGHT;
+        for list in others.iter_mut() {
+            while list.next_if(|p| p.doc_id < doc_id).is_some() {}
+            match list.next_if(|p| p.doc_id == doc_id) {
+                Some(p) => aligned.push(p),
+                None if list.peek().is_some() => continue 'docs,
+                None => break 'docs,
+            }
+        }
+
+        if !index.is_live(doc_id) {
+            continue;
+        }
+
+        let starts = match field {
+            Some(f) => first.field_positions(f),
+            None => &first.positions,
+        };
+        let matched = starts.iter().any(|&start| {
+            aligned
+                .iter()
+                .zip(rest)
+                .all(|(p, (_, offset))| p.positions.binary_search(&(start + offset)).is_ok())
+        });
+        if matched {
+            out.push(doc_id);
         }
     }
+    out
+}
 
-    apply_proximity_boost(index, &qterms, &candidates, &mut scores);
+fn intersect(a: &[usize], b: &[usize]) -> Vec<usize> {
+    let (mut i, mut j) = (0, 0);
+    let mut out = Vec::with_capacity(a.len().min(b.len()));
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            Ordering::Less => i += 1,
+            Ordering::Greater => j += 1,
+            Ordering::Equal => {
+                out.push(a[i]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    out
+}
 
-    let mut results: Vec<_> = scores.into_iter().collect();
-    results.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
-    results.truncate(top_k);
-    results
+fn union(a: &[usize], b: &[usize]) -> Vec<usize> {
+    let (mut i, mut j) = (0, 0);
+    let mut out = Vec::with_capacity(a.len() + b.len());
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            Ordering::Less => {
+                out.push(a[i]);
+                i += 1;
+            }
+            Ordering::Greater => {
+                out.push(b[j]);
+                j += 1;
+            }
+            Ordering::Equal => {
+                out.push(a[i]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    out.extend_from_slice(&a[i..]);
+    out.extend_from_slice(&b[j..]);
+    out
+}
+
+fn difference(a: &[usize], b: &[usize]) -> Vec<usize> {
+    let mut j = 0;
+    let mut out = Vec::with_capacity(a.len());
+    for &x in a {
+        while j < b.len() && b[j] < x {
+            j += 1;
+        }
+        if j >= b.len() || b[j] != x {
+            out.push(x);
+        }
+    }
+    out
 }
 
 fn apply_proximity_boost(
-    index: &IndexStore,
+    lists: &PostingsMap,
     qterms: &[String],
     candidates: &HashSet<usize>,
     scores: &mut HashMap<usize, f64>,
@@ -90,37 +598,9 @@ fn apply_proximity_boost(
     }
 
     for &doc_id in candidates {
-        let mut min_total_dist = 0;
-        let mut pair_found = false;
-
-        for pair in qterms.windows(2) {
-            let p1 = index
-                .dict
-                .get(&pair[0])
-                .and_then(|v| v.iter().find(|p| p.doc_id == doc_id));
-            let p2 = index
-                .dict
-                .get(&pair[1])
-                .and_then(|v| v.iter().find(|p| p.doc_id == doc_id));
-
-            if let (Some(pos1), Some(pos2)) = (p1, p2) {
-                let mut best_pair_dist = usize::MAX;
-                for &a in &pos1.positions {
-                    for &b in &pos2.positions {
-                        let d = a.abs_diff(b);
-                        if d < best_pair_dist {
-                            best_pair_dist = d;
-                        }
-                    }
-                }
-                min_total_dist += best_pair_dist;
-                pair_found = true;
-            }
-        }
-
-        if pair_found {
+        if let Some(min_total_dist) = proximity_distance(lists, qterms, doc_id) {
             let boost = match min_total_dist {
-                d if d <= qterms.len() - 1 => 5.0,
+                d if d < qterms.len() => 5.0,
                 d if d <= (qterms.len() - 1) * 2 => 2.5,
                 d if d <= (qterms.len() - 1) * 5 => 1.0,
                 _ => 0.0,
@@ -130,6 +610,38 @@ fn apply_proximity_boost(
     }
 }
 
+/// Sum over adjacent pairs of typed words of their closest distance in the
+/// document; `None` if no pair occurs in it together.
+pub fn proximity_distance(lists: &PostingsMap, qterms: &[String], doc_id: usize) -> Option<usize> {
+    let mut total = None;
+    for pair in qterms.windows(2) {
+        let p1 = find_posting(lists, &pair[0], doc_id);
+        let p2 = find_posting(lists, &pair[1], doc_id);
+
+        if let (Some(pos1), Some(pos2)) = (p1, p2) {
+            let mut best_pair_dist = usize::MAX;
+            for &a in &pos1.positions {
+                for &b in &pos2.positions {
+                    let d = a.abs_diff(b);
+                    if d < best_pair_dist {
+                        best_pair_dist = d;
+                    }
+                }
+            }
+            total = Some(total.unwrap_or(0) + best_pair_dist);
+        }
+    }
+    total
+}
+
+pub fn find_posting<'a>(lists: &'a PostingsMap, term: &str, doc_id: usize) -> Option<&'a Posting> {
+    let postings = lists.get(term)?;
+    postings
+        .binary_search_by_key(&doc_id, |p| p.doc_id)
+        .ok()
+        .map(|i| &postings[i])
+}
+
 pub fn damerau_levenshtein(a: &str, b: &str) -> usize {
     let a_chars: Vec<char> = a.chars().collect();
     let b_chars: Vec<char> = b.chars().collect();
@@ -137,11 +649,11 @@ pub fn damerau_levenshtein(a: &str, b: &str) -> usize {
     let m = b_chars.len();
     let mut dp = vec![vec![0; m + 1]; n + 1];
 
-    for i in 0..=n {
-        dp[i][0] = i;
+    for (i, row) in dp.iter_mut().enumerate() {
+        row[0] = i;
     }
-    for j in 0..=m {
-        dp[0][j] = j;
+    for (j, cell) in dp[0].iter_mut().enumerate() {
+        *cell = j;
     }
 
     for i in 1..=n {
@@ -175,21 +687,251 @@ pub fn suggest_terms(
 ) -> Vec<String> {
     let mut cands: Vec<(String, f64)> = Vec::new();
 
-    for (term, postings) in index.dict.iter() {
+    // the automaton only walks dictionary branches that can still end
+    // within `max_dist`, so just the (few) accepted terms get an exact
+    // distance computed for ranking
+    index.terms_matching(DamerauAutomaton::new(token, max_dist), |term, df| {
         let dist = damerau_levenshtein(term, token);
-        if dist > max_dist {
-            continue;
-        }
+        let score = -(dist as f64) * 3.0 + (df as f64 + 1.0).ln();
+        cands.push((term.to_string(), score));
+    });
 
-        let df = postings.len() as f64;
-        let score = -(dist as f64) * 3.0 + (df + 1.0).ln();
-        cands.push((term.clone(), score));
-    }
-
-    cands.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    cands.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
     cands
         .into_iter()
         .take(max_suggestions)
         .map(|(t, _)| t)
         .collect()
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::analysis::AnalysisConfig;
+    use crate::automaton::PatternTooComplex;
+    use crate::indexer::{DocMeta, DocTerms, Surfaces};
+    use crate::segment::tests::{build, doc, fielded_doc, temp_dir};
+    use crate::segment::IndexWriter;
+    use std::path::PathBuf;
+
+    /// A one-segment index of body-only documents, see `segment::tests::doc`.
+    pub(crate) fn index(name: &str, docs: &[(&str, &[&str])]) -> (PathBuf, IndexStore) {
+        index_of(
+            name,
+            docs.iter().map(|(url, words)| doc(url, words)).collect(),
+        )
+    }
+
+    fn index_of(name: &str, docs: Vec<(DocMeta, DocTerms, Surfaces)>) -> (PathBuf, IndexStore) {
+        let dir = temp_dir(name);
+        let mut writer = IndexWriter::create(&dir, AnalysisConfig::default(), false).unwrap();
+        writer.add_segment(build(docs)).unwrap();
+        writer.commit().unwrap();
+        let store = IndexStore::open(&dir).unwrap();
+        (dir, store)
+    }
+
+    /// BM25 with default weights and nothing added to the query.
+    pub(crate) fn options() -> SearchOptions {
+        SearchOptions {
+            weights: FieldWeights::default(),
+            synonyms: Synonyms::default(),
+            transliteration: None,
+            wildcards: WildcardOptions::default(),
+            ranker: Ranker::Bm25,
+            params: RankingParams::default(),
+            pagerank_weight: 0.0,
+            reranker: None,
+        }
+    }
+
+    /// URLs of the documents matching `query`, sorted.
+    fn matching(index: &IndexStore, query: &str) -> Vec<String> {
+        let options = options();
+        let scorer = options.ranker.scorer(&options.params);
+        let mut urls: Vec<String> = search(index, query, 100, &options, scorer.as_ref())
+            .unwrap()
+            .hits
+            .into_iter()
+            .map(|(doc_id, _)| index.doc(doc_id).url)
+            .collect();
+        urls.sort();
+        urls
+    }
+
+    #[test]
+    fn field_queries_only_match_their_field() {
+        let (dir, store) = index_of(
+            "fields",
+            vec![
+                fielded_doc(
+                    "a",
+                    &[(Field::Title, &["rust", "book"]), (Field::Body, &["intro"])],
+                ),
+                fielded_doc(
+                    "b",
+                    &[(Field::Title, &["intro"]), (Field::Body, &["rust", "book"])],
+                ),
+                fielded_doc("c", &[(Field::Url, &["rust"])]),
+            ],
+        );
+        assert_eq!(matching(&store, "rust"), ["a", "b", "c"]);
+        assert_eq!(matching(&store, "title:rust"), ["a"]);
+        assert_eq!(matching(&store, "body:rust"), ["b"]);
+        assert_eq!(matching(&store, "url:rust"), ["c"]);
+        assert_eq!(matching(&store, "title:book intro"), ["a"]);
+        assert!(matching(&store, "title:book body:book").is_empty());
+        // the field applies to the term it prefixes only
+        assert_eq!(matching(&store, "title:intro book"), ["b"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn title_hits_outrank_body_hits() {
+        let (dir, store) = index_of(
+            "field-weights",
+            vec![
+                fielded_doc(
+                    "a",
+                    &[(Field::Title, &["rust"]), (Field::Body, &["x", "y"])],
+                ),
+                fielded_doc(
+                    "b",
+                    &[(Field::Title, &["x"]), (Field::Body, &["rust", "y"])],
+                ),
+            ],
+        );
+        let ranked = |weights: FieldWeights| {
+            let options = SearchOptions {
+                weights,
+                ..options()
+            };
+            let scorer = options.ranker.scorer(&options.params);
+            search(&store, "rust", 10, &options, scorer.as_ref())
+                .unwrap()
+                .hits
+                .into_iter()
+                .map(|(doc_id, _)| store.doc(doc_id).url)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ranked(FieldWeights::default()), ["a", "b"]);
+        assert_eq!(ranked("title=1,body=5".parse().unwrap()), ["b", "a"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn phrases_match_terms_at_their_offsets() {
+        let (dir, store) = index(
+            "phrases",
+            &[
+                ("a", &["new", "york", "times"]),
+                ("b", &["york", "new", "times"]),
+                ("c", &["new", "jersey", "york"]),
+                ("d", &["the", "new", "new", "york"]),
+            ],
+        );
+        assert_eq!(matching(&store, "\"new york\""), ["a", "d"]);
+        assert_eq!(matching(&store, "\"york new\""), ["b"]);
+        assert_eq!(matching(&store, "\"new york times\""), ["a"]);
+        assert_eq!(matching(&store, "new york"), ["a", "b", "c", "d"]);
+        assert_eq!(matching(&store, "york -\"new york\""), ["b", "c"]);
+        assert!(matching(&store, "\"times new\"").is_empty());
+        assert!(matching(&store, "\"new boston\"").is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn wildcards_keep_the_most_frequent_terms() {
+        let (dir, store) = index(
+            "wildcard-cap",
+            &[
+                ("a", &["book"]),
+                ("b", &["book"]),
+                ("c", &["books"]),
+                ("d", &["bookshop"]),
+                ("e", &["books"]),
+            ],
+        );
+        let wildcards = WildcardOptions {
+            max_terms: 2,
+            scoring: WildcardScoring::Blended,
+        };
+        let pattern = Query::Wildcard("book*".to_string());
+        let scorer = Ranker::Bm25.scorer(&RankingParams::default());
+        let expanded = expand_wildcards(&store, &pattern, &wildcards, scorer.as_ref()).unwrap();
+        let mut kept = expanded.positive_terms();
+        kept.sort();
+        assert_eq!(kept, ["book", "books"]);
+        let options = SearchOptions {
+            wildcards,
+            ..options()
+        };
+        assert_eq!(count_matches(&store, &pattern, &options).unwrap(), 4);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn too_complex_wildcards_fail_the_search() {
+        let (dir, store) = index("wildcard-complex", &[("a", &["book"])]);
+        let options = options();
+        let scorer = options.ranker.scorer(&options.params);
+        let query = format!("*a{}", "?".repeat(16));
+        let err = search(&store, &query, 10, &options, scorer.as_ref())
+            .err()
+            .unwrap();
+        assert!(err.is::<PatternTooComplex>());
+        assert_eq!(matching(&store, "b??k*"), ["a"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn wildcard_scoring_does_not_favour_rare_expansions() {
+        let (dir, store) = index(
+            "wildcard-scoring",
+            &[("a", &["book"]), ("b", &["book"]), ("c", &["books"])],
+        );
+        let scores = |query: &str, scoring: WildcardScoring, ranker: Ranker| {
+            let options = SearchOptions {
+                wildcards: WildcardOptions {
+                    scoring,
+                    ..WildcardOptions::default()
+                },
+                ..options()
+            };
+            let scorer = ranker.scorer(&options.params);
+            search(&store, query, 10, &options, scorer.as_ref())
+                .unwrap()
+                .hits
+                .into_iter()
+                .map(|(_, score)| score)
+                .collect::<Vec<f64>>()
+        };
+        // plain BM25 would rank the rarer "books" first
+        for ranker in [Ranker::Bm25, Ranker::Bm25Plus] {
+            let blended = scores("book*", WildcardScoring::Blended, ranker);
+            assert_eq!(blended.len(), 3);
+            assert!(blended.iter().all(|s| (s - blended[0]).abs() < 1e-9));
+        }
+        // other models get the terms' own scores
+        assert_eq!(
+            scores("book*", WildcardScoring::Blended, Ranker::TfIdf),
+            scores("book OR books", WildcardScoring::Blended, Ranker::TfIdf)
+        );
+        assert_eq!(
+            scores("book*", WildcardScoring::Constant, Ranker::Bm25),
+            [CONSTANT_WILDCARD_SCORE; 3]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn phrase_offsets_leave_room_for_skipped_words() {
+        let (dir, store) = index(
+            "gaps",
+            &[("a", &["new", "jersey", "york"]), ("b", &["new", "york"])],
+        );
+        let query = Query::Phrase(vec![("new".to_string(), 0), ("york".to_string(), 2)]);
+        assert_eq!(count_matches(&store, &query, &options()).unwrap(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
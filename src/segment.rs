@@ -0,0 +1,1098 @@
+use crate::analysis::AnalysisConfig;
+use crate::codec::{check_magic, get_u32, get_u64, get_varint, put_u32, put_u64, put_varint};
+use crate::field::{Field, FIELD_COUNT};
+use crate::indexer::{most_common_form, DocMeta, DocTerms, Posting, Surfaces};
+use crate::ngram;
+use anyhow::Context;
+use fst::{Map, MapBuilder, Streamer};
+use memmap2::Mmap;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+const LOCK_FILE: &str = "write.lock";
+const SEGMENT_PREFIX: &str = "seg_";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SegmentInfo {
+    pub name: String,
+    pub doc_count: usize,
+    pub del_count: usize,
+    /// Generation of the current `.del` file; 0 means no deletions yet.
+    pub del_gen: u64,
+    /// Sum of the lengths of all docs in the segment, deleted ones included
+    /// (like Lucene, collection statistics only drop deleted docs on merge).
+    pub total_len: usize,
+    /// Per-field share of `total_len`, in `Field::ALL` order.
+    pub field_len: [usize; FIELD_COUNT],
+    /// Size of the encoded postings file.
+    pub postings_bytes: u64,
+    /// What the same postings would take as raw `usize` fields, for
+    /// reporting the compression ratio.
+    pub raw_postings_bytes: u64,
+    /// Whether the segment has an n-gram index of its terms.
+    pub has_grams: bool,
+}
+
+impl SegmentInfo {
+    pub fn live_docs(&self) -> usize {
+        self.doc_count - self.del_count
+    }
+
+    fn terms_file(&self) -> String {
+        format!("{}.terms", self.name)
+    }
+
+    fn postings_file(&self) -> String {
+        format!("{}.post", self.name)
+    }
+
+    fn docs_file(&self) -> String {
+        format!("{}.docs", self.name)
+    }
+
+    fn grams_file(&self) -> String {
+        format!("{}.grams", self.name)
+    }
+
+    fn gram_lists_file(&self) -> String {
+        format!("{}.glist", self.name)
+    }
+
+    fn del_file(&self) -> Option<String> {
+        (self.del_gen > 0).then(|| format!("{}_{}.del", self.name, self.del_gen))
+    }
+}
+
+/// The list of segments that make up the index at a given generation.
+/// Segments are immutable once written; only their delete files change.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Manifest {
+    pub generation: u64,
+    pub next_segment_id: u64,
+    pub segments: Vec<SegmentInfo>,
+    /// How documents were analyzed; queries are analyzed the same way.
+    #[serde(flatten)]
+    pub analysis: AnalysisConfig,
+    /// Whether new segments get an n-gram index for wildcard queries.
+    pub ngrams: bool,
+}
+
+impl Manifest {
+    pub fn load(dir: &Path) -> anyhow::Result<Self> {
+        let f = File::open(dir.join(MANIFEST_FILE))?;
+        // older versions had fewer fields, so their per-field arrays fail
+        // to parse
+        serde_json::from_reader(std::io::BufReader::new(f))
+            .context("manifest has an unknown format (rebuild the index)")
+    }
+
+    fn save(&self, dir: &Path) -> anyhow::Result<()> {
+        let tmp = dir.join(format!("{}.tmp", MANIFEST_FILE));
+        {
+            let mut bw = BufWriter::new(File::create(&tmp)?);
+            serde_json::to_writer_pretty(&mut bw, self)?;
+            bw.flush()?;
+        }
+        std::fs::rename(&tmp, dir.join(MANIFEST_FILE))?;
+        Ok(())
+    }
+
+    /// Total (raw, encoded) postings size over all segments.
+    pub fn postings_sizes(&self) -> (u64, u64) {
+        self.segments.iter().fold((0, 0), |(raw, enc), s| {
+            (raw + s.raw_postings_bytes, enc + s.postings_bytes)
+        })
+    }
+
+    fn referenced_files(&self) -> HashSet<String> {
+        let mut files = HashSet::new();
+        for s in &self.segments {
+            files.insert(s.terms_file());
+            files.insert(s.postings_file());
+            files.insert(s.docs_file());
+            if s.has_grams {
+                files.insert(s.grams_file());
+                files.insert(s.gram_lists_file());
+            }
+            files.extend(s.del_file());
+        }
+        files
+    }
+}
+
+const POSTINGS_MAGIC: &[u8; 8] = b"SEPOSTS5";
+const DOCS_MAGIC: &[u8; 8] = b"SEDOCS04";
+const GRAMS_MAGIC: &[u8; 8] = b"SEGRAMS1";
+
+/// A memory-mapped segment. Nothing is decoded up front: the term
+/// dictionary is an FST walked in place and postings and stored docs are
+/// decoded on demand, so opening a segment costs the same at any size.
+///
+/// File layout (all integers little-endian):
+///
+/// ```text
+/// .terms  FST map from term to its postings offset in .post
+/// .post   magic | per term: varint count | varint length | surface form | count × posting
+///         posting = varint doc gap | F × varint field tf | tf × varint position gap
+/// .docs   magic | u64 n | n × F × u32 field length | n × f32 PageRank | (n+1) × u64 blob offset
+///         | bincode DocMeta blobs
+/// .del    bitset, one bit per local doc id, set = deleted
+/// .grams  FST map from character n-gram to its term list offset in .glist (optional)
+/// .glist  magic | per gram: varint count | count × (varint length | term bytes), sorted
+/// ```
+///
+/// F is `FIELD_COUNT`; a posting's tf is the sum of its field tfs. A term's
+/// surface form is the word documents most often wrote it as, empty when
+/// that is the term itself.
+/// Doc ids inside a segment are local (0-based).
+pub struct Segment {
+    pub info: SegmentInfo,
+    terms: Map<Mmap>,
+    postings: Mmap,
+    docs: Mmap,
+    deleted: Option<Mmap>,
+    /// `.grams` and `.glist`, if the segment has them.
+    grams: Option<(Map<Mmap>, Mmap)>,
+}
+
+/// Maps a segment file read-only.
+fn map_file(path: &Path) -> anyhow::Result<Mmap> {
+    let f = File::open(path)?;
+    // SAFETY: segment files are never modified after being written; new
+    // data (including deletions) always goes to a freshly named file.
+    Ok(unsafe { Mmap::map(&f)? })
+}
+
+impl Segment {
+    pub fn open(dir: &Path, info: &SegmentInfo) -> anyhow::Result<Self> {
+        let terms = Map::new(map_file(&dir.join(info.terms_file()))?)?;
+        let postings = map_file(&dir.join(info.postings_file()))?;
+        let docs = map_file(&dir.join(info.docs_file()))?;
+        check_magic(&postings, POSTINGS_MAGIC, "postings file")?;
+        check_magic(&docs, DOCS_MAGIC, "doc store")?;
+
+        let deleted = match info.del_file() {
+            Some(name) => Some(map_file(&dir.join(name))?),
+            None => None,
+        };
+        let grams = if info.has_grams {
+            let index = Map::new(map_file(&dir.join(info.grams_file()))?)?;
+            let lists = map_file(&dir.join(info.gram_lists_file()))?;
+            check_magic(&lists, GRAMS_MAGIC, "n-gram term lists")?;
+            Some((index, lists))
+        } else {
+            None
+        };
+
+        Ok(Segment {
+            info: info.clone(),
+            terms,
+            postings,
+            docs,
+            deleted,
+            grams,
+        })
+    }
+
+    pub fn is_live(&self, local_id: usize) -> bool {
+        match &self.deleted {
+            Some(bits) => bits[local_id / 8] & (1 << (local_id % 8)) == 0,
+            None => true,
+        }
+    }
+
+    pub fn doc_count(&self) -> usize {
+        get_u64(&self.docs, 8) as usize
+    }
+
+    pub fn field_len(&self, local_id: usize, field: Field) -> usize {
+        get_u32(
+            &self.docs,
+            16 + (local_id * FIELD_COUNT + field.index()) * 4,
+        ) as usize
+    }
+
+    pub fn pagerank(&self, local_id: usize) -> f64 {
+        let n = self.doc_count();
+        f32::from_bits(get_u32(&self.docs, 16 + n * FIELD_COUNT * 4 + local_id * 4)) as f64
+    }
+
+    pub fn doc(&self, local_id: usize) -> DocMeta {
+        let n = self.doc_count();
+        let offsets = 16 + n * (FIELD_COUNT + 1) * 4;
+        let blobs = offsets + (n + 1) * 8;
+        let start = get_u64(&self.docs, offsets + local_id * 8) as usize;
+        let end = get_u64(&self.docs, offsets + (local_id + 1) * 8) as usize;
+        bincode::deserialize(&self.docs[blobs + start..blobs + end])
+            .expect("corrupt doc store entry")
+    }
+
+    pub fn term_dict(&self) -> &Map<Mmap> {
+        &self.terms
+    }
+
+    /// Decoding iterator over the postings of `term` (local doc ids), or
+    /// `None` if the segment does not contain it.
+    pub fn postings(&self, term: &str) -> Option<PostingsIter<'_>> {
+        let offset = self.terms.get(term)?;
+        Some(PostingsIter::new(&self.postings, offset as usize))
+    }
+
+    /// Sorted terms containing every one of `grams`, from the n-gram index.
+    /// `None` if the segment has no n-gram index.
+    pub fn terms_with_grams(&self, grams: &[String]) -> Option<Vec<String>> {
+        let (index, lists) = self.grams.as_ref()?;
+        let mut terms: Option<Vec<String>> = None;
+        for gram in grams {
+            let Some(offset) = index.get(gram) else {
+                return Some(vec![]);
+            };
+            let list = read_term_list(lists, offset as usize);
+            let narrowed = match terms {
+                Some(mut acc) => {
+                    acc.retain(|t| list.binary_search(t).is_ok());
+                    acc
+                }
+                None => list,
+            };
+            if narrowed.is_empty() {
+                return Some(narrowed);
+            }
+            terms = Some(narrowed);
+        }
+        Some(terms.unwrap_or_default())
+    }
+
+    /// Document frequency of the postings list starting at `offset` (the
+    /// value stored for a term in the dictionary).
+    pub fn doc_freq_at(&self, offset: u64) -> usize {
+        let mut pos = offset as usize;
+        get_varint(&self.postings, &mut pos) as usize
+    }
+
+    /// Surface form of the term whose postings start at `offset`, or `None`
+    /// if it is written like the term.
+    pub fn surface_at(&self, offset: u64) -> Option<String> {
+        let mut pos = offset as usize;
+        get_varint(&self.postings, &mut pos);
+        let len = get_varint(&self.postings, &mut pos) as usize;
+        (len > 0).then(|| String::from_utf8_lossy(&self.postings[pos..pos + len]).into_owned())
+    }
+}
+
+/// Lazily decodes one delta/varint-encoded postings list.
+pub struct PostingsIter<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    remaining: usize,
+    last_doc: usize,
+}
+
+impl<'a> PostingsIter<'a> {
+    fn new(buf: &'a [u8], offset: usize) -> Self {
+        let mut pos = offset;
+        let remaining = get_varint(buf, &mut pos) as usize;
+        pos += get_varint(buf, &mut pos) as usize; // surface form
+        PostingsIter {
+            buf,
+            pos,
+            remaining,
+            last_doc: 0,
+        }
+    }
+}
+
+impl Iterator for PostingsIter<'_> {
+    type Item = Posting;
+
+    fn next(&mut self) -> Option<Posting> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let doc_id = self.last_doc + get_varint(self.buf, &mut self.pos) as usize;
+        self.last_doc = doc_id;
+        let mut field_tf = [0; FIELD_COUNT];
+        for f in field_tf.iter_mut() {
+            *f = get_varint(self.buf, &mut self.pos) as usize;
+        }
+        let tf = field_tf.iter().sum();
+        let mut positions = Vec::with_capacity(tf);
+        let mut last = 0;
+        for _ in 0..tf {
+            last += get_varint(self.buf, &mut self.pos) as usize;
+            positions.push(last);
+        }
+        Some(Posting {
+            doc_id,
+            tf,
+            field_tf,
+            positions,
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+fn read_term_list(buf: &[u8], offset: usize) -> Vec<String> {
+    let mut pos = offset;
+    let count = get_varint(buf, &mut pos) as usize;
+    (0..count)
+        .map(|_| {
+            let len = get_varint(buf, &mut pos) as usize;
+            pos += len;
+            String::from_utf8_lossy(&buf[pos - len..pos]).into_owned()
+        })
+        .collect()
+}
+
+fn load_deletes(dir: &Path, info: &SegmentInfo) -> anyhow::Result<Vec<u8>> {
+    match info.del_file() {
+        Some(name) => Ok(std::fs::read(dir.join(name))?),
+        None => Ok(vec![0; info.doc_count.div_ceil(8)]),
+    }
+}
+
+/// In-memory buffer of documents that becomes one segment when written.
+#[derive(Default)]
+pub struct SegmentBuilder {
+    dict: HashMap<String, Vec<Posting>>,
+    docs: Vec<DocMeta>,
+    /// Term → number of documents writing it in each surface form.
+    forms: HashMap<String, HashMap<String, usize>>,
+}
+
+impl SegmentBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.docs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.docs.is_empty()
+    }
+
+    pub fn add_document(&mut self, meta: DocMeta, terms: DocTerms, surfaces: Surfaces) {
+        let doc_id = self.docs.len();
+        self.docs.push(meta);
+        for (term, mut posting) in terms {
+            posting.doc_id = doc_id;
+            self.dict.entry(term).or_default().push(posting);
+        }
+        for (term, form) in surfaces {
+            *self.forms.entry(term).or_default().entry(form).or_default() += 1;
+        }
+    }
+
+    /// Appends the live documents of `segment`, renumbering them after the
+    /// documents already in the builder.
+    fn add_segment(&mut self, segment: &Segment) {
+        let base = self.docs.len();
+        let mut remap = vec![usize::MAX; segment.doc_count()];
+        for (local, slot) in remap.iter_mut().enumerate() {
+            if segment.is_live(local) {
+                *slot = self.docs.len() - base;
+                self.docs.push(segment.doc(local));
+            }
+        }
+
+        let mut stream = segment.terms.stream();
+        while let Some((term, offset)) = stream.next() {
+            let live: Vec<Posting> = PostingsIter::new(&segment.postings, offset as usize)
+                .filter(|p| remap[p.doc_id] != usize::MAX)
+                .map(|mut p| {
+                    p.doc_id = base + remap[p.doc_id];
+                    p
+                })
+                .collect();
+            if !live.is_empty() {
+                let term = String::from_utf8_lossy(term).into_owned();
+                if let Some(form) = segment.surface_at(offset) {
+                    *self
+                        .forms
+                        .entry(term.clone())
+                        .or_default()
+                        .entry(form)
+                        .or_default() += live.len();
+                }
+                self.dict.entry(term).or_default().extend(live);
+            }
+        }
+    }
+
+    fn write(mut self, dir: &Path, name: String, grams: bool) -> anyhow::Result<SegmentInfo> {
+        self.dict.par_iter_mut().for_each(|(_, postings)| {
+            postings.sort_by_key(|p| p.doc_id);
+        });
+        let mut terms: Vec<(String, Vec<Posting>)> = self.dict.into_iter().collect();
+        terms.par_sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+        let mut info = SegmentInfo {
+            name,
+            doc_count: self.docs.len(),
+            del_count: 0,
+            del_gen: 0,
+            total_len: self.docs.iter().map(|d| d.length).sum(),
+            field_len: std::array::from_fn(|f| self.docs.iter().map(|d| d.field_lengths[f]).sum()),
+            postings_bytes: 0,
+            raw_postings_bytes: 0,
+            has_grams: grams,
+        };
+
+        // postings, remembering where each term's list starts
+        let mut post =
+            BufWriter::with_capacity(1024 * 1024, File::create(dir.join(info.postings_file()))?);
+        post.write_all(POSTINGS_MAGIC)?;
+        let mut offset = POSTINGS_MAGIC.len();
+        let mut dict = MapBuilder::new(BufWriter::new(File::create(dir.join(info.terms_file()))?))?;
+        let mut scratch = Vec::new();
+        for (term, postings) in &terms {
+            scratch.clear();
+            put_varint(&mut scratch, postings.len() as u64);
+            let form = self
+                .forms
+                .remove(term)
+                .map(most_common_form)
+                .filter(|f| f != term)
+                .unwrap_or_default();
+            put_varint(&mut scratch, form.len() as u64);
+            scratch.extend_from_slice(form.as_bytes());
+            let mut last_doc = 0;
+            for p in postings {
+                put_varint(&mut scratch, (p.doc_id - last_doc) as u64);
+                last_doc = p.doc_id;
+                for &tf in &p.field_tf {
+                    put_varint(&mut scratch, tf as u64);
+                }
+                let mut last_pos = 0;
+                for &position in &p.positions {
+                    put_varint(&mut scratch, (position - last_pos) as u64);
+                    last_pos = position;
+                }
+                // doc_id, tf, field tfs and the positions Vec (length + items)
+                info.raw_postings_bytes += ((3 + FIELD_COUNT + p.positions.len()) * 8) as u64;
+            }
+            post.write_all(&scratch)?;
+
+            // terms are sorted, as the FST builder requires
+            dict.insert(term, offset as u64)?;
+
+            offset += scratch.len();
+        }
+        post.flush()?;
+        dict.finish()?;
+        info.postings_bytes = offset as u64;
+
+        // doc store: lengths and PageRank up front so scoring never decodes
+        // a DocMeta
+        let mut blobs = Vec::new();
+        let mut blob_offsets = Vec::with_capacity(self.docs.len() + 1);
+        for d in &self.docs {
+            blob_offsets.push(blobs.len());
+            bincode::serialize_into(&mut blobs, d)?;
+        }
+        blob_offsets.push(blobs.len());
+
+        let mut buf = Vec::with_capacity(
+            16 + self.docs.len() * ((FIELD_COUNT + 1) * 4 + 8) + 8 + blobs.len(),
+        );
+        buf.extend_from_slice(DOCS_MAGIC);
+        put_u64(&mut buf, self.docs.len() as u64);
+        for d in &self.docs {
+            for &len in &d.field_lengths {
+                put_u32(&mut buf, len as u32);
+            }
+        }
+        for d in &self.docs {
+            put_u32(&mut buf, (d.pagerank as f32).to_bits());
+        }
+        for o in blob_offsets {
+            put_u64(&mut buf, o as u64);
+        }
+        buf.extend_from_slice(&blobs);
+        std::fs::write(dir.join(info.docs_file()), &buf)?;
+
+        if grams {
+            write_grams(dir, &info, terms.iter().map(|(t, _)| t.as_str()))?;
+        }
+
+        Ok(info)
+    }
+}
+
+/// Writes the n-gram index of `terms`, which must be sorted so every gram's
+/// term list comes out sorted too.
+fn write_grams<'a>(
+    dir: &Path,
+    info: &SegmentInfo,
+    terms: impl Iterator<Item = &'a str>,
+) -> anyhow::Result<()> {
+    let mut by_gram: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for term in terms {
+        for gram in ngram::term_grams(term) {
+            by_gram.entry(gram).or_default().push(term);
+        }
+    }
+
+    let mut lists = Vec::new();
+    lists.extend_from_slice(GRAMS_MAGIC);
+    let mut index = MapBuilder::new(BufWriter::new(File::create(dir.join(info.grams_file()))?))?;
+    for (gram, terms) in &by_gram {
+        index.insert(gram, lists.len() as u64)?;
+        put_varint(&mut lists, terms.len() as u64);
+        for term in terms {
+            put_varint(&mut lists, term.len() as u64);
+            lists.extend_from_slice(term.as_bytes());
+        }
+    }
+    index.finish()?;
+    std::fs::write(dir.join(info.gram_lists_file()), &lists)?;
+    Ok(())
+}
+
+/// Log-structured merge policy: segments are bucketed by the order of
+/// magnitude (base `merge_factor`) of their live doc count, and any bucket
+/// holding `merge_factor` segments is merged into one. Segments whose
+/// deleted ratio exceeds `max_deleted_ratio` are rewritten on their own to
+/// reclaim space.
+#[derive(Debug, Clone)]
+pub struct MergePolicy {
+    pub merge_factor: usize,
+    pub max_deleted_ratio: f64,
+}
+
+impl Default for MergePolicy {
+    fn default() -> Self {
+        MergePolicy {
+            merge_factor: 10,
+            max_deleted_ratio: 0.3,
+        }
+    }
+}
+
+impl MergePolicy {
+    pub fn find_merges(&self, segments: &[SegmentInfo]) -> Vec<Vec<String>> {
+        let factor = self.merge_factor.max(2);
+        let mut merges = Vec::new();
+        let mut taken: HashSet<&str> = HashSet::new();
+
+        let mut levels: HashMap<u32, Vec<&SegmentInfo>> = HashMap::new();
+        for s in segments {
+            let level = (s.live_docs().max(1) as f64).log(factor as f64).floor() as u32;
+            levels.entry(level).or_default().push(s);
+        }
+        let mut keys: Vec<_> = levels.keys().copied().collect();
+        keys.sort_unstable();
+        for level in keys {
+            for chunk in levels[&level].chunks(factor) {
+                if chunk.len() == factor {
+                    taken.extend(chunk.iter().map(|s| s.name.as_str()));
+                    merges.push(chunk.iter().map(|s| s.name.clone()).collect());
+                }
+            }
+        }
+
+        for s in segments {
+            if taken.contains(s.name.as_str()) || s.del_count == 0 {
+                continue;
+            }
+            if s.del_count as f64 / s.doc_count.max(1) as f64 > self.max_deleted_ratio {
+                merges.push(vec![s.name.clone()]);
+            }
+        }
+        merges
+    }
+}
+
+/// Exclusive writer for an index directory. Holding one keeps other
+/// processes (e.g. a concurrent `update` and the server's merge thread)
+/// from committing at the same time.
+pub struct IndexWriter {
+    dir: PathBuf,
+    manifest: Manifest,
+    pending_deletes: HashMap<String, HashSet<usize>>,
+    _lock: WriteLock,
+}
+
+struct WriteLock(PathBuf);
+
+impl Drop for WriteLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+impl IndexWriter {
+    /// Starts a new, empty index in `dir`. Existing segments are dropped
+    /// from the manifest on the first commit.
+    pub fn create(dir: &Path, analysis: AnalysisConfig, ngrams: bool) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let lock = acquire_lock(dir)?.ok_or_else(|| lock_error(dir))?;
+        let previous = Manifest::load(dir).unwrap_or_default();
+        Ok(IndexWriter {
+            dir: dir.to_path_buf(),
+            manifest: Manifest {
+                generation: previous.generation,
+                next_segment_id: previous.next_segment_id,
+                segments: Vec::new(),
+                analysis,
+                ngrams,
+            },
+            pending_deletes: HashMap::new(),
+            _lock: lock,
+        })
+    }
+
+    pub fn open(dir: &Path) -> anyhow::Result<Self> {
+        Self::try_open(dir)?.ok_or_else(|| lock_error(dir))
+    }
+
+    /// Like `open`, but returns `None` instead of failing when another
+    /// writer holds the lock.
+    pub fn try_open(dir: &Path) -> anyhow::Result<Option<Self>> {
+        let Some(lock) = acquire_lock(dir)? else {
+            return Ok(None);
+        };
+        Ok(Some(IndexWriter {
+            dir: dir.to_path_buf(),
+            manifest: Manifest::load(dir)?,
+            pending_deletes: HashMap::new(),
+            _lock: lock,
+        }))
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    pub fn add_segment(&mut self, builder: SegmentBuilder) -> anyhow::Result<()> {
+        if builder.is_empty() {
+            return Ok(());
+        }
+        let name = self.next_segment_name();
+        let info = builder.write(&self.dir, name, self.manifest.ngrams)?;
+        self.manifest.segments.push(info);
+        Ok(())
+    }
+
+    pub fn delete_document(&mut self, segment: &str, local_id: usize) {
+        self.pending_deletes
+            .entry(segment.to_string())
+            .or_default()
+            .insert(local_id);
+    }
+
+    /// Publishes everything written since the last commit as a new manifest
+    /// generation and removes files no longer referenced by it.
+    pub fn commit(&mut self) -> anyhow::Result<()> {
+        let generation = self.manifest.generation + 1;
+
+        for info in self.manifest.segments.iter_mut() {
+            let Some(new_deletes) = self.pending_deletes.remove(&info.name) else {
+                continue;
+            };
+            let mut bits = load_deletes(&self.dir, info)?;
+            for id in new_deletes {
+                bits[id / 8] |= 1 << (id % 8);
+            }
+
+            info.del_gen = generation;
+            info.del_count = bits.iter().map(|b| b.count_ones() as usize).sum();
+            std::fs::write(self.dir.join(info.del_file().unwrap()), &bits)?;
+        }
+        self.pending_deletes.clear();
+
+        self.manifest.generation = generation;
+        self.manifest.save(&self.dir)?;
+        self.remove_unreferenced_files()?;
+        Ok(())
+    }
+
+    /// Applies `policy` until it finds nothing left to merge, committing
+    /// after each merge. Returns the number of merges performed.
+    pub fn maybe_merge(&mut self, policy: &MergePolicy) -> anyhow::Result<usize> {
+        let mut merges = 0;
+        loop {
+            let candidates = policy.find_merges(&self.manifest.segments);
+            if candidates.is_empty() {
+                return Ok(merges);
+            }
+            for names in candidates {
+                self.merge_segments(&names)?;
+                merges += 1;
+            }
+        }
+    }
+
+    /// Merges every segment into a single one, dropping all deletions.
+    pub fn force_merge(&mut self) -> anyhow::Result<()> {
+        let names: Vec<String> = self
+            .manifest
+            .segments
+            .iter()
+            .map(|s| s.name.clone())
+            .collect();
+        let needs_merge = names.len() > 1 || self.manifest.segments.iter().any(|s| s.del_count > 0);
+        if needs_merge {
+            self.merge_segments(&names)?;
+        }
+        Ok(())
+    }
+
+    fn merge_segments(&mut self, names: &[String]) -> anyhow::Result<()> {
+        // pending deletes must be on disk before the segments are read back
+        if !self.pending_deletes.is_empty() {
+            self.commit()?;
+        }
+
+        let mut builder = SegmentBuilder::new();
+        let mut insert_at = None;
+        for (i, info) in self.manifest.segments.iter().enumerate() {
+            if names.contains(&info.name) {
+                insert_at.get_or_insert(i);
+                builder.add_segment(&Segment::open(&self.dir, info)?);
+            }
+        }
+        let Some(insert_at) = insert_at else {
+            return Ok(());
+        };
+
+        // the merged segment takes the place of the first source segment so
+        // relative document order is preserved across the index
+        self.manifest.segments.retain(|s| !names.contains(&s.name));
+        if !builder.is_empty() {
+            let name = self.next_segment_name();
+            let info = builder.write(&self.dir, name, self.manifest.ngrams)?;
+            self.manifest.segments.insert(insert_at, info);
+        }
+        self.commit()
+    }
+
+    fn next_segment_name(&mut self) -> String {
+        let id = self.manifest.next_segment_id;
+        self.manifest.next_segment_id += 1;
+        format!("{}{:06}", SEGMENT_PREFIX, id)
+    }
+
+    fn remove_unreferenced_files(&self) -> anyhow::Result<()> {
+        let keep = self.manifest.referenced_files();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            if name.starts_with(SEGMENT_PREFIX) && !keep.contains(&name) {
+                let _ = std::fs::remove_file(self.dir.join(&name));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn acquire_lock(dir: &Path) -> anyhow::Result<Option<WriteLock>> {
+    let path = dir.join(LOCK_FILE);
+    match OpenOptions::new().write(true).create_new(true).open(&path) {
+        Ok(mut f) => {
+            writeln!(f, "{}", std::process::id())?;
+            Ok(Some(WriteLock(path)))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn lock_error(dir: &Path) -> anyhow::Error {
+    anyhow::anyhow!(
+        "index {:?} is locked by another writer (remove {} if no writer is running)",
+        dir,
+        LOCK_FILE
+    )
+}
+
+/// Total size in bytes of the files in an index directory.
+pub fn dir_size(dir: &Path) -> u64 {
+    std::fs::read_dir(dir)
+        .map(|rd| {
+            rd.filter_map(|e| e.ok())
+                .filter_map(|e| e.metadata().ok())
+                .filter(|m| m.is_file())
+                .map(|m| m.len())
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::field::FIELD_POSITION_GAP;
+    use crate::parser::Link;
+
+    pub(crate) fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("segment-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A document whose body is `words`, each its own term, at positions
+    /// 0, 1, 2, …
+    pub(crate) fn doc(url: &str, words: &[&str]) -> (DocMeta, DocTerms, Surfaces) {
+        fielded_doc(url, &[(Field::Body, words)])
+    }
+
+    /// A document with the given words in each field, each its own term.
+    /// Positions run on from field to field, `FIELD_POSITION_GAP` apart.
+    pub(crate) fn fielded_doc(
+        url: &str,
+        fields: &[(Field, &[&str])],
+    ) -> (DocMeta, DocTerms, Surfaces) {
+        let mut terms = DocTerms::new();
+        let mut field_lengths = [0; FIELD_COUNT];
+        let mut position = 0;
+        for (i, &(field, words)) in fields.iter().enumerate() {
+            if i > 0 {
+                position += FIELD_POSITION_GAP;
+            }
+            for word in words {
+                let posting = terms.entry(word.to_string()).or_insert_with(|| Posting {
+                    doc_id: 0,
+                    tf: 0,
+                    field_tf: [0; FIELD_COUNT],
+                    positions: Vec::new(),
+                });
+                posting.tf += 1;
+                posting.field_tf[field.index()] += 1;
+                posting.positions.push(position);
+                position += 1;
+            }
+            field_lengths[field.index()] += words.len();
+        }
+        let text = |wanted: Field| {
+            fields
+                .iter()
+                .filter(|(field, _)| *field == wanted)
+                .map(|(_, words)| words.join(" "))
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        let title = text(Field::Title);
+        let meta = DocMeta {
+            url: url.to_string(),
+            title: if title.is_empty() {
+                url.to_uppercase()
+            } else {
+                title
+            },
+            body: text(Field::Body),
+            length: field_lengths.iter().sum(),
+            field_lengths,
+            path: url.to_string(),
+            mtime: 0,
+            content_hash: 0,
+            links: Vec::new(),
+            pagerank: 1.0,
+        };
+        (meta, terms, Surfaces::new())
+    }
+
+    pub(crate) fn build(docs: Vec<(DocMeta, DocTerms, Surfaces)>) -> SegmentBuilder {
+        let mut builder = SegmentBuilder::new();
+        for (meta, terms, surfaces) in docs {
+            builder.add_document(meta, terms, surfaces);
+        }
+        builder
+    }
+
+    fn write_segment(dir: &Path, builder: SegmentBuilder) -> Segment {
+        let info = builder.write(dir, "seg_000000".to_string(), false).unwrap();
+        Segment::open(dir, &info).unwrap()
+    }
+
+    #[test]
+    fn postings_round_trip_through_gaps_and_varints() {
+        let dir = temp_dir("postings");
+        // far apart doc ids and positions need multi-byte gaps
+        let mut docs: Vec<_> = (0..300)
+            .map(|i| doc(&format!("d{}", i), &["filler"]))
+            .collect();
+        let mut words = vec!["x"; 200];
+        words[0] = "rare";
+        words[150] = "rare";
+        docs[7] = doc("d7", &words);
+        docs[299] = doc("d299", &["rare", "rare", "rare"]);
+        let segment = write_segment(&dir, build(docs));
+
+        let postings: Vec<Posting> = segment.postings("rare").unwrap().collect();
+        assert_eq!(
+            postings.iter().map(|p| p.doc_id).collect::<Vec<_>>(),
+            [7, 299]
+        );
+        assert_eq!(postings[0].positions, [0, 150]);
+        assert_eq!(postings[0].tf, 2);
+        assert_eq!(postings[0].field_positions(Field::Body), [0, 150]);
+        assert_eq!(postings[0].field_tf[Field::Title.index()], 0);
+        assert_eq!(postings[1].positions, [0, 1, 2]);
+
+        let filler = segment.postings("filler").unwrap();
+        assert_eq!(filler.size_hint(), (298, Some(298)));
+        assert!(segment.postings("missing").is_none());
+        assert!(segment.info.postings_bytes < segment.info.raw_postings_bytes / 4);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn doc_store_and_term_data_read_back() {
+        let dir = temp_dir("docs");
+        let (mut a, terms_a, mut surfaces_a) = doc("a", &["کتاب", "خوب"]);
+        a.pagerank = 2.5;
+        a.links = vec![Link {
+            url: "b".to_string(),
+            text: "بعدی".to_string(),
+        }];
+        surfaces_a.insert("کتاب".to_string(), "کتاب‌ها".to_string());
+        let (b, terms_b, mut surfaces_b) = doc("b", &["کتاب", "کتاب", "دیگر"]);
+        surfaces_b.insert("کتاب".to_string(), "کتاب‌ها".to_string());
+        surfaces_b.insert("دیگر".to_string(), "دیگر".to_string());
+        let mut builder = build(vec![(a, terms_a, surfaces_a)]);
+        builder.add_document(b, terms_b, surfaces_b);
+        let segment = write_segment(&dir, builder);
+
+        assert_eq!(segment.doc_count(), 2);
+        assert_eq!(segment.field_len(1, Field::Body), 3);
+        assert_eq!(segment.field_len(1, Field::Title), 0);
+        assert_eq!(segment.pagerank(0), 2.5);
+        let stored = segment.doc(0);
+        assert_eq!(stored.url, "a");
+        assert_eq!(stored.body, "کتاب خوب");
+        assert_eq!(stored.links[0].text, "بعدی");
+        assert_eq!(segment.doc(1).title, "B");
+
+        let offset = segment.term_dict().get("کتاب").unwrap();
+        assert_eq!(segment.doc_freq_at(offset), 2);
+        assert_eq!(segment.surface_at(offset).as_deref(), Some("کتاب‌ها"));
+        // a form equal to the term is not stored
+        let offset = segment.term_dict().get("دیگر").unwrap();
+        assert_eq!(segment.surface_at(offset), None);
+        assert!(segment.is_live(0) && segment.is_live(1));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn files_of_another_format_are_rejected() {
+        let dir = temp_dir("magic");
+        let info = build(vec![doc("a", &["x"])])
+            .write(&dir, "seg_000000".to_string(), false)
+            .unwrap();
+        let post = dir.join(info.postings_file());
+        let mut bytes = std::fs::read(&post).unwrap();
+        bytes[..8].copy_from_slice(b"SEPOSTS1");
+        std::fs::write(&post, bytes).unwrap();
+        let err = Segment::open(&dir, &info).err().unwrap();
+        assert!(err.to_string().contains("rebuild the index"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn info(name: &str, doc_count: usize, del_count: usize) -> SegmentInfo {
+        SegmentInfo {
+            name: name.to_string(),
+            doc_count,
+            del_count,
+            del_gen: 0,
+            total_len: 0,
+            field_len: [0; FIELD_COUNT],
+            postings_bytes: 0,
+            raw_postings_bytes: 0,
+            has_grams: false,
+        }
+    }
+
+    #[test]
+    fn merge_policy_merges_full_levels_and_mostly_deleted_segments() {
+        let policy = MergePolicy {
+            merge_factor: 3,
+            max_deleted_ratio: 0.3,
+        };
+        // levels by live docs, base 3: 1–2 → 0, 3–8 → 1, 9–26 → 2
+        let segments = [
+            info("a", 1, 0),
+            info("b", 2, 0),
+            info("c", 20, 0),
+            info("d", 2, 0),
+            info("e", 5, 0),
+            info("f", 12, 0),
+            info("g", 14, 4),
+            info("h", 1, 0),
+        ];
+        // g has too many deletes too, but is merged with its level anyway
+        assert_eq!(
+            policy.find_merges(&segments),
+            [vec!["a", "b", "d"], vec!["c", "f", "g"]]
+        );
+
+        let segments = [info("a", 10, 4), info("b", 10, 3), info("c", 9, 0)];
+        assert_eq!(policy.find_merges(&segments), [vec!["a"]]);
+        assert!(policy.find_merges(&[info("a", 5, 0)]).is_empty());
+    }
+
+    #[test]
+    fn deletes_are_committed_and_dropped_by_merges() {
+        let dir = temp_dir("merge");
+        let mut writer = IndexWriter::create(&dir, AnalysisConfig::default(), false).unwrap();
+        writer
+            .add_segment(build(vec![doc("a", &["x", "y"]), doc("b", &["y"])]))
+            .unwrap();
+        writer
+            .add_segment(build(vec![doc("c", &["x"]), doc("d", &["z"])]))
+            .unwrap();
+        writer.commit().unwrap();
+
+        writer.delete_document("seg_000000", 0);
+        writer.delete_document("seg_000001", 1);
+        writer.commit().unwrap();
+        let manifest = Manifest::load(&dir).unwrap();
+        let first = &manifest.segments[0];
+        assert_eq!((first.del_count, first.del_gen), (1, 2));
+        let segment = Segment::open(&dir, first).unwrap();
+        assert!(!segment.is_live(0) && segment.is_live(1));
+        // postings keep deleted docs until a merge
+        assert_eq!(segment.postings("x").unwrap().count(), 1);
+
+        writer.force_merge().unwrap();
+        let manifest = Manifest::load(&dir).unwrap();
+        assert_eq!(manifest.segments.len(), 1);
+        let merged = Segment::open(&dir, &manifest.segments[0]).unwrap();
+        assert_eq!(manifest.segments[0].del_count, 0);
+        let urls: Vec<String> = (0..merged.doc_count()).map(|i| merged.doc(i).url).collect();
+        assert_eq!(urls, ["b", "c"]);
+        let ids = |term: &str| -> Vec<usize> {
+            merged
+                .postings(term)
+                .map_or(vec![], |ps| ps.map(|p| p.doc_id).collect())
+        };
+        assert_eq!(ids("x"), [1]);
+        assert_eq!(ids("y"), [0]);
+        assert!(merged.postings("z").is_none());
+
+        // files of the replaced segments are gone
+        let files: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|n| n.starts_with(SEGMENT_PREFIX))
+            .collect();
+        assert!(
+            files.iter().all(|n| n.starts_with("seg_000002")),
+            "{:?}",
+            files
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,26 +1,67 @@
+use crate::analysis::Analyzer;
+use crate::api::RankingQuery;
+use crate::automaton::PatternTooComplex;
 use crate::indexer::IndexStore;
-use crate::search::{search, suggest_terms};
+use crate::ranking::Scorer;
+use crate::search::{search, SearchOptions};
+use crate::segment::{IndexWriter, Manifest, MergePolicy};
+use crate::spell::{did_you_mean, Correction};
+use crate::suggest::Suggester;
+use crate::synonyms::Expansion;
 use actix_web::{middleware::Logger, web, App, HttpResponse, HttpServer, Responder};
 use serde::Deserialize;
 use std::collections::HashSet;
-use std::sync::Arc;
-use std::time::Instant;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// Upper bound on the number of ranked results kept per query.
+pub const MAX_RESULTS: usize = 1000;
 
 #[derive(Clone)]
 pub struct AppState {
-    pub index: Arc<IndexStore>,
+    index: Arc<RwLock<Arc<IndexStore>>>,
+    pub options: Arc<SearchOptions>,
+    pub suggester: Arc<Suggester>,
+}
+
+impl AppState {
+    pub fn new(index: IndexStore, options: SearchOptions, suggester: Suggester) -> Self {
+        AppState {
+            index: Arc::new(RwLock::new(Arc::new(index))),
+            options: Arc::new(options),
+            suggester: Arc::new(suggester),
+        }
+    }
+
+    /// Snapshot of the current index. Requests keep using the snapshot they
+    /// started with even if a merge swaps in a newer one meanwhile.
+    pub fn index(&self) -> Arc<IndexStore> {
+        self.index.read().unwrap().clone()
+    }
 }
 
 #[derive(Deserialize)]
 pub struct Query {
     q: String,
     page: Option<usize>,
+    #[serde(flatten)]
+    ranking: RankingQuery,
 }
 
-pub async fn run_server(index: IndexStore, host: String) -> anyhow::Result<()> {
-    let data = web::Data::new(AppState {
-        index: Arc::new(index),
-    });
+pub async fn run_server(
+    index: IndexStore,
+    index_dir: PathBuf,
+    host: String,
+    merge_interval: Duration,
+    policy: MergePolicy,
+    options: SearchOptions,
+    suggester: Suggester,
+) -> anyhow::Result<()> {
+    let data = web::Data::new(AppState::new(index, options, suggester));
+    if !merge_interval.is_zero() {
+        spawn_maintenance(data.index.clone(), index_dir, merge_interval, policy);
+    }
 
     println!("🚀 Server starting at http://{}", host);
 
@@ -30,6 +71,8 @@ pub async fn run_server(index: IndexStore, host: String) -> anyhow::Result<()> {
             .wrap(Logger::default())
             .route("/", web::get().to(index_page))
             .route("/search", web::get().to(search_handler))
+            .route("/api/search", web::get().to(crate::api::search_api))
+            .route("/api/suggest", web::get().to(crate::api::suggest_api))
     })
     .bind(host)?
     .run()
@@ -37,6 +80,43 @@ pub async fn run_server(index: IndexStore, host: String) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Periodically runs the merge policy and reopens the index whenever the
+/// manifest generation changes (after a merge here or an external `update`).
+fn spawn_maintenance(
+    shared: Arc<RwLock<Arc<IndexStore>>>,
+    dir: PathBuf,
+    interval: Duration,
+    policy: MergePolicy,
+) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+
+        // another process holding the write lock just means we merge later
+        if let Ok(Some(mut writer)) = IndexWriter::try_open(&dir) {
+            if let Err(e) = writer.maybe_merge(&policy) {
+                eprintln!("⚠️ Background merge failed: {}", e);
+            }
+        }
+
+        let current = shared.read().unwrap().generation;
+        match Manifest::load(&dir) {
+            Ok(m) if m.generation != current => match IndexStore::open(&dir) {
+                Ok(fresh) => {
+                    println!(
+                        "🔄 Reloaded index at generation {} ({} segment(s))",
+                        fresh.generation,
+                        fresh.segment_count()
+                    );
+                    *shared.write().unwrap() = Arc::new(fresh);
+                }
+                Err(e) => eprintln!("⚠️ Reloading index failed: {}", e),
+            },
+            Ok(_) => {}
+            Err(e) => eprintln!("⚠️ Reading manifest failed: {}", e),
+        }
+    });
+}
+
 async fn index_page() -> impl Responder {
     let html = r#"
     <!DOCTYPE html>
@@ -56,17 +136,74 @@ async fn index_page() -> impl Responder {
             button { background: var(--primary); color: white; border: none; padding: 12px 35px; border-radius: 99px; font-size: 16px; cursor: pointer; transition: 0.2s; font-weight: bold; }
             button:hover { background: #1d4ed8; transform: scale(1.05); }
             .info { margin-top: 25px; color: #64748b; font-size: 0.9rem; }
+            .search-wrap { position: relative; }
+            .suggestions { position: absolute; top: 100%; left: 20px; right: 20px; margin: 6px 0 0; padding: 6px 0; list-style: none; background: white; border: 1px solid #e2e8f0; border-radius: 16px; box-shadow: 0 10px 25px rgba(0,0,0,0.08); text-align: right; z-index: 10; }
+            .suggestions:empty { display: none; }
+            .suggestions li { padding: 8px 20px; cursor: pointer; }
+            .suggestions li.active, .suggestions li:hover { background: #eff6ff; }
+            .suggestions li.query::before { content: "🕘 "; }
         </style>
     </head>
     <body>
         <div class="container">
             <h1>Search Engine</h1>
-            <form action="/search" method="get" class="search-box">
-                <input type="text" name="q" placeholder="جستجو کنید..." required autofocus />
-                <button type="submit">search</button>
-            </form>
+            <div class="search-wrap">
+                <form action="/search" method="get" class="search-box">
+                    <input type="text" name="q" id="q" placeholder="جستجو کنید..." required autofocus autocomplete="off" />
+                    <button type="submit">search</button>
+                </form>
+                <ul class="suggestions" id="suggestions"></ul>
+            </div>
             <div class="info">طراحی شده با Rust</div>
         </div>
+        <script>
+            const input = document.getElementById('q');
+            const list = document.getElementById('suggestions');
+            let active = -1, timer = null, latest = 0;
+
+            function render(items) {
+                list.innerHTML = '';
+                active = -1;
+                for (const s of items) {
+                    const li = document.createElement('li');
+                    li.textContent = s.text;
+                    li.className = s.kind;
+                    li.addEventListener('mousedown', e => { e.preventDefault(); choose(s.text); });
+                    list.appendChild(li);
+                }
+            }
+            function choose(text) {
+                input.value = text;
+                input.form.submit();
+            }
+            function highlight(i) {
+                const items = list.children;
+                if (!items.length) return;
+                active = (i + items.length) % items.length;
+                for (let j = 0; j < items.length; j++) items[j].classList.toggle('active', j === active);
+                input.value = items[active].textContent;
+            }
+            input.addEventListener('input', () => {
+                clearTimeout(timer);
+                timer = setTimeout(async () => {
+                    const id = ++latest;
+                    const prefix = input.value;
+                    if (!prefix.trim()) { render([]); return; }
+                    try {
+                        const res = await fetch('/api/suggest?prefix=' + encodeURIComponent(prefix));
+                        const body = await res.json();
+                        // a slower earlier response must not overwrite a newer one
+                        if (id === latest) render(body.suggestions || []);
+                    } catch (e) { render([]); }
+                }, 120);
+            });
+            input.addEventListener('keydown', e => {
+                if (e.key === 'ArrowDown') { e.preventDefault(); highlight(active + 1); }
+                else if (e.key === 'ArrowUp') { e.preventDefault(); highlight(active - 1); }
+                else if (e.key === 'Escape') render([]);
+            });
+            input.addEventListener('blur', () => render([]));
+        </script>
     </body>
     </html>
     "#;
@@ -75,10 +212,13 @@ async fn index_page() -> impl Responder {
         .body(html)
 }
 
-fn render_pagination(q: &str, page: usize, total_pages: usize) -> String {
+/// Page links for `q`; `ranker` is carried along when the request chose
+/// one.
+fn render_pagination(q: &str, ranking: &RankingQuery, page: usize, total_pages: usize) -> String {
     if total_pages <= 1 {
         return String::new();
     }
+    let q = format!("{}{}", encode_query_param(q), ranking.link_params());
     let max_visible = 5;
     let mut html = String::from("<div class='pagination'>");
     html.push_str(r#"<style>
@@ -100,7 +240,7 @@ fn render_pagination(q: &str, page: usize, total_pages: usize) -> String {
     if page > 1 {
         html.push_str(&format!(
             "<a class='page-link' href='/search?q={}&page={}'>قبلی</a>",
-            html_escape::encode_text(q),
+            q,
             page - 1
         ));
     }
@@ -110,16 +250,14 @@ fn render_pagination(q: &str, page: usize, total_pages: usize) -> String {
         } else {
             html.push_str(&format!(
                 "<a class='page-link' href='/search?q={}&page={}'>{}</a>",
-                html_escape::encode_text(q),
-                p,
-                p
+                q, p, p
             ));
         }
     }
     if page < total_pages {
         html.push_str(&format!(
             "<a class='page-link' href='/search?q={}&page={}'>بعدی</a>",
-            html_escape::encode_text(q),
+            q,
             page + 1
         ));
     }
@@ -127,6 +265,145 @@ fn render_pagination(q: &str, page: usize, total_pages: usize) -> String {
     html
 }
 
+/// "Did you mean" box linking to the corrected query, with the replaced
+/// words in bold.
+fn render_correction(correction: &Correction, analyzer: &dyn Analyzer) -> String {
+    let replacements: HashSet<String> = correction
+        .changes
+        .iter()
+        .flat_map(|(_, r)| analyzer.terms(r))
+        .collect();
+    let shown: Vec<String> = correction
+        .query
+        .split_whitespace()
+        .map(|word| {
+            let escaped = html_escape::encode_text(word).to_string();
+            let tokens = analyzer.terms(word);
+            if tokens.iter().any(|t| replacements.contains(t)) {
+                format!("<b>{}</b>", escaped)
+            } else {
+                escaped
+            }
+        })
+        .collect();
+
+    format!(
+        "<div class='suggestion-box'>🔍 شاید منظور شما این بود: <a href='/search?q={}'>{}</a> ({} نتیجه)</div>",
+        encode_query_param(&correction.query),
+        shown.join(" "),
+        correction.hits
+    )
+}
+
+/// Lists the synonyms and transliterations the query was expanded with.
+fn render_expansions(expansions: &[Expansion]) -> String {
+    if expansions.is_empty() {
+        return String::new();
+    }
+    let shown: Vec<String> = expansions
+        .iter()
+        .map(|e| {
+            format!(
+                "{} ← {}",
+                html_escape::encode_text(&e.to),
+                html_escape::encode_text(&e.from)
+            )
+        })
+        .collect();
+    format!(
+        "<div class='expansion-box'>📚 نتایج شامل این معادل‌ها هم هست: {}</div>",
+        shown.join("، ")
+    )
+}
+
+/// Percent-encodes a value for use in a URL query string.
+pub fn encode_query_param(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() * 3);
+    for b in value.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// Everything the HTML page and the JSON API need from one search.
+pub struct SearchOutcome {
+    pub results: Vec<(usize, f64)>,
+    pub highlight_terms: HashSet<String>,
+    pub correction: Option<Correction>,
+    pub expansions: Vec<Expansion>,
+    /// Every word of the query was a stopword, so nothing was searched.
+    pub only_stopwords: bool,
+}
+
+pub fn run_search(
+    index: &IndexStore,
+    options: &SearchOptions,
+    q: &str,
+    scorer: &dyn Scorer,
+) -> anyhow::Result<SearchOutcome> {
+    let searched = search(index, q, MAX_RESULTS, options, scorer)?;
+
+    // فقط کلمات مثبت پرس‌وجو (خارج از NOT) هایلایت می‌شوند، مترادف‌ها هم
+    let highlight_terms: HashSet<String> = searched
+        .query
+        .map(|query| query.positive_terms())
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+    let correction = did_you_mean(index, q, searched.hits.len(), options);
+
+    Ok(SearchOutcome {
+        results: searched.hits,
+        highlight_terms,
+        correction,
+        expansions: searched.expansions,
+        only_stopwords: searched.only_stopwords,
+    })
+}
+
+/// HTML-escaped excerpt of `body_text` around the first query term, with
+/// query terms wrapped in `<mark>`.
+pub fn highlighted_snippet(body_text: &str, highlight_terms: &HashSet<String>) -> String {
+    // lowercasing can change a character's length in bytes, so each byte of
+    // the lowercased text is mapped back to where its character starts
+    let mut body_lower = String::with_capacity(body_text.len());
+    let mut origin: Vec<usize> = Vec::with_capacity(body_text.len());
+    for (i, c) in body_text.char_indices() {
+        body_lower.extend(c.to_lowercase());
+        origin.resize(body_lower.len(), i);
+    }
+    let mut first_match_pos = 0;
+    for term in highlight_terms {
+        if let Some(pos) = body_lower.find(&term.to_lowercase()) {
+            first_match_pos = origin[pos];
+            break;
+        }
+    }
+
+    let snippet_raw: String = {
+        let start_char_idx = body_text[..first_match_pos]
+            .chars()
+            .count()
+            .saturating_sub(60);
+        body_text.chars().skip(start_char_idx).take(300).collect()
+    };
+
+    let mut highlighted_snippet = html_escape::encode_text(&snippet_raw).to_string();
+    for term in highlight_terms {
+        if term.chars().count() > 1 {
+            let escaped_term = html_escape::encode_text(term).to_string();
+            let highlight_tag = format!("<mark>{}</mark>", escaped_term);
+            highlighted_snippet = highlighted_snippet.replace(&escaped_term, &highlight_tag);
+        }
+    }
+    highlighted_snippet
+}
+
 async fn search_handler(
     data: web::Data<AppState>,
     web::Query(query): web::Query<Query>,
@@ -135,44 +412,46 @@ async fn search_handler(
     let page_size = 10;
     let page = query.page.unwrap_or(1).max(1);
     let start_time = Instant::now();
-
-    // مجموعه کلماتی که باید هایلایت شوند
-    let mut highlight_terms: HashSet<String> = crate::normalize::tokenize(q).into_iter().collect();
-
-    let mut all_results: Vec<(usize, f64)> = search(&data.index, q, 1000);
-    let mut suggestion_html = String::new();
-    let mut extra_results: Vec<(usize, f64)> = Vec::new();
-    let tokens = crate::normalize::tokenize(q);
-    let mut suggs_list = Vec::new();
-
-    for t in tokens.iter() {
-        let s = suggest_terms(&data.index, t, 2, 3);
-        if !s.is_empty() {
-            suggs_list.push(format!("<b>{}</b> &rarr; {}", t, s.join(", ")));
-            for sug in s.iter() {
-                highlight_terms.insert(sug.clone());
-                extra_results.extend(search(&data.index, sug, 30));
-            }
+    let index = data.index();
+    let (ranker, params) = match query.ranking.resolve(&data.options) {
+        Ok(r) => r,
+        Err(message) => {
+            return HttpResponse::BadRequest()
+                .content_type("text/plain; charset=utf-8")
+                .body(message)
         }
-    }
-
-    if !suggs_list.is_empty() {
-        suggestion_html = format!(
-            "<div class='suggestion-box'>🔍 شاید منظور شما این بود: {}</div>",
-            suggs_list.join(" | ")
-        );
-    }
+    };
 
-    let mut seen = HashSet::new();
-    all_results.retain(|(id, _)| seen.insert(*id));
-    for (id, score) in extra_results {
-        if seen.insert(id) {
-            all_results.push((id, score));
+    let outcome = match run_search(&index, &data.options, q, ranker.scorer(&params).as_ref()) {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            let mut response = if e.is::<PatternTooComplex>() {
+                HttpResponse::BadRequest()
+            } else {
+                HttpResponse::InternalServerError()
+            };
+            return response
+                .content_type("text/plain; charset=utf-8")
+                .body(e.to_string());
         }
+    };
+    let all_results = &outcome.results;
+    // فقط پرس‌وجوهایی که نتیجه داشته‌اند برای پیشنهاد ذخیره می‌شوند
+    if !all_results.is_empty() && page == 1 {
+        data.suggester.record(q);
     }
 
+    // پیشنهاد اصلاح فقط وقتی که پرس‌وجو واقعاً به آن نیاز دارد
+    let suggestion_html = match &outcome.correction {
+        Some(correction) => render_correction(correction, index.analyzer.as_ref()),
+        None if outcome.only_stopwords => "<div class='suggestion-box'>ℹ️ همه‌ی کلمات این پرس‌وجو \
+            ایست‌واژه هستند و در نمایه نیستند؛ یک کلمه‌ی مشخص‌تر اضافه کنید.</div>"
+            .to_string(),
+        None => String::new(),
+    };
+
     let total_results = all_results.len();
-    let total_pages = (total_results + page_size - 1) / page_size;
+    let total_pages = total_results.div_ceil(page_size);
     let start_idx = (page - 1) * page_size;
     let end_idx = (start_idx + page_size).min(total_results);
     let current_results = if start_idx < total_results {
@@ -203,47 +482,24 @@ async fn search_handler(
 font-size: 11px; border: 1px solid #d1fae5; } .snippet { color: #475569; font-size: 14px; overflow: hidden; text-overflow: 
 ellipsis; display: -webkit-box; -webkit-line-clamp: 3; -webkit-box-orient: vertical; } mark { background-color: #fef08a; color:
  #854d0e; padding: 0 2px; border-radius: 3px; font-weight: 600; } .suggestion-box { background: #eff6ff; color: #1e40af; padding: 
- 15px; border-radius: 10px; margin-bottom: 20px; font-size: 14px; border: 1px solid #bfdbfe; }</style></head><body><header><a href="/"
+ 15px; border-radius: 10px; margin-bottom: 20px; font-size: 14px; border: 1px solid #bfdbfe; } .suggestion-box a { color: #1d4ed8; text-decoration: underline; } .expansion-box { color: var(--text-muted); font-size: 13px; margin-bottom: 15px; padding-right: 5px; }</style></head><body><header><a href="/"
   class="logo">Search Engine</a><form action="/search" method="get" class="search-form"><input type="text" name="q" value="{QUERY}"
    id="nav-input" /><button type="submit">search</button></form></header><main>"#);
 
     let final_body = body.replace("{QUERY}", &html_escape::encode_text(q));
     let mut results_html = String::new();
     results_html.push_str(&format!(
-        "<div class='stats'>حدود {} نتیجه پیدا شد ({:.4} ثانیه)</div>{}",
-        total_results, elapsed, suggestion_html
+        "<div class='stats'>حدود {} نتیجه پیدا شد ({:.4} ثانیه)</div>{}{}",
+        total_results,
+        elapsed,
+        suggestion_html,
+        render_expansions(&outcome.expansions)
     ));
     results_html.push_str("<ol>");
 
     for (doc_id, score) in current_results {
-        let meta = &data.index.docs[*doc_id];
-        let body_text = &meta.body;
-
-        let mut first_match_pos = 0;
-        let body_lower = body_text.to_lowercase();
-        for term in &highlight_terms {
-            if let Some(pos) = body_lower.find(&term.to_lowercase()) {
-                first_match_pos = pos;
-                break;
-            }
-        }
-
-        let snippet_raw: String = {
-            let start_char_idx = body_text[..first_match_pos]
-                .chars()
-                .count()
-                .saturating_sub(60);
-            body_text.chars().skip(start_char_idx).take(300).collect()
-        };
-
-        let mut highlighted_snippet = html_escape::encode_text(&snippet_raw).to_string();
-        for term in &highlight_terms {
-            if term.chars().count() > 1 {
-                let escaped_term = html_escape::encode_text(term).to_string();
-                let highlight_tag = format!("<mark>{}</mark>", escaped_term);
-                highlighted_snippet = highlighted_snippet.replace(&escaped_term, &highlight_tag);
-            }
-        }
+        let meta = &index.doc(*doc_id);
+        let highlighted_snippet = highlighted_snippet(&meta.body, &outcome.highlight_terms);
 
         results_html.push_str(&format!(
             r#"<li>
@@ -259,10 +515,51 @@ ellipsis; display: -webkit-box; -webkit-line-clamp: 3; -webkit-box-orient: verti
     }
 
     results_html.push_str("</ol>");
-    results_html.push_str(&render_pagination(q, page, total_pages));
+    results_html.push_str(&render_pagination(q, &query.ranking, page, total_pages));
     results_html.push_str("</main></body></html>");
 
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
         .body([final_body, results_html].concat())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::tests::state;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+
+    #[test]
+    fn snippet_survives_case_folding_that_changes_lengths() {
+        // "İ" is 2 bytes but lowercases to 3, pushing later offsets past
+        // character boundaries of the original
+        let body = format!("{}کتاب خوب", "İ".repeat(101));
+        let terms: HashSet<String> = ["کتاب".to_string()].into_iter().collect();
+        let snippet = highlighted_snippet(&body, &terms);
+        assert!(snippet.contains("<mark>کتاب</mark>"));
+        assert!(snippet.starts_with(&"İ".repeat(60)));
+    }
+
+    #[actix_web::test]
+    async fn page_links_keep_the_ranking_parameters() {
+        let (dir, state) = state("results-pages");
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .route("/search", web::get().to(search_handler)),
+        )
+        .await;
+        let get = |uri: &str| TestRequest::get().uri(uri).to_request();
+
+        let response = call_service(&app, get("/search?q=rust&ranker=tfidf&k1=2&b=0.5")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = read_body(response).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains("href='/search?q=rust&ranker=tfidf&k1=2&b=0.5&page=2'"));
+
+        let response = call_service(&app, get("/search?q=rust&k1=-1")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,266 @@
+use crate::indexer::IndexStore;
+use crate::query;
+use crate::search::{count_matches, damerau_levenshtein, suggest_terms, SearchOptions};
+use std::collections::HashMap;
+
+/// Below this many results a query is worth trying to correct.
+const MIN_RESULTS: usize = 3;
+/// A term this rare is suspicious if a close term is far more common.
+const RARE_DF: usize = 2;
+/// How much more common the alternative has to be for a rare term.
+const RARE_RATIO: usize = 20;
+const CANDIDATES_PER_TERM: usize = 3;
+/// Upper bound on the number of whole-query rewrites that get evaluated.
+const MAX_COMBINATIONS: usize = 64;
+
+/// A rewritten query offered to the user as "did you mean".
+pub struct Correction {
+    /// The corrected query in the user's own syntax, ready to be searched.
+    pub query: String,
+    /// (word as typed, replacement as written in the documents) for every
+    /// word that changed.
+    pub changes: Vec<(String, String)>,
+    /// Number of documents the corrected query matches.
+    pub hits: usize,
+}
+
+fn max_edits(term: &str) -> usize {
+    match term.chars().count() {
+        0..=3 => 1,
+        _ => 2,
+    }
+}
+
+/// Decides whether `raw`, which matched `result_count` documents when
+/// searched with `options`, needs correcting and, if so, returns the best
+/// rewrite of the whole query. Rewrites are counted with the same options,
+/// so synonyms and transliterations help both sides alike.
+///
+/// A correction is only considered when the query returned fewer than
+/// `MIN_RESULTS` documents or one of its terms is unknown or suspiciously
+/// rare; that is checked with dictionary lookups alone before any candidate
+/// is searched for. Rewrites are scored jointly: candidates for all terms
+/// are combined and each combination is run against the index, so the
+/// terms that actually co-occur win over independently "best" per-term
+/// corrections.
+pub fn did_you_mean(
+    index: &IndexStore,
+    raw: &str,
+    result_count: usize,
+    options: &SearchOptions,
+) -> Option<Correction> {
+    let parsed = query::parse(raw, index.analyzer.as_ref())?;
+    let terms = parsed.positive_terms();
+    let dfs: Vec<usize> = terms.iter().map(|t| index.doc_freq(t)).collect();
+
+    // only rare terms are ever replaced, so with enough results and no rare
+    // term there is nothing to do
+    if result_count >= MIN_RESULTS && dfs.iter().all(|&df| df > RARE_DF) {
+        return None;
+    }
+
+    let mut choices: Vec<Vec<(String, usize, usize)>> = Vec::with_capacity(terms.len());
+    let mut suspicious = false;
+
+    for (term, &df) in terms.iter().zip(&dfs) {
+        if df > RARE_DF {
+            // well-established terms are left alone
+            choices.push(vec![(term.clone(), 0, df)]);
+            continue;
+        }
+        let alternatives: Vec<(String, usize, usize)> =
+            suggest_terms(index, term, max_edits(term), CANDIDATES_PER_TERM + 1)
+                .into_iter()
+                .filter(|c| c != term)
+                .take(CANDIDATES_PER_TERM)
+                .map(|c| {
+                    let dist = damerau_levenshtein(&c, term);
+                    let cdf = index.doc_freq(&c);
+                    (c, dist, cdf)
+                })
+                .collect();
+
+        let best_alt_df = alternatives.iter().map(|a| a.2).max().unwrap_or(0);
+        let rare = df == 0 || best_alt_df >= df * RARE_RATIO;
+        suspicious |= rare;
+
+        let mut opts = Vec::new();
+        if df > 0 {
+            opts.push((term.clone(), 0, df));
+        }
+        if rare || result_count < MIN_RESULTS {
+            opts.extend(alternatives);
+        }
+        if opts.is_empty() {
+            opts.push((term.clone(), 0, 0));
+        }
+        choices.push(opts);
+    }
+
+    if !suspicious && result_count >= MIN_RESULTS {
+        return None;
+    }
+
+    let mut best: Option<(f64, usize, HashMap<String, String>)> = None;
+    for combo in combinations(&choices) {
+        let subst: HashMap<String, String> = terms
+            .iter()
+            .zip(&combo)
+            .filter(|(t, c)| *t != &c.0)
+            .map(|(t, c)| (t.clone(), c.0.clone()))
+            .collect();
+        if subst.is_empty() {
+            continue;
+        }
+
+        let rewritten = parsed.map_terms(&|t| subst.get(t).cloned().unwrap_or_else(|| t.into()));
+        // the wildcards are the original query's, which already expanded
+        let hits = count_matches(index, &rewritten, options).unwrap_or(0);
+        if hits <= result_count {
+            continue;
+        }
+
+        let edits: usize = combo.iter().map(|c| c.1).sum();
+        let popularity: f64 = combo.iter().map(|c| (c.2 as f64 + 1.0).ln()).sum();
+        let score = 2.0 * (hits as f64 + 1.0).ln() - 3.0 * edits as f64 + popularity;
+
+        if best.as_ref().is_none_or(|b| score > b.0) {
+            best = Some((score, hits, subst));
+        }
+    }
+
+    let (_, hits, subst) = best?;
+    let (query, changes) = rewrite(raw, &subst, index);
+    Some(Correction {
+        query,
+        changes,
+        hits,
+    })
+}
+
+/// Cartesian product of the per-term choices, capped at `MAX_COMBINATIONS`.
+fn combinations<T: Clone>(choices: &[Vec<T>]) -> Vec<Vec<T>> {
+    let mut out: Vec<Vec<T>> = vec![Vec::new()];
+    for opts in choices {
+        let mut next = Vec::new();
+        'outer: for prefix in &out {
+            for o in opts {
+                if next.len() >= MAX_COMBINATIONS {
+                    break 'outer;
+                }
+                let mut v = prefix.clone();
+                v.push(o.clone());
+                next.push(v);
+            }
+        }
+        out = next;
+    }
+    out
+}
+
+/// Replaces corrected words in the raw query, keeping the user's spelling,
+/// operators, quotes and parentheses everywhere else. Replacements are
+/// written the way the documents write them rather than as index terms
+/// ("کتاب‌ها", not "کتاب"). Returns the query and the words changed.
+fn rewrite(
+    raw: &str,
+    subst: &HashMap<String, String>,
+    index: &IndexStore,
+) -> (String, Vec<(String, String)>) {
+    let mut changes: Vec<(String, String)> = Vec::new();
+    let words: Vec<String> = raw
+        .split_whitespace()
+        .map(|word| {
+            let (_, unfielded) = query::split_field(word.trim_start_matches('-'));
+            let core = unfielded.trim_matches(|c: char| matches!(c, '-' | '(' | ')' | '"'));
+            let mut tokens = index.analyzer.terms(core);
+            let replacement = match (tokens.pop(), tokens.is_empty()) {
+                (Some(token), true) => subst.get(&token),
+                _ => None,
+            };
+            let Some(replacement) = replacement else {
+                return word.to_string();
+            };
+            let shown = index
+                .surface_form(replacement)
+                .unwrap_or_else(|| replacement.clone());
+            let change = (core.to_string(), shown.clone());
+            if !changes.contains(&change) {
+                changes.push(change);
+            }
+            word.replacen(core, &shown, 1)
+        })
+        .collect();
+    (words.join(" "), changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::search;
+    use crate::search::tests::{index, options};
+    use crate::synonyms::Synonyms;
+
+    /// Searches `q` and asks for a correction, as the server does.
+    fn correct(index: &IndexStore, options: &SearchOptions, q: &str) -> Option<Correction> {
+        let scorer = options.ranker.scorer(&options.params);
+        let hits = search(index, q, 100, options, scorer.as_ref())
+            .unwrap()
+            .hits;
+        did_you_mean(index, q, hits.len(), options)
+    }
+
+    #[test]
+    fn misspelled_words_are_corrected() {
+        let (dir, store) = index(
+            "spell",
+            &[
+                ("a", &["search", "engine"]),
+                ("b", &["search", "engine"]),
+                ("c", &["search", "engine"]),
+                ("d", &["search"]),
+                ("e", &["boom", "rust"]),
+                ("f", &["rust"]),
+                ("g", &["rust"]),
+                ("h", &["book"]),
+                ("i", &["book"]),
+            ],
+        );
+        let options = options();
+        let correction = correct(&store, &options, "serch engine").unwrap();
+        assert_eq!(correction.query, "search engine");
+        assert_eq!(correction.changes, [("serch".into(), "search".into())]);
+        assert_eq!(correction.hits, 3);
+        assert!(correct(&store, &options, "search engine").is_none());
+        // "book rust" is closer to nothing than "boom rust" is
+        assert!(correct(&store, &options, "boom rust").is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rewrites_are_counted_with_synonyms_too() {
+        let (dir, store) = index(
+            "spell-synonyms",
+            &[
+                ("a", &["colr"]),
+                ("b", &["color"]),
+                ("c", &["hue"]),
+                ("d", &["hue"]),
+                ("e", &["hue"]),
+            ],
+        );
+        // without synonyms "color" finds no more than the typo does
+        assert!(correct(&store, &options(), "colr").is_none());
+
+        let path = dir.join("synonyms.txt");
+        std::fs::write(&path, "color, hue\n").unwrap();
+        let options = SearchOptions {
+            synonyms: Synonyms::load(&path, store.analyzer.as_ref()).unwrap(),
+            ..options()
+        };
+        let correction = correct(&store, &options, "colr").unwrap();
+        assert_eq!(correction.query, "color");
+        assert_eq!(correction.hits, 4);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
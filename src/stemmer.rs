@@ -0,0 +1,252 @@
+//! Rule-based Persian stemming.
+//!
+//! Works on normalized tokens. Affixes written with a ZWNJ ("کتاب‌ها") are
+//! either separate tokens already or, with `ZwnjMode::Join`, attached like
+//! any other suffix or prefix, which is what the rules here handle.
+
+use crate::analysis::{CharFilter, PersianCharMap};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stemmer {
+    /// Only a trailing "ها" or "ان" is removed (the original behaviour).
+    Light,
+    /// Inflectional suffixes and verb prefixes, with an exception list.
+    Persian,
+}
+
+impl Stemmer {
+    pub fn stem(self, word: &str) -> String {
+        match self {
+            Stemmer::Light => light_stem(word),
+            Stemmer::Persian => persian_stem(word),
+        }
+    }
+}
+
+fn light_stem(word: &str) -> String {
+    let mut word = word.to_string();
+    if word.len() > 4 && (word.ends_with("ها") || word.ends_with("ان")) {
+        word.truncate(word.len() - word.chars().last().unwrap().len_utf8() * 2);
+    }
+    word
+}
+
+/// Shortest stem (in chars) a suffix or prefix may leave behind.
+const MIN_STEM: usize = 2;
+
+/// One suffix rule: strip `suffix`, append `replace`, as long as at least
+/// `min_stem` chars of the word remain.
+struct Rule {
+    suffix: &'static str,
+    replace: &'static str,
+    min_stem: usize,
+}
+
+const fn rule(suffix: &'static str, replace: &'static str, min_stem: usize) -> Rule {
+    Rule {
+        suffix,
+        replace,
+        min_stem,
+    }
+}
+
+/// Suffix groups in the order they attach to a word, outermost first:
+/// pronoun clitics and indefinite ی, then plural markers, then the
+/// comparative and superlative. At most one rule per group is applied.
+///
+/// A plural followed by ezafe or indefinite ی ("کتابهای", "کتابهایی") is
+/// taken off whole before the bare "ای" and "ی" rules, which would
+/// otherwise leave "کتابه" behind.
+const SUFFIX_GROUPS: &[&[Rule]] = &[
+    &[
+        rule("هایی", "", MIN_STEM),
+        rule("های", "", MIN_STEM),
+        rule("شان", "", 3),
+        rule("مان", "", 3),
+        rule("اش", "", 3),
+        rule("ای", "", 3),
+        rule("یی", "", 3),
+        rule("ی", "", 4),
+    ],
+    &[
+        rule("هایی", "", MIN_STEM),
+        rule("های", "", MIN_STEM),
+        rule("ها", "", MIN_STEM),
+        rule("گان", "ه", 3),
+        rule("یان", "", 3),
+        rule("ات", "", 4),
+        rule("ان", "", 3),
+    ],
+    &[rule("ترین", "", 3), rule("تر", "", 3)],
+];
+
+/// Person endings of a present or past verb stem.
+const VERB_ENDINGS: &[&str] = &["ند", "ید", "یم", "م", "ی", "د"];
+
+/// Continuous / negative verb prefixes, longest first.
+const VERB_PREFIXES: &[&str] = &["نمی", "می"];
+
+lazy_static::lazy_static! {
+    /// Words that look inflected but are not: proper nouns and common
+    /// words ending in "ان", "ات", "ی", "تر", ... or starting with "می".
+    /// Both tables are folded like the tokens they are matched against
+    /// ("قرآن" → "قران").
+    static ref PROTECTED: HashSet<String> = [
+        // places
+        "ایران", "تهران", "اصفهان", "کرمان", "گیلان", "خراسان", "زنجان", "همدان",
+        "آبادان", "سمنان", "لرستان", "کردستان", "خوزستان", "گلستان", "سیستان",
+        "بلوچستان", "مازندران", "هرمزگان", "یونان", "ژاپن", "لبنان", "عمان",
+        "آلمان", "رامسر", "کاشان", "گرگان", "دامغان", "مراغه", "اراک", "شمیران",
+        // common nouns ending in ان
+        "زبان", "جهان", "انسان", "باران", "دندان", "میدان", "خیابان", "زمان",
+        "ایمان", "قرآن", "نان", "جان", "خان", "آسمان", "مهمان", "میهمان", "درمان",
+        "فرمان", "سامان", "پایان", "بیابان", "داستان", "تابستان", "زمستان",
+        "بیمارستان", "دبستان", "بوستان", "استان", "ساختمان", "سازمان", "قهرمان",
+        "امکان", "مکان", "دوران", "بحران", "میزان", "کاروان", "روان", "جوان",
+        "ارزان", "گران", "آسان", "پنهان", "نشان", "دکان", "دهان", "بنیان",
+        "عنوان", "ایوان", "دیوان", "طوفان", "توفان", "جریان", "ویران", "ریحان",
+        "گریبان", "پیمان", "ارمغان", "همان", "چنان", "چنین", "آنان", "ایشان",
+        "خاندان", "نردبان", "شبان", "بان", "کمان", "رمان", "برهان", "سلطان",
+        "شیطان", "لیوان", "گمان", "فراوان", "ناگهان", "مسلمان", "آرمان", "سیمان",
+        "پشیمان", "زایمان", "تنها", "پاداش",
+        // ات / ی / تر that are part of the word
+        "حیات", "ساعات", "ملاقات", "اوقات", "صفات", "نجات", "زکات", "برات",
+        "کشتی", "بازی", "زندگی", "قاضی", "ماهی", "کرسی", "هستی", "مستی", "دوستی",
+        "شادی", "آزادی", "آبادی", "سبزی", "کرمانی", "فارسی", "عربی", "کوری",
+        "دختر", "دفتر", "کبوتر", "شتر", "چتر", "بستر", "اختر", "کمتر", "بیشتر",
+        "کتر", "انگشتر", "بهتر", "بدتر", "خاکستر", "دکتر", "اکثر", "کلاستر",
+        // words starting with می / نمی that are not verbs
+        "میز", "میوه", "میلیون", "میلیارد", "میراث", "میان", "میانه", "میهن",
+        "میل", "میخ", "میش", "میگو", "میمون", "میکروب", "میلاد", "مینا", "میرزا",
+        "میکرو", "میکروفون", "میخک", "نمین", "نمیر",
+    ]
+    .into_iter()
+    .map(|w| PersianCharMap.filter(w))
+    .collect();
+
+    /// Irregular forms mapped straight to their lemma.
+    static ref IRREGULAR: HashMap<String, String> = [
+        ("بهترین", "به"),
+        ("بیشترین", "بیش"),
+        ("کمترین", "کم"),
+        ("آقایان", "آقا"),
+        ("خانمها", "خانم"),
+        ("مطالعات", "مطالعه"),
+        ("تحقیقات", "تحقیق"),
+        ("کتب", "کتاب"),
+        ("علوم", "علم"),
+        ("اخبار", "خبر"),
+        ("افراد", "فرد"),
+        ("اشخاص", "شخص"),
+        ("آثار", "اثر"),
+        ("مسائل", "مسئله"),
+        ("مناطق", "منطقه"),
+        ("مدارس", "مدرسه"),
+    ]
+    .into_iter()
+    .map(|(form, lemma)| (PersianCharMap.filter(form), PersianCharMap.filter(lemma)))
+    .collect();
+}
+
+fn strip_suffix(word: &str, rule: &Rule) -> Option<String> {
+    let stem = word.strip_suffix(rule.suffix)?;
+    (stem.chars().count() >= rule.min_stem).then(|| format!("{}{}", stem, rule.replace))
+}
+
+fn persian_stem(word: &str) -> String {
+    if let Some(lemma) = IRREGULAR.get(word) {
+        return lemma.clone();
+    }
+    // only Persian script is stemmed; Latin words and numbers pass through
+    if !word.chars().any(|c| ('\u{0600}'..='\u{06FF}').contains(&c)) {
+        return word.to_string();
+    }
+
+    let mut stem = word.to_string();
+    for group in SUFFIX_GROUPS {
+        if PROTECTED.contains(&stem) {
+            // "میدان‌ها" is a plural noun, not a verb
+            return stem;
+        }
+        if let Some(shorter) = group.iter().find_map(|rule| strip_suffix(&stem, rule)) {
+            stem = shorter;
+        }
+    }
+    if PROTECTED.contains(&stem) {
+        return stem;
+    }
+
+    // می‌روند / نمی‌دانم written without the half-space; the clitics are
+    // already gone from "میخوانندشان"
+    if let Some(rest) = VERB_PREFIXES.iter().find_map(|p| stem.strip_prefix(p)) {
+        if rest.chars().count() >= MIN_STEM {
+            let verb_stem = VERB_ENDINGS
+                .iter()
+                .filter_map(|ending| rest.strip_suffix(ending))
+                .find(|s| s.chars().count() >= MIN_STEM);
+            return verb_stem.unwrap_or(rest).to_string();
+        }
+    }
+    stem
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stem(word: &str) -> String {
+        Stemmer::Persian.stem(word)
+    }
+
+    #[test]
+    fn plurals() {
+        assert_eq!(stem("کتابها"), "کتاب");
+        assert_eq!(stem("درختان"), "درخت");
+        assert_eq!(stem("پرندگان"), "پرنده");
+        assert_eq!(stem("دانشجویان"), "دانشجو");
+    }
+
+    #[test]
+    fn plural_with_ezafe_or_indefinite() {
+        assert_eq!(stem("کتابهای"), "کتاب");
+        assert_eq!(stem("کتابهایی"), "کتاب");
+        assert_eq!(stem("کتابهایشان"), "کتاب");
+    }
+
+    #[test]
+    fn comparative_and_superlative() {
+        assert_eq!(stem("بزرگتر"), "بزرگ");
+        assert_eq!(stem("بزرگترین"), "بزرگ");
+        assert_eq!(stem("بهترین"), "به");
+    }
+
+    #[test]
+    fn verb_prefixes() {
+        assert_eq!(stem("میروند"), "رو");
+        assert_eq!(stem("نمیدانم"), "دان");
+        assert_eq!(stem("میخوانیم"), "خوان");
+        // prefix stripped after the pronoun clitic
+        assert_eq!(stem("میخوانندشان"), "خوان");
+    }
+
+    #[test]
+    fn protected_words_are_kept() {
+        assert_eq!(stem("تهران"), "تهران");
+        assert_eq!(stem("میدانها"), "میدان");
+        assert_eq!(stem("میز"), "میز");
+        assert_eq!(stem("دختر"), "دختر");
+    }
+
+    #[test]
+    fn latin_passes_through() {
+        assert_eq!(stem("books"), "books");
+        assert_eq!(stem("2024"), "2024");
+    }
+
+    #[test]
+    fn light_stemmer() {
+        assert_eq!(Stemmer::Light.stem("کتابها"), "کتاب");
+        assert_eq!(Stemmer::Light.stem("ها"), "ها");
+    }
+}
@@ -0,0 +1,172 @@
+//! Built-in stopword lists and loading of custom ones.
+
+use anyhow::Context;
+
+/// Function words, auxiliaries and clitics split off by ZWNJ ("می", "ها").
+/// Words that are also content words are left out even where they are
+/// mostly function words: "کرد" (Kurd), "کند" (slow), "تر" (wet), "کی"
+/// (Kish), "شان" (standing), "روی" (zinc, face).
+pub const PERSIAN: &[&str] = &[
+    "و",
+    "در",
+    "به",
+    "از",
+    "که",
+    "این",
+    "را",
+    "با",
+    "است",
+    "برای",
+    "آن",
+    "یک",
+    "خود",
+    "تا",
+    "بر",
+    "هم",
+    "نیز",
+    "یا",
+    "اما",
+    "اگر",
+    "پس",
+    "چه",
+    "چون",
+    "هر",
+    "همه",
+    "هیچ",
+    "دیگر",
+    "ولی",
+    "زیرا",
+    "بی",
+    "پیش",
+    "بین",
+    "نزد",
+    "درباره",
+    "توسط",
+    "طی",
+    "مانند",
+    "مثل",
+    "همچنین",
+    "بنابراین",
+    "سپس",
+    "حتی",
+    "فقط",
+    "تنها",
+    "نه",
+    "آیا",
+    "چرا",
+    "کجا",
+    "چگونه",
+    "چنین",
+    "چنان",
+    "همین",
+    "همان",
+    "آنها",
+    "اینها",
+    "ایشان",
+    "آنان",
+    "من",
+    "تو",
+    "او",
+    "ما",
+    "شما",
+    "وی",
+    "ام",
+    "ای",
+    "اش",
+    "مان",
+    "تان",
+    "می",
+    "نمی",
+    "ها",
+    "های",
+    "هایی",
+    "هست",
+    "هستند",
+    "بود",
+    "بودند",
+    "باشد",
+    "باشند",
+    "شد",
+    "شده",
+    "شود",
+    "شوند",
+    "شدند",
+    "باید",
+    "نیست",
+    "نبود",
+    "اند",
+    "ایم",
+    "اید",
+    "بوده",
+    "خواهد",
+    "اینکه",
+    "آنکه",
+    "وقتی",
+    "چیزی",
+    "کسی",
+    "یکی",
+    "بسیار",
+    "خیلی",
+];
+
+pub const ENGLISH: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "been", "but", "by", "for", "from", "has", "have",
+    "he", "her", "his", "i", "if", "in", "into", "is", "it", "its", "no", "not", "of", "on", "or",
+    "she", "so", "such", "that", "the", "their", "then", "there", "these", "they", "this", "to",
+    "was", "we", "were", "which", "will", "with", "you",
+];
+
+/// Resolves a comma-separated stopword spec: built-in list names
+/// (`persian`, `english`), paths of files with one word per line (`#`
+/// starts a comment), or `none`.
+pub fn load(spec: &str) -> anyhow::Result<Vec<String>> {
+    let mut words = Vec::new();
+    for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        match part {
+            "none" => {}
+            "persian" => words.extend(PERSIAN.iter().map(|w| w.to_string())),
+            "english" => words.extend(ENGLISH.iter().map(|w| w.to_string())),
+            path => {
+                let text = std::fs::read_to_string(path)
+                    .with_context(|| format!("reading stopword list {:?}", path))?;
+                words.extend(
+                    text.lines()
+                        .map(|l| l.split('#').next().unwrap_or("").trim())
+                        .filter(|l| !l.is_empty())
+                        .map(str::to_string),
+                );
+            }
+        }
+    }
+    words.sort();
+    words.dedup();
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn persian_list_keeps_content_words_searchable() {
+        let words = load("persian").unwrap();
+        for content in ["کرد", "کند", "تر", "کی", "شان", "روی"] {
+            assert!(!words.iter().any(|w| w == content), "{}", content);
+        }
+        for function in ["و", "از", "را", "می", "ها"] {
+            assert!(words.iter().any(|w| w == function), "{}", function);
+        }
+    }
+
+    #[test]
+    fn lists_are_merged_with_files() {
+        let path = std::env::temp_dir().join(format!("stopwords-{}.txt", std::process::id()));
+        std::fs::write(&path, "# custom\nfoo\n  bar # trailing\n\nthe\n").unwrap();
+        let words = load(&format!("none, english,{}", path.display())).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(words.contains(&"foo".to_string()));
+        assert!(words.contains(&"bar".to_string()));
+        assert_eq!(words.iter().filter(|w| *w == "the").count(), 1);
+        assert!(load("none").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,424 @@
+//! Search-as-you-type completions.
+//!
+//! Two sources are merged: queries users ran before (only with a query
+//! log), ranked by how often they were searched, and dictionary terms
+//! completing the last word being typed, ranked by document frequency.
+//! Terms are shown in the form documents most often wrote them in. The
+//! best completions of short prefixes are computed once per index
+//! generation; longer prefixes are streamed from the segments' term
+//! dictionaries on every request.
+
+use crate::indexer::IndexStore;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Prefixes up to this many characters have their best completions
+/// precomputed; their term ranges are too large to scan per keystroke.
+const CACHED_PREFIX_CHARS: usize = 2;
+
+/// Completions kept per cached prefix, and the most a request may ask for.
+pub const MAX_SUGGESTIONS: usize = 20;
+
+/// Distinct queries kept in the query log. Past this, every count is
+/// halved and queries falling to zero are forgotten, so popular queries
+/// stay and one-offs age out.
+const MAX_LOGGED_QUERIES: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuggestionKind {
+    /// A query from the query log.
+    Query,
+    /// The typed text with its last word completed to a dictionary term.
+    Term,
+}
+
+#[derive(Debug, Clone)]
+pub struct Suggestion {
+    pub text: String,
+    pub kind: SuggestionKind,
+    /// Times searched for queries, document frequency for terms.
+    pub weight: u64,
+}
+
+/// Best completions of every prefix of up to `CACHED_PREFIX_CHARS`
+/// characters, for one index generation.
+struct PrefixCompletions {
+    generation: u64,
+    /// Prefix → (term, document frequency) of its best completions, best
+    /// first.
+    top: HashMap<String, Vec<(String, usize)>>,
+}
+
+impl PrefixCompletions {
+    fn build(index: &IndexStore) -> Self {
+        let mut top: HashMap<String, Vec<(String, usize)>> = HashMap::new();
+        index.for_each_term(|term, df| {
+            for (end, c) in term.char_indices().take(CACHED_PREFIX_CHARS) {
+                let prefix = &term[..end + c.len_utf8()];
+                let Some(best) = top.get_mut(prefix) else {
+                    top.insert(prefix.to_string(), vec![(term.to_string(), df)]);
+                    continue;
+                };
+                // terms arrive sorted, so ties stay in term order
+                let at = best.partition_point(|&(_, d)| d >= df);
+                if at < MAX_SUGGESTIONS {
+                    best.insert(at, (term.to_string(), df));
+                    best.truncate(MAX_SUGGESTIONS);
+                }
+            }
+        });
+        PrefixCompletions {
+            generation: index.generation,
+            top,
+        }
+    }
+}
+
+/// Queries that found something, counted, and appended to a file so the
+/// counts survive restarts.
+///
+/// The file has one query per line, optionally followed by a tab and a
+/// count. It is rewritten with counts whenever it has grown to twice the
+/// number of queries it holds, so it stays bounded like the counts.
+struct QueryLog {
+    counts: BTreeMap<String, u64>,
+    path: PathBuf,
+    file: File,
+    /// Lines in `file`.
+    lines: usize,
+}
+
+impl QueryLog {
+    fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut counts = BTreeMap::new();
+        let mut lines = 0;
+        if path.exists() {
+            for line in std::fs::read_to_string(path)?.lines() {
+                let (q, count) = match line.rsplit_once('\t') {
+                    Some((q, n)) => (q, n.trim().parse().unwrap_or(1)),
+                    None => (line, 1),
+                };
+                let q = normalize_query(q);
+                if !q.is_empty() {
+                    *counts.entry(q).or_default() += count;
+                }
+                lines += 1;
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut log = QueryLog {
+            counts,
+            path: path.to_path_buf(),
+            file,
+            lines,
+        };
+        log.decay();
+        if log.lines > log.counts.len() {
+            log.compact()?;
+        }
+        Ok(log)
+    }
+
+    fn record(&mut self, q: String) -> anyhow::Result<()> {
+        writeln!(self.file, "{}", q)?;
+        self.lines += 1;
+        *self.counts.entry(q).or_default() += 1;
+        self.decay();
+        if self.lines > 2 * self.counts.len().max(MAX_LOGGED_QUERIES / 2) {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn decay(&mut self) {
+        while self.counts.len() > MAX_LOGGED_QUERIES {
+            self.counts.retain(|_, count| {
+                *count /= 2;
+                *count > 0
+            });
+        }
+    }
+
+    /// Rewrites the file as one `query<TAB>count` line per query.
+    fn compact(&mut self) -> anyhow::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        {
+            let mut w = BufWriter::new(File::create(&tmp)?);
+            for (q, count) in &self.counts {
+                writeln!(w, "{}\t{}", q, count)?;
+            }
+            w.flush()?;
+        }
+        std::fs::rename(&tmp, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.lines = self.counts.len();
+        Ok(())
+    }
+}
+
+pub struct Suggester {
+    prefixes: RwLock<Option<Arc<PrefixCompletions>>>,
+    queries: Option<Mutex<QueryLog>>,
+}
+
+impl Suggester {
+    /// Given a query log path, loads the queries logged there and keeps
+    /// appending to it.
+    pub fn new(query_log: Option<&Path>) -> anyhow::Result<Self> {
+        let queries = match query_log {
+            Some(path) => Some(Mutex::new(QueryLog::open(path)?)),
+            None => None,
+        };
+        Ok(Suggester {
+            prefixes: RwLock::new(None),
+            queries,
+        })
+    }
+
+    /// Number of distinct logged queries (0 without a query log).
+    pub fn logged_queries(&self) -> usize {
+        self.queries
+            .as_ref()
+            .map_or(0, |log| log.lock().unwrap().counts.len())
+    }
+
+    /// Counts a query that returned results. A no-op without a query log.
+    pub fn record(&self, q: &str) {
+        let Some(log) = &self.queries else {
+            return;
+        };
+        let q = normalize_query(q);
+        if q.is_empty() {
+            return;
+        }
+        if let Err(e) = log.lock().unwrap().record(q) {
+            eprintln!("⚠️ Writing query log failed: {}", e);
+        }
+    }
+
+    /// Up to `limit` completions of `prefix`: logged queries first, then
+    /// the typed text with its last word completed.
+    pub fn suggest(&self, index: &IndexStore, prefix: &str, limit: usize) -> Vec<Suggestion> {
+        let typed = normalize_query(prefix);
+        let mut out: Vec<Suggestion> = Vec::new();
+        if typed.is_empty() {
+            return out;
+        }
+
+        if let Some(log) = &self.queries {
+            let log = log.lock().unwrap();
+            let mut matches: Vec<(&String, &u64)> = log
+                .counts
+                .range(typed.clone()..)
+                .take_while(|(q, _)| q.starts_with(&typed))
+                .collect();
+            matches.sort_by_key(|(_, count)| Reverse(**count));
+            out.extend(
+                matches
+                    .into_iter()
+                    .take(limit)
+                    .map(|(q, &count)| Suggestion {
+                        text: q.clone(),
+                        kind: SuggestionKind::Query,
+                        weight: count,
+                    }),
+            );
+        }
+
+        // a trailing space means the last word is finished
+        if prefix.ends_with(char::is_whitespace) {
+            return out;
+        }
+        let (head, last) = match typed.rsplit_once(' ') {
+            Some((head, last)) => (format!("{} ", head), last),
+            None => (String::new(), typed.as_str()),
+        };
+        let last = index.analyzer.normalize(last).to_lowercase();
+        if last.is_empty() {
+            return out;
+        }
+
+        for (term, df) in self.top_terms(index, &last, limit) {
+            if out.len() >= limit {
+                break;
+            }
+            let word = index.surface_form(&term).unwrap_or(term);
+            let text = format!("{}{}", head, word);
+            if !out.iter().any(|s| s.text == text) {
+                out.push(Suggestion {
+                    text,
+                    kind: SuggestionKind::Term,
+                    weight: df as u64,
+                });
+            }
+        }
+        out.truncate(limit);
+        out
+    }
+
+    /// The `limit` terms starting with `prefix` with the highest document
+    /// frequency, best first.
+    fn top_terms(&self, index: &IndexStore, prefix: &str, limit: usize) -> Vec<(String, usize)> {
+        if prefix.chars().count() > CACHED_PREFIX_CHARS {
+            return scan_top_terms(index, prefix, limit);
+        }
+        let prefixes = self.prefix_completions(index);
+        let best = prefixes.top.get(prefix).map_or(&[][..], Vec::as_slice);
+        best.iter().take(limit).cloned().collect()
+    }
+
+    /// Short-prefix completions of `index`, rebuilt when a newer generation
+    /// of the index has been loaded since they were built.
+    fn prefix_completions(&self, index: &IndexStore) -> Arc<PrefixCompletions> {
+        if let Some(current) = self.prefixes.read().unwrap().as_ref() {
+            if current.generation == index.generation {
+                return current.clone();
+            }
+        }
+        let fresh = Arc::new(PrefixCompletions::build(index));
+        *self.prefixes.write().unwrap() = Some(fresh.clone());
+        fresh
+    }
+}
+
+/// The `limit` terms starting with `prefix` with the highest document
+/// frequency, best first, found by walking the term dictionaries.
+fn scan_top_terms(index: &IndexStore, prefix: &str, limit: usize) -> Vec<(String, usize)> {
+    // a min-heap of the best so far, so each candidate costs O(log limit)
+    let mut best: BinaryHeap<Reverse<(usize, Reverse<String>)>> = BinaryHeap::new();
+    index.terms_with_prefix(prefix, |term, df| {
+        if best.len() < limit {
+            best.push(Reverse((df, Reverse(term.to_string()))));
+        } else if best.peek().is_some_and(|Reverse((min, _))| df > *min) {
+            best.pop();
+            best.push(Reverse((df, Reverse(term.to_string()))));
+        }
+    });
+    best.into_sorted_vec()
+        .into_iter()
+        .map(|Reverse((df, Reverse(term)))| (term, df))
+        .collect()
+}
+
+/// Trims and collapses whitespace so the same query typed slightly
+/// differently is counted once.
+fn normalize_query(q: &str) -> String {
+    q.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::AnalysisConfig;
+    use crate::segment::tests::{build, doc, temp_dir};
+    use crate::segment::IndexWriter;
+
+    fn index(name: &str) -> (PathBuf, IndexStore) {
+        let dir = temp_dir(name);
+        let (meta, terms, mut surfaces) = doc("d", &["برنامهنویس"]);
+        surfaces.insert("برنامهنویس".to_string(), "برنامه‌نویس".to_string());
+        let docs = vec![
+            doc("a", &["book", "books", "boom"]),
+            doc("b", &["book", "books"]),
+            doc("c", &["book", "cook"]),
+            (meta, terms, surfaces),
+        ];
+        let mut writer = IndexWriter::create(&dir, AnalysisConfig::default(), false).unwrap();
+        writer.add_segment(build(docs)).unwrap();
+        writer.commit().unwrap();
+        let store = IndexStore::open(&dir).unwrap();
+        (dir, store)
+    }
+
+    fn texts(suggestions: Vec<Suggestion>) -> Vec<String> {
+        suggestions.into_iter().map(|s| s.text).collect()
+    }
+
+    #[test]
+    fn terms_are_completed_by_document_frequency() {
+        let (dir, store) = index("complete");
+        let suggester = Suggester::new(None).unwrap();
+        // two characters come from the cache, three from the dictionaries
+        let cached = suggester.suggest(&store, "bo", 10);
+        assert_eq!(texts(cached.clone()), ["book", "books", "boom"]);
+        assert_eq!(
+            cached.iter().map(|s| s.weight).collect::<Vec<_>>(),
+            [3, 2, 1]
+        );
+        assert!(cached.iter().all(|s| s.kind == SuggestionKind::Term));
+        assert_eq!(
+            texts(suggester.suggest(&store, "boo", 2)),
+            ["book", "books"]
+        );
+        assert_eq!(
+            suggester.top_terms(&store, "b", MAX_SUGGESTIONS),
+            scan_top_terms(&store, "b", MAX_SUGGESTIONS)
+        );
+        // only the last word is completed, and only while it is typed
+        assert_eq!(
+            texts(suggester.suggest(&store, "old  boo", 1)),
+            ["old book"]
+        );
+        assert!(suggester.suggest(&store, "boo ", 10).is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn terms_are_shown_as_documents_write_them() {
+        let (dir, store) = index("surface");
+        let suggester = Suggester::new(None).unwrap();
+        assert_eq!(texts(suggester.suggest(&store, "بر", 10)), ["برنامه‌نویس"]);
+        assert_eq!(
+            texts(suggester.suggest(&store, "برنامه", 10)),
+            ["برنامه‌نویس"]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn temp_log(name: &str, contents: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("suggest-{}-{}.log", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn log_is_counted_and_compacted_on_open() {
+        let path = temp_log("open", "کتاب\n  رمان   فارسی \nکتاب\nرمان فارسی\t3\n");
+        let log = QueryLog::open(&path).unwrap();
+        assert_eq!(log.counts["کتاب"], 2);
+        assert_eq!(log.counts["رمان فارسی"], 4);
+        let compacted = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(compacted, "رمان فارسی\t4\nکتاب\t2\n");
+
+        drop(log);
+        let reopened = QueryLog::open(&path).unwrap();
+        assert_eq!(reopened.counts["رمان فارسی"], 4);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn log_forgets_rare_queries_past_the_cap() {
+        let path = temp_log("cap", "");
+        let mut log = QueryLog::open(&path).unwrap();
+        for _ in 0..4 {
+            log.record("popular".to_string()).unwrap();
+        }
+        for i in 0..MAX_LOGGED_QUERIES {
+            log.record(format!("q{}", i)).unwrap();
+        }
+        assert!(log.counts.len() <= MAX_LOGGED_QUERIES);
+        assert_eq!(log.counts["popular"], 2);
+        assert!(log.lines <= 2 * MAX_LOGGED_QUERIES);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn queries_are_normalized() {
+        assert_eq!(normalize_query("  a \t b  "), "a b");
+    }
+}
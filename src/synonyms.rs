@@ -0,0 +1,368 @@
+//! Query-time synonym expansion.
+//!
+//! A synonym file has one rule per line; `#` starts a comment:
+//!
+//! ```text
+//! خودرو, ماشین, اتومبیل, car     # equivalent: each one finds the others
+//! تلفن همراه, گوشی => موبایل      # directional: only the left side expands
+//! ```
+//!
+//! Entries may be several words long. Both sides go through the index's
+//! analyzer, so a rule matches however the query words end up indexed.
+//! Synonyms are added next to the original words as alternatives that
+//! score `EXPANSION_WEIGHT` times as much as the original would.
+
+use crate::analysis::Analyzer;
+use crate::query::Query;
+use anyhow::Context;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Score multiplier for terms added by expansion (synonyms and
+/// transliterations).
+pub const EXPANSION_WEIGHT: f64 = 0.5;
+
+/// One synonym applied to a query, as written in the synonym file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expansion {
+    pub from: String,
+    pub to: String,
+}
+
+/// A word or phrase from the synonym file with its analyzed terms and their
+/// offsets from the first one.
+#[derive(Debug, Clone)]
+struct Entry {
+    text: String,
+    terms: Vec<(String, usize)>,
+}
+
+impl Entry {
+    fn key(&self) -> Vec<String> {
+        self.terms.iter().map(|(t, _)| t.clone()).collect()
+    }
+
+    fn query(&self) -> Query {
+        match self.terms.as_slice() {
+            [(t, _)] => Query::Term(t.clone()),
+            _ => Query::Phrase(self.terms.clone()),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Synonyms {
+    /// Analyzed terms of a left-hand side → its text and what it expands to.
+    rules: HashMap<Vec<String>, (String, Vec<Entry>)>,
+    /// Number of terms in the longest left-hand side.
+    max_len: usize,
+}
+
+impl Synonyms {
+    /// Reads a synonym file, analyzing every entry with `analyzer` (the one
+    /// the index was built with).
+    pub fn load(path: &Path, analyzer: &dyn Analyzer) -> anyhow::Result<Synonyms> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("reading synonym file {:?}", path))?;
+        let mut synonyms = Synonyms::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            synonyms
+                .add_rule(line, analyzer)
+                .with_context(|| format!("{}:{}", path.display(), n + 1))?;
+        }
+        Ok(synonyms)
+    }
+
+    /// Number of words and phrases that have synonyms.
+    pub fn rule_count(&self) -> usize {
+        self.rules.len()
+    }
+
+    fn add_rule(&mut self, line: &str, analyzer: &dyn Analyzer) -> anyhow::Result<()> {
+        let entries = |side: &str| -> anyhow::Result<Vec<Entry>> {
+            side.split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(|text| {
+                    let tokens = analyzer.analyze(text);
+                    let first = tokens
+                        .first()
+                        .with_context(|| format!("{:?} has no searchable words", text))?
+                        .position;
+                    Ok(Entry {
+                        text: text.to_string(),
+                        terms: tokens
+                            .into_iter()
+                            .map(|t| (t.text, t.position - first))
+                            .collect(),
+                    })
+                })
+                .collect()
+        };
+
+        let (from, to) = match line.split_once("=>") {
+            Some((lhs, rhs)) => (entries(lhs)?, entries(rhs)?),
+            None => {
+                let all = entries(line)?;
+                if all.len() < 2 {
+                    anyhow::bail!("an equivalence needs at least two entries: {:?}", line);
+                }
+                (all.clone(), all)
+            }
+        };
+        if from.is_empty() || to.is_empty() {
+            anyhow::bail!("both sides of `=>` need an entry: {:?}", line);
+        }
+
+        for entry in &from {
+            let key = entry.key();
+            self.max_len = self.max_len.max(key.len());
+            let (_, alternatives) = self
+                .rules
+                .entry(key.clone())
+                .or_insert_with(|| (entry.text.clone(), Vec::new()));
+            for alt in &to {
+                let alt_key = alt.key();
+                if alt_key != key && !alternatives.iter().any(|a| a.key() == alt_key) {
+                    alternatives.push(alt.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the synonyms of words and phrases in `query` as lower-weighted
+    /// alternatives and lists what was added. Words under a NOT are left
+    /// alone.
+    pub fn expand(&self, query: &Query) -> (Query, Vec<Expansion>) {
+        let mut applied = Vec::new();
+        if self.rules.is_empty() {
+            return (query.clone(), applied);
+        }
+        let expanded = self.expand_node(query, &mut applied);
+        (expanded, applied)
+    }
+
+    fn expand_node(&self, q: &Query, applied: &mut Vec<Expansion>) -> Query {
+        match q {
+            Query::Term(t) => self.with_alternatives(std::slice::from_ref(t), q.clone(), applied),
+            Query::Phrase(terms) => {
+                let key: Vec<String> = terms.iter().map(|(t, _)| t.clone()).collect();
+                self.with_alternatives(&key, q.clone(), applied)
+            }
+            Query::And(children) => {
+                // "تلفن همراه" is parsed as two ANDed terms, so multi-word
+                // rules are matched against runs of adjacent terms
+                let mut out = Vec::with_capacity(children.len());
+                let mut i = 0;
+                while i < children.len() {
+                    match self.longest_rule_at(&children[i..]) {
+                        Some(key) => {
+                            let run = Query::And(children[i..i + key.len()].to_vec());
+                            out.push(self.with_alternatives(&key, run, applied));
+                            i += key.len();
+                        }
+                        None => {
+                            out.push(self.expand_node(&children[i], applied));
+                            i += 1;
+                        }
+                    }
+                }
+                Query::And(out)
+            }
+            Query::Or(children) => Query::Or(
+                children
+                    .iter()
+                    .map(|c| self.expand_node(c, applied))
+                    .collect(),
+            ),
+            Query::Not(_) | Query::Wildcard(_) => q.clone(),
+            Query::Field(field, inner) => {
+                Query::Field(*field, Box::new(self.expand_node(inner, applied)))
+            }
+            Query::Boost(weight, inner) => {
+                Query::Boost(*weight, Box::new(self.expand_node(inner, applied)))
+            }
+            Query::ConstantScore(score, inner) => {
+                Query::ConstantScore(*score, Box::new(self.expand_node(inner, applied)))
+            }
+        }
+    }
+
+    /// Longest multi-word left-hand side made of the plain terms at the
+    /// start of `clauses`.
+    fn longest_rule_at(&self, clauses: &[Query]) -> Option<Vec<String>> {
+        let terms: Vec<String> = clauses
+            .iter()
+            .take(self.max_len)
+            .map_while(|c| match c {
+                Query::Term(t) => Some(t.clone()),
+                _ => None,
+            })
+            .collect();
+        (2..=terms.len())
+            .rev()
+            .map(|n| terms[..n].to_vec())
+            .find(|key| self.rules.contains_key(key))
+    }
+
+    fn with_alternatives(
+        &self,
+        key: &[String],
+        original: Query,
+        applied: &mut Vec<Expansion>,
+    ) -> Query {
+        let Some((from, alternatives)) = self.rules.get(key) else {
+            return original;
+        };
+        let mut clauses = vec![original];
+        for alt in alternatives {
+            let expansion = Expansion {
+                from: from.clone(),
+                to: alt.text.clone(),
+            };
+            if !applied.contains(&expansion) {
+                applied.push(expansion);
+            }
+            clauses.push(Query::Boost(EXPANSION_WEIGHT, Box::new(alt.query())));
+        }
+        Query::Or(clauses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::AnalysisConfig;
+    use crate::query;
+
+    fn analyzer() -> Box<dyn Analyzer> {
+        AnalysisConfig {
+            analyzer: "standard".to_string(),
+            ..AnalysisConfig::default()
+        }
+        .build()
+        .unwrap()
+    }
+
+    fn synonyms(lines: &[&str]) -> Synonyms {
+        let analyzer = analyzer();
+        let mut synonyms = Synonyms::default();
+        for line in lines {
+            synonyms.add_rule(line, analyzer.as_ref()).unwrap();
+        }
+        synonyms
+    }
+
+    fn expand(synonyms: &Synonyms, input: &str) -> (Query, Vec<Expansion>) {
+        synonyms.expand(&query::parse(input, analyzer().as_ref()).unwrap())
+    }
+
+    fn term(t: &str) -> Query {
+        Query::Term(t.to_string())
+    }
+
+    fn alt(q: Query) -> Query {
+        Query::Boost(EXPANSION_WEIGHT, Box::new(q))
+    }
+
+    #[test]
+    fn equivalent_entries_find_each_other() {
+        let synonyms = synonyms(&["Car, auto, automobile"]);
+        assert_eq!(synonyms.rule_count(), 3);
+        let (expanded, applied) = expand(&synonyms, "auto");
+        assert_eq!(
+            expanded,
+            Query::Or(vec![
+                term("auto"),
+                alt(term("car")),
+                alt(term("automobile"))
+            ])
+        );
+        assert_eq!(
+            applied,
+            [
+                Expansion {
+                    from: "auto".to_string(),
+                    to: "Car".to_string()
+                },
+                Expansion {
+                    from: "auto".to_string(),
+                    to: "automobile".to_string()
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn directional_rules_only_expand_the_left_side() {
+        let synonyms = synonyms(&["cell phone, mobile => smartphone"]);
+        let (expanded, _) = expand(&synonyms, "mobile");
+        assert_eq!(
+            expanded,
+            Query::Or(vec![term("mobile"), alt(term("smartphone"))])
+        );
+        let (expanded, applied) = expand(&synonyms, "smartphone");
+        assert_eq!(expanded, term("smartphone"));
+        assert!(applied.is_empty());
+    }
+
+    #[test]
+    fn multi_word_entries_match_runs_and_phrases() {
+        let synonyms = synonyms(&["cell phone => smartphone", "new york, nyc"]);
+        let (expanded, _) = expand(&synonyms, "cheap cell phone");
+        assert_eq!(
+            expanded,
+            Query::And(vec![
+                term("cheap"),
+                Query::Or(vec![
+                    Query::And(vec![term("cell"), term("phone")]),
+                    alt(term("smartphone"))
+                ])
+            ])
+        );
+
+        let (expanded, _) = expand(&synonyms, "\"new york\"");
+        let phrase = Query::Phrase(vec![("new".to_string(), 0), ("york".to_string(), 1)]);
+        assert_eq!(expanded, Query::Or(vec![phrase.clone(), alt(term("nyc"))]));
+        let (expanded, _) = expand(&synonyms, "nyc");
+        assert_eq!(expanded, Query::Or(vec![term("nyc"), alt(phrase)]));
+    }
+
+    #[test]
+    fn negated_words_are_not_expanded() {
+        let synonyms = synonyms(&["car, auto"]);
+        let (expanded, applied) = expand(&synonyms, "rental -car");
+        assert_eq!(
+            expanded,
+            Query::And(vec![term("rental"), Query::Not(Box::new(term("car")))])
+        );
+        assert!(applied.is_empty());
+    }
+
+    #[test]
+    fn malformed_rules_are_reported_with_their_line() {
+        let analyzer = analyzer();
+        let mut synonyms = Synonyms::default();
+        assert!(synonyms.add_rule("lonely", analyzer.as_ref()).is_err());
+        assert!(synonyms.add_rule("a => ", analyzer.as_ref()).is_err());
+        assert!(synonyms.add_rule("!!, word", analyzer.as_ref()).is_err());
+
+        let path = std::env::temp_dir().join(format!("synonyms-{}.txt", std::process::id()));
+        std::fs::write(&path, "# cars\ncar, auto # same thing\n\nlonely\n").unwrap();
+        let err = Synonyms::load(&path, analyzer.as_ref()).err().unwrap();
+        assert!(format!("{:#}", err).contains(":4"), "{:#}", err);
+        std::fs::write(&path, "car, auto # same thing\n").unwrap();
+        assert_eq!(
+            Synonyms::load(&path, analyzer.as_ref())
+                .unwrap()
+                .rule_count(),
+            2
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
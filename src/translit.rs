@@ -0,0 +1,312 @@
+//! Finglish ↔ Persian transliteration.
+//!
+//! Persian typed in Latin letters ("ketab", "tehran") has no single
+//! spelling in either script: short vowels are not written in Persian, and
+//! several Persian letters share one sound (س ص ث are all "s"). Instead of
+//! guessing one spelling, a word is turned into a regular expression over
+//! the plausible spellings in the other script and run against the term
+//! dictionary, so only spellings that occur in the index are used.
+//!
+//! Only words that are missing or rare in the index are transliterated:
+//! an English word that occurs in the documents ("car") is meant as
+//! English, not as a Persian word that happens to sound alike ("کار").
+
+use crate::indexer::IndexStore;
+use crate::query::Query;
+use crate::synonyms::{Expansion, EXPANSION_WEIGHT};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Spellings in the other script kept per query word, most frequent first.
+const MAX_CANDIDATES: usize = 3;
+
+/// Shorter words match too many unrelated terms to be worth transliterating.
+const MIN_CHARS: usize = 3;
+
+/// Words in at least this many documents are searched as typed only.
+const RARE_DOC_FREQ: usize = 3;
+
+/// Words whose candidates are remembered; the cache is emptied when full.
+const CACHE_SIZE: usize = 10_000;
+
+/// Latin spellings and the Persian letters they can stand for, longest
+/// first so digraphs win. Vowels depend on where they are in the word:
+/// (latin, at the start, in the middle, at the end).
+const LATIN_VOWELS: &[(&str, &str, &str, &str)] = &[
+    ("aa", "ا", "ا", "ا"),
+    ("ou", "او", "و", "و"),
+    ("oo", "او", "و", "و"),
+    ("ei", "ای", "ی", "ی"),
+    ("ey", "ای", "ی", "ی"),
+    ("ee", "ای", "ی", "ی"),
+    ("a", "(ا|ع)", "(ا|ع)?", "(ا|ه|ع)"),
+    ("e", "(ا|ع)", "ع?", "(ه|ی)"),
+    ("i", "ای", "ی", "ی"),
+    ("o", "(ا|او|ع)", "(و|ع)?", "(و|ه)"),
+    ("u", "او", "و", "و"),
+];
+
+const LATIN_CONSONANTS: &[(&str, &str)] = &[
+    ("kh", "خ"),
+    ("sh", "ش"),
+    ("ch", "چ"),
+    ("gh", "(ق|غ)"),
+    ("zh", "ژ"),
+    ("b", "ب"),
+    ("c", "ک"),
+    ("d", "د"),
+    ("f", "ف"),
+    ("g", "گ"),
+    ("h", "(ه|ح)"),
+    ("j", "ج"),
+    ("k", "ک"),
+    ("l", "ل"),
+    ("m", "م"),
+    ("n", "ن"),
+    ("p", "پ"),
+    ("q", "(ق|غ)"),
+    ("r", "ر"),
+    ("s", "(س|ص|ث)"),
+    ("t", "(ت|ط)"),
+    ("v", "و"),
+    ("w", "و"),
+    ("x", "کس"),
+    ("y", "ی"),
+    ("z", "(ز|ذ|ض|ظ)"),
+];
+
+/// Latin spellings of each Persian letter (as normalized by the analyzer).
+fn persian_letter(c: char) -> Option<&'static str> {
+    Some(match c {
+        'ا' => "(aa|a|e|o)?",
+        'ب' => "b",
+        'پ' => "p",
+        'ت' | 'ط' => "t",
+        'ث' | 'س' | 'ص' => "s",
+        'ج' => "j",
+        'چ' => "ch",
+        'ح' => "h",
+        'خ' => "kh",
+        'د' => "d",
+        'ذ' | 'ز' | 'ض' | 'ظ' => "z",
+        'ر' => "r",
+        'ژ' => "zh",
+        'ش' => "sh",
+        'ع' | 'ء' => "'?",
+        'غ' => "gh",
+        'ف' => "f",
+        'ق' => "(gh|q)",
+        'ک' => "k",
+        'گ' => "g",
+        'ل' => "l",
+        'م' => "m",
+        'ن' => "n",
+        'و' => "(v|w|o|u|oo|ou)",
+        'ه' => "(h|e)",
+        'ی' => "(y|i|ee|ei|ey)",
+        _ => return None,
+    })
+}
+
+/// Regex over the Persian spellings of a lowercase Latin word.
+fn persian_pattern(word: &str) -> Option<String> {
+    let mut pattern = String::new();
+    let mut rest = word;
+    let mut last_consonant = None;
+    while !rest.is_empty() {
+        let at_start = rest.len() == word.len();
+        if let Some(&(latin, start, middle, end)) =
+            LATIN_VOWELS.iter().find(|(l, ..)| rest.starts_with(l))
+        {
+            rest = &rest[latin.len()..];
+            pattern.push_str(match (at_start, rest.is_empty()) {
+                (true, _) => start,
+                (false, true) => end,
+                (false, false) => middle,
+            });
+            last_consonant = None;
+        } else {
+            let &(latin, persian) = LATIN_CONSONANTS.iter().find(|(l, _)| rest.starts_with(l))?;
+            rest = &rest[latin.len()..];
+            // doubled consonants ("mohammad") are written once
+            if last_consonant != Some(latin) {
+                pattern.push_str(persian);
+            }
+            last_consonant = Some(latin);
+        }
+    }
+    Some(pattern)
+}
+
+/// Regex over the Latin spellings of a Persian word, allowing the
+/// unwritten short vowels between letters.
+fn latin_pattern(word: &str) -> Option<String> {
+    let letters = word
+        .chars()
+        .map(persian_letter)
+        .collect::<Option<Vec<_>>>()?;
+    Some(letters.join("[aeo]?"))
+}
+
+/// Spellings of `term` in the other script that occur in the index, most
+/// frequent first. Empty for words that are neither all Latin letters nor
+/// all Persian letters.
+pub fn candidates(index: &IndexStore, term: &str) -> Vec<String> {
+    if term.chars().count() < MIN_CHARS {
+        return vec![];
+    }
+    let pattern = if term.chars().all(|c| c.is_ascii_lowercase()) {
+        persian_pattern(term)
+    } else {
+        latin_pattern(term)
+    };
+    let Some(pattern) = pattern else {
+        return vec![];
+    };
+
+    let mut found: Vec<(String, usize)> = Vec::new();
+    let matched = index.terms_matching_regex(&pattern, |t, df| {
+        if t != term {
+            found.push((t.to_string(), df));
+        }
+    });
+    if matched.is_err() {
+        return vec![];
+    }
+    found.sort_by_key(|&(_, df)| std::cmp::Reverse(df));
+    found
+        .into_iter()
+        .take(MAX_CANDIDATES)
+        .map(|(t, _)| t)
+        .collect()
+}
+
+/// Query-time transliteration, remembering the spellings found per word
+/// so the dictionary is searched once per word and index generation.
+#[derive(Default)]
+pub struct Transliterator {
+    /// Index generation the cache was filled at, and word → candidates.
+    cache: Mutex<(u64, HashMap<String, Vec<String>>)>,
+}
+
+impl Transliterator {
+    /// `candidates` of `term`, or none if the term is common in the index.
+    fn alternatives(&self, index: &IndexStore, term: &str) -> Vec<String> {
+        {
+            let mut cache = self.cache.lock().unwrap();
+            if cache.0 != index.generation {
+                *cache = (index.generation, HashMap::new());
+            }
+            if let Some(found) = cache.1.get(term) {
+                return found.clone();
+            }
+        }
+        let found = if index.doc_freq(term) >= RARE_DOC_FREQ {
+            vec![]
+        } else {
+            candidates(index, term)
+        };
+        let mut cache = self.cache.lock().unwrap();
+        if cache.0 == index.generation {
+            if cache.1.len() >= CACHE_SIZE {
+                cache.1.clear();
+            }
+            cache.1.insert(term.to_string(), found.clone());
+        }
+        found
+    }
+
+    /// Adds the other-script spellings of the query's rare words as
+    /// lower-weighted alternatives and lists what was added.
+    pub fn expand(&self, index: &IndexStore, query: &Query) -> (Query, Vec<Expansion>) {
+        expand_with(query, |term| self.alternatives(index, term))
+    }
+}
+
+fn expand_with(
+    query: &Query,
+    mut alternatives_of: impl FnMut(&str) -> Vec<String>,
+) -> (Query, Vec<Expansion>) {
+    let mut applied = Vec::new();
+    let expanded = query.expand_terms(&mut |term| {
+        let original = Query::Term(term.to_string());
+        let alternatives = alternatives_of(term);
+        if alternatives.is_empty() {
+            return original;
+        }
+        let mut clauses = vec![original];
+        for alt in alternatives {
+            let expansion = Expansion {
+                from: term.to_string(),
+                to: alt.clone(),
+            };
+            if !applied.contains(&expansion) {
+                applied.push(expansion);
+            }
+            clauses.push(Query::Boost(EXPANSION_WEIGHT, Box::new(Query::Term(alt))));
+        }
+        Query::Or(clauses)
+    });
+    (expanded, applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, word: &str) -> bool {
+        regex::Regex::new(&format!("^(?:{})$", pattern))
+            .unwrap()
+            .is_match(word)
+    }
+
+    #[test]
+    fn finglish_to_persian() {
+        let pattern = persian_pattern("ketab").unwrap();
+        assert!(matches(&pattern, "کتاب"));
+        assert!(!matches(&pattern, "کباب"));
+        assert!(matches(&persian_pattern("tehran").unwrap(), "تهران"));
+        assert!(matches(&persian_pattern("mohammad").unwrap(), "محمد"));
+        assert_eq!(persian_pattern("x2"), None);
+    }
+
+    #[test]
+    fn persian_to_finglish() {
+        let pattern = latin_pattern("کتاب").unwrap();
+        assert!(matches(&pattern, "ketab"));
+        assert!(matches(&pattern, "ktab"));
+        assert_eq!(latin_pattern("ab1"), None);
+    }
+
+    #[test]
+    fn expansions_are_weighted_alternatives() {
+        let query = Query::And(vec![
+            Query::Term("ketab".into()),
+            Query::Term("rust".into()),
+        ]);
+        let (expanded, applied) = expand_with(&query, |t| {
+            if t == "ketab" {
+                vec!["کتاب".to_string()]
+            } else {
+                vec![]
+            }
+        });
+        assert_eq!(
+            applied,
+            [Expansion {
+                from: "ketab".into(),
+                to: "کتاب".into()
+            }]
+        );
+        assert_eq!(
+            expanded,
+            Query::And(vec![
+                Query::Or(vec![
+                    Query::Term("ketab".into()),
+                    Query::Boost(EXPANSION_WEIGHT, Box::new(Query::Term("کتاب".into()))),
+                ]),
+                Query::Term("rust".into()),
+            ])
+        );
+    }
+}